@@ -1,12 +1,16 @@
-use super::helpers::{CapturesHelpers, EMPTY_STR, EMPTY_STRING, RegexHelpers, StringHelpers};
-use super::models::Compression;
+use super::bucket::{S3Bucket, is_bucket};
+use super::helpers::{
+    CapturesHelpers, EMPTY_STR, EMPTY_STRING, MILLION, RegexHelpers, StringHelpers, is_stdio,
+};
+use super::models::{ArchiveFormat, Compression};
+use super::state::Hooks;
 use clap::builder::styling::{AnsiColor as Ansi, Styles};
-use clap::{Args, Parser, Subcommand, ValueEnum};
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
 use clap_complete::Shell;
 use log::LevelFilter;
 use regex::Regex;
 use simplelog::TerminalMode;
-use std::{fmt, path::Path, path::PathBuf, str::FromStr};
+use std::{env, fmt, fs, path::Path, path::PathBuf, str::FromStr};
 use url::Url;
 
 // #region Cli arguments
@@ -52,6 +56,14 @@ pub(crate) enum Commands {
     Create(Execute),
     /// Get information about the Solr instance
     Info(Execute),
+    /// Compare the documents of a Solr core with another core or with local backup files
+    Diff(Diff),
+    /// Copy the documents modified since the last run from a Solr core into another core
+    Sync(Synchronize),
+    /// Report the contents of local backup files without restoring them
+    Inspect(Inspect),
+    /// Check the checksums and document counts of local backup files
+    Verify(Verify),
     /// Generates man page and completion scripts for different shells
     Generate(Generate),
 }
@@ -93,6 +105,30 @@ pub(crate) struct Backup {
     #[arg(short, long, display_order = 46, value_name = "field1,field2,...", value_parser = parse_trim, value_delimiter = ',')]
     pub exclude: Vec<String>,
 
+    /// Retrieve the nested child documents of the parent documents matching this filter.
+    /// Adds the `[child]` transformer to the field list and retrieves only the parent documents
+    #[arg(long, display_order = 47, value_name = "'doc_type:parent'")]
+    pub nested: Option<String>,
+
+    /// Fetch again into archives named `_refetch_` the slices whose documents written differ
+    /// from the ones requested, failing when they still differ. Otherwise only warns about them
+    #[arg(long, display_order = 48)]
+    pub verify: bool,
+
+    /// Track the uniqueKeys of the documents written for counting the ones duplicated in
+    /// several batches and the ones missing, as happens with unstable sort orders
+    #[arg(long, display_order = 60, value_name = "mode", value_enum)]
+    pub duplicates: Option<DuplicateMode>,
+
+    /// Name of the uniqueKey field defined in the schema of the core
+    #[arg(long, display_order = 61, default_value = "id", value_name = "field")]
+    pub unique_key: String,
+
+    /// Existing folder where the uniqueKeys tracked by `--duplicates` are stored instead of
+    /// memory, for cores with more documents than the memory can hold
+    #[arg(long, display_order = 62, value_name = "/path/to/folder", requires = "duplicates")]
+    pub keys_dir: Option<PathBuf>,
+
     /// Slice the queries by using the variables {begin} and {end} for iterating in `--query`
     /// Used in bigger solr cores with huge number of docs because querying the end of docs is expensive and fails frequently
     #[arg(short, long, display_order = 50, default_value_t = IterateMode::Day, value_name = "mode", requires = "iterate_between", value_enum)]
@@ -121,6 +157,24 @@ pub(crate) struct Backup {
     )]
     pub iterate_step: u64,
 
+    /// Retrieve only a random fraction of the documents matching the query, like `0.01` or `1%`.
+    /// Sorts the documents by the `random_*` dynamic field that the schema must define
+    #[arg(long, display_order = 55, value_parser = parse_fraction, value_name = "fraction", conflicts_with = "order")]
+    pub sample: Option<u64>,
+
+    /// Retrieve a random sample of each value of this field, with at most `--per-bucket`
+    /// documents each. Documents without a value in the field are not retrieved
+    #[arg(long, display_order = 56, value_name = "field", requires = "per_bucket", conflicts_with_all = ["order", "skip", "limit"])]
+    pub stratify: Option<String>,
+
+    /// Maximum number of documents retrieved for each value of the `--stratify` field
+    #[arg(long, display_order = 57, value_parser = parse_quantity, value_name = "quantity", requires = "stratify")]
+    pub per_bucket: Option<u64>,
+
+    /// Seed of the random order used by `--sample` and `--stratify` for repeating the same sample
+    #[arg(long, display_order = 58, default_value_t = 0, value_name = "number")]
+    pub seed: u64,
+
     /// Number of documents to retrieve from solr in each reader step
     #[arg(long, display_order = 70, default_value = "4k", value_parser = parse_quantity, value_name = "quantity")]
     pub num_docs: u64,
@@ -135,9 +189,30 @@ pub(crate) struct Backup {
 
     /// Compression method to use for compressing the archive files
     /// [possible values: stored, zip, zstd ]
+    /// For `tar`, `jsonl` and `csv` files zip means gzip and stored means uncompressed
     #[arg(long, display_order = 73, default_value = "zip", value_parser = parse_compression, value_name = "compression")]
     pub archive_compression: Compression,
 
+    /// Format of the backup files: zip or tar archives of json arrays, newline-delimited json
+    /// with one document per line for tools like Spark, DuckDB and jq, csv for spreadsheets
+    /// or parquet for analytics. Tar archives are written as a stream, so they stay readable
+    /// up to the last complete entry if the backup is interrupted. Parquet cannot be restored
+    #[arg(
+        long,
+        display_order = 74,
+        default_value = "zip",
+        value_name = "zip | tar | jsonl | csv | parquet"
+    )]
+    pub archive_format: ArchiveFormat,
+
+    /// Size of the parquet row groups in memory before being flushed to the file
+    #[arg(long, display_order = 78, default_value = "128mb", value_parser = parse_quantity, value_name = "size")]
+    pub parquet_row_group_size: u64,
+
+    /// Size of the parquet files for starting a new one besides `--archive-files`
+    #[arg(long, display_order = 79, default_value = "1gb", value_parser = parse_quantity, value_name = "size")]
+    pub parquet_file_size: u64,
+
     /// Use only when your Solr Cloud returns a distinct count of docs for some queries in a row.
     /// This may be caused by replication problems between cluster nodes of shard replicas of a core.
     /// Response with 'num_found' bellow the greatest value are ignored for getting all possible docs.
@@ -151,6 +226,12 @@ pub(crate) struct Backup {
     )]
     pub workaround_shards: u64,
 
+    #[command(flatten)]
+    pub csv: CsvArgs,
+
+    #[command(flatten)]
+    pub encryption: EncryptionArgs,
+
     #[command(flatten)]
     pub options: CommonArgs,
 
@@ -173,6 +254,53 @@ pub(crate) struct Restore {
     #[arg(long, display_order = 42)]
     pub disable_replication: bool,
 
+    /// Name of the uniqueKey field defined in the schema of the target core
+    #[arg(long, display_order = 43, default_value = "id", value_name = "field")]
+    pub unique_key: String,
+
+    /// Restore documents with nested child documents extracted with `backup --nested`.
+    /// Posts the documents to the `/update` handler preserving the parent/child hierarchy
+    #[arg(long, display_order = 44, conflicts_with = "atomic")]
+    pub nested: bool,
+
+    /// Send the documents as atomic updates changing only the fields present in the archives.
+    /// The operation is applied to every field except the `--unique-key` field
+    #[arg(long, display_order = 45, value_name = "operation", value_enum)]
+    pub atomic: Option<AtomicOperation>,
+
+    /// Operation of the atomic update for specific fields overriding the one in `--atomic`
+    #[arg(long, display_order = 46, value_name = "field=operation,...", value_parser = parse_atomic_field, value_delimiter = ',', requires = "atomic")]
+    pub atomic_fields: Vec<AtomicField>,
+
+    /// What to do with documents not existing in the target core when using `--atomic`.
+    /// Skipping requires Solr 8.8 or newer
+    #[arg(long, display_order = 47, default_value_t = MissingDocs::Create, value_name = "action", value_enum, requires = "atomic")]
+    pub atomic_missing: MissingDocs,
+
+    /// Skip documents whose copy in the target core has a greater value in this field.
+    /// Use a timestamp or version field for not overwriting documents changed after the backup
+    #[arg(long, display_order = 48, value_name = "field")]
+    pub only_if_newer: Option<String>,
+
+    /// Continue with the next entry of an archive when an entry cannot be read.
+    /// Otherwise the rest of the archive is skipped. Both count in `--max-errors`
+    #[arg(long, display_order = 49)]
+    pub skip_corrupted: bool,
+
+    /// Check after the final commit that the core holds as many documents as restored and that
+    /// a random sample of them is stored with the same values. Fails when they differ
+    #[arg(long, display_order = 50, conflicts_with_all = ["atomic", "no_final_commit"])]
+    pub verify: bool,
+
+    /// Solr Query for counting only the restored documents when the core holds other ones.
+    /// Required with `--nested` for counting only the parent documents
+    #[arg(long, display_order = 51, value_name = "'f1:vl1 AND f2:vl2'", requires = "verify")]
+    pub verify_query: Option<String>,
+
+    /// Number of documents from the archives checked with real-time get by `--verify`
+    #[arg(long, display_order = 52, default_value = "100", value_parser = parse_quantity, value_name = "quantity")]
+    pub verify_samples: u64,
+
     /// Search pattern for matching names of the archive backup files
     #[arg(short, long, display_order = 70, value_name = "core*.zip")]
     pub search: Option<String>,
@@ -181,6 +309,22 @@ pub(crate) struct Restore {
     #[arg(long, display_order = 71, default_value = "none", value_name = "asc | desc")]
     pub order: SortOrder,
 
+    /// Format of the backup files to search and restore when `--search` is not given.
+    /// Each file is read according to its extension: `.zip`, or `.tar`, `.jsonl` or `.csv`
+    /// plus `.gz` or `.zst`
+    #[arg(long, display_order = 72, default_value = "zip", value_name = "zip | tar | jsonl | csv")]
+    pub archive_format: ArchiveFormat,
+
+    /// Columns of csv files holding multi-valued fields to be split by `--csv-join`
+    #[arg(long, display_order = 78, value_delimiter = ',', value_name = "field1,field2")]
+    pub csv_split: Vec<String>,
+
+    #[command(flatten)]
+    pub csv: CsvArgs,
+
+    #[command(flatten)]
+    pub encryption: EncryptionArgs,
+
     #[command(flatten)]
     pub options: CommonArgs,
 
@@ -205,6 +349,198 @@ pub(crate) struct Delete {
     pub options: CommonArgs,
 }
 
+#[derive(Parser, Debug)]
+#[command(group(ArgGroup::new("compared").required(true).args(["target_core", "dir"])))]
+pub(crate) struct Diff {
+    /// Case sensitive name of the core compared with the one in `--core`
+    #[arg(short, long, display_order = 21, value_name = "core")]
+    pub target_core: Option<String>,
+
+    /// Url pointing to the Solr cluster of the `--target-core` [default: same as `--url`]
+    #[arg(long, display_order = 22, value_parser = parse_solr_url, value_name = "url", requires = "target_core")]
+    pub target_url: Option<String>,
+
+    /// Existing folder with the backup archive files compared with the `--core`
+    #[arg(short, long, display_order = 30, value_name = "/path/to/output")]
+    pub dir: Option<PathBuf>,
+
+    /// Search pattern for matching names of the archive backup files
+    #[arg(short, long, display_order = 31, value_name = "core*.zip", requires = "dir")]
+    pub search: Option<String>,
+
+    /// Format of the backup files to compare when `--search` is not given.
+    /// Each file is read according to its extension
+    #[arg(long, display_order = 32, default_value = "zip", value_name = "zip | tar | jsonl | csv")]
+    pub archive_format: ArchiveFormat,
+
+    /// Solr Query param 'q' for filtering which documents are compared in the cores
+    #[arg(short, long, display_order = 40, value_name = "'f1:vl1 AND f2:vl2'")]
+    pub query: Option<String>,
+
+    /// Solr Filter Query param 'fq' for filtering which documents are compared in the cores
+    #[arg(short = 'f', long, display_order = 41, value_name = "'f1:vl1 AND f2:vl2'")]
+    pub fq: Option<String>,
+
+    /// Names of core fields compared in each document [default: all but _*]
+    #[arg(long, display_order = 45, value_name = "field1,field2,...", value_parser = parse_trim, value_delimiter = ',')]
+    pub select: Vec<String>,
+
+    /// Name of the uniqueKey field defined in the schema of the cores
+    #[arg(long, display_order = 47, default_value = "id", value_name = "field")]
+    pub unique_key: String,
+
+    /// Number of documents to retrieve from solr in each step
+    #[arg(long, display_order = 70, default_value = "4k", value_parser = parse_quantity, value_name = "quantity")]
+    pub num_docs: u64,
+
+    /// Existing folder where archives with the documents missing or changed in the compared
+    /// core/backup are written for restoring them later
+    #[arg(long, display_order = 71, value_name = "/path/to/output")]
+    pub output_dir: Option<PathBuf>,
+
+    /// Write the uniqueKeys of the missing, extra and changed documents to this file
+    #[arg(long, display_order = 72, value_name = "path")]
+    pub ids_file: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub csv: CsvArgs,
+
+    #[command(flatten)]
+    pub encryption: EncryptionArgs,
+
+    #[command(flatten)]
+    pub options: CommonArgs,
+}
+
+#[derive(Parser, Debug)]
+pub(crate) struct Synchronize {
+    /// Case sensitive name of the core receiving the documents modified in the `--core`
+    #[arg(short, long, display_order = 21, value_name = "core")]
+    pub target_core: String,
+
+    /// Url pointing to the Solr cluster of the `--target-core` [default: same as `--url`]
+    #[arg(long, display_order = 22, value_parser = parse_solr_url, value_name = "url")]
+    pub target_url: Option<String>,
+
+    /// Date field of the `--core` with the time of the last modification of each document
+    #[arg(long, display_order = 30, value_name = "field")]
+    pub field: String,
+
+    /// File keeping the greatest value of `--field` already copied between runs
+    #[arg(long, display_order = 31, value_name = "path")]
+    pub state_file: PathBuf,
+
+    /// Copy the documents modified since this date when the `--state-file` does not exist yet.
+    /// Use dates in ISO 8601 format (yyyy-mm-ddTHH:MM:SS)
+    #[arg(long, display_order = 32, default_value = "1970-01-01T00:00:00Z", value_name = "date")]
+    pub since: String,
+
+    /// Solr Query param 'q' for filtering which documents are copied
+    #[arg(short, long, display_order = 40, value_name = "'f1:vl1 AND f2:vl2'")]
+    pub query: Option<String>,
+
+    /// Solr Filter Query param 'fq' for filtering which documents are copied
+    #[arg(short = 'f', long, display_order = 41, value_name = "'f1:vl1 AND f2:vl2'")]
+    pub fq: Option<String>,
+
+    /// Names of core fields copied in each document [default: all but _*]
+    #[arg(short, long, display_order = 45, value_name = "field1,field2,...", value_parser = parse_trim, value_delimiter = ',')]
+    pub select: Vec<String>,
+
+    /// Name of the uniqueKey field defined in the schema of the cores
+    #[arg(long, display_order = 47, default_value = "id", value_name = "field")]
+    pub unique_key: String,
+
+    /// Remove from the `--target-core` the documents not found anymore in the `--core`.
+    /// Compares the uniqueKeys of all documents matching the query in both cores
+    #[arg(long, display_order = 50)]
+    pub delete_missing: bool,
+
+    /// Do not perform a final hard commit in the `--target-core` before finishing
+    #[arg(long, display_order = 51)]
+    pub no_final_commit: bool,
+
+    /// Number of documents to retrieve from solr in each step
+    #[arg(long, display_order = 70, default_value = "4k", value_parser = parse_quantity, value_name = "quantity")]
+    pub num_docs: u64,
+
+    /// Extra parameter for Solr Select Handler.
+    /// Must be enclosed in quotes and separated by &
+    #[arg(long, display_order = 71, value_name = "useParams=mypars")]
+    pub params: Option<String>,
+
+    #[command(flatten)]
+    pub options: CommonArgs,
+}
+
+#[derive(Parser, Debug)]
+pub(crate) struct Inspect {
+    /// Existing folder with the backup archive files to inspect
+    #[arg(short, display_order = 30, long, env = SOLR_COPY_DIR, value_name = "/path/to/output")]
+    pub dir: PathBuf,
+
+    /// Search pattern for matching names of the archive backup files.
+    /// By default all the files of the `--archive-format`
+    #[arg(short, long, display_order = 31, value_name = "core*.zip")]
+    pub search: Option<String>,
+
+    /// Optional order for listing the archive files
+    #[arg(long, display_order = 32, default_value = "asc", value_name = "asc | desc")]
+    pub order: SortOrder,
+
+    /// Format of the backup files to inspect when `--search` is not given.
+    /// Each file is read according to its extension
+    #[arg(long, display_order = 33, default_value = "zip", value_name = "zip | tar | jsonl | csv")]
+    pub archive_format: ArchiveFormat,
+
+    /// Report the minimum and maximum values of this field in the documents
+    #[arg(long, display_order = 40, value_name = "field")]
+    pub field: Option<String>,
+
+    /// Format of the report printed in the standard output
+    #[arg(long, display_order = 50, default_value_t = OutputFormat::Text, value_name = "format", value_enum)]
+    pub format: OutputFormat,
+
+    #[command(flatten)]
+    pub csv: CsvArgs,
+
+    #[command(flatten)]
+    pub encryption: EncryptionArgs,
+
+    #[command(flatten)]
+    pub logging: LoggingArgs,
+}
+
+#[derive(Parser, Debug)]
+pub(crate) struct Verify {
+    /// Existing folder with the backup archive files to verify
+    #[arg(short, display_order = 30, long, env = SOLR_COPY_DIR, value_name = "/path/to/output")]
+    pub dir: PathBuf,
+
+    /// Search pattern for matching names of the archive backup files.
+    /// By default all the files of the `--archive-format`
+    #[arg(short, long, display_order = 31, value_name = "core*.zip")]
+    pub search: Option<String>,
+
+    /// Optional order for verifying the archive files
+    #[arg(long, display_order = 32, default_value = "asc", value_name = "asc | desc")]
+    pub order: SortOrder,
+
+    /// Format of the backup files to verify when `--search` is not given.
+    /// Each file is read according to its extension
+    #[arg(long, display_order = 33, default_value = "zip", value_name = "zip | tar | jsonl | csv")]
+    pub archive_format: ArchiveFormat,
+
+    #[command(flatten)]
+    pub csv: CsvArgs,
+
+    #[command(flatten)]
+    pub encryption: EncryptionArgs,
+
+    #[command(flatten)]
+    pub logging: LoggingArgs,
+}
+
 #[derive(Parser, Debug)]
 pub(crate) struct Execute {
     #[command(flatten)]
@@ -246,6 +582,10 @@ pub(crate) struct CommonArgs {
 
     #[command(flatten)]
     pub logging: LoggingArgs,
+
+    /// Cancel token and progress callback given by the library builders
+    #[arg(skip)]
+    pub hooks: Hooks,
 }
 
 #[derive(Parser, Clone, Debug)]
@@ -270,7 +610,9 @@ pub(crate) struct LoggingArgs {
 #[derive(Args, Debug)]
 /// Dumps and restores documents from a Apache Solr core into local backup files
 pub(crate) struct ParallelArgs {
-    /// Existing folder where the backuped files containing the extracted documents are stored
+    /// Existing folder where the backuped files containing the extracted documents are stored.
+    /// Use `-` for writing a tar, jsonl or csv backup to stdout or restoring it from stdin,
+    /// or `s3://bucket/prefix` for a bucket of an S3-compatible object storage
     #[arg(short, display_order = 30, long, env = SOLR_COPY_DIR, value_name = "/path/to/output")]
     pub dir: PathBuf,
 
@@ -301,6 +643,10 @@ pub(crate) struct ParallelArgs {
     #[arg(long, display_order = 64, default_value_t = 0, value_name = "time", value_parser = parse_millis, hide_default_value = true)]
     pub delay_after: u64,
 
+    /// Write a json report of the run with its arguments, counts, errors and exit status
+    #[arg(long, display_order = 65, value_name = "/path/to/report.json")]
+    pub report: Option<PathBuf>,
+
     /// Number parallel threads exchanging documents with the solr core
     #[arg(
         short,
@@ -324,6 +670,39 @@ pub(crate) struct ParallelArgs {
     pub writers: u64,
 }
 
+#[derive(Args, Clone, Debug)]
+/// Layout of the csv files written by backup and read by restore
+pub(crate) struct CsvArgs {
+    /// Character separating the columns of csv files. Use `tab` for tab-separated values
+    #[arg(long, display_order = 75, default_value = ",", value_parser = parse_csv_char, value_name = "char")]
+    pub csv_separator: u8,
+
+    /// Character quoting the csv values containing separators, quotes or line breaks
+    #[arg(long, display_order = 76, default_value = "\"", value_parser = parse_csv_char, value_name = "char")]
+    pub csv_quote: u8,
+
+    /// Text joining the values of multi-valued fields in a single csv column
+    #[arg(long, display_order = 77, default_value = "|", value_name = "text")]
+    pub csv_join: String,
+}
+
+#[derive(Args, Clone, Debug, Default)]
+#[group(multiple = false)]
+/// Key for encrypting the entries of zip archives with AES-256 and for decrypting them
+pub(crate) struct EncryptionArgs {
+    /// Read the encryption key from the first line of this file
+    #[arg(long, display_order = 84, value_name = "path")]
+    pub key_file: Option<PathBuf>,
+
+    /// Read the encryption key from this environment variable
+    #[arg(long, display_order = 85, value_name = "VARIABLE")]
+    pub key_env: Option<String>,
+
+    /// Use this passphrase as the encryption key. Visible to other users of the host
+    #[arg(long, display_order = 86, value_name = "text")]
+    pub passphrase: Option<String>,
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
 /// Tells Solrt to performs a commit of the updated documents while updating the core
 pub(crate) enum CommitMode {
@@ -357,6 +736,54 @@ pub(crate) enum SortOrder {
     Desc,
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+/// Operations for modifying the fields of existing documents with atomic updates
+pub(crate) enum AtomicOperation {
+    /// Replace the value of the field
+    Set,
+    /// Add the values to a multi-valued field
+    Add,
+    /// Add the values to a multi-valued field only if not already present
+    AddDistinct,
+    /// Increment a numeric field by the value
+    Inc,
+    /// Remove the values from a multi-valued field
+    Remove,
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) struct AtomicField {
+    pub field: String,
+    pub operation: AtomicOperation,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+/// Handling of the atomic updates for documents missing in the target core
+pub(crate) enum MissingDocs {
+    /// Create a new document with the fields being updated
+    Create,
+    /// Ignore the update of documents not found in the target core
+    Skip,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+/// Handling of the documents whose uniqueKey was already written by backup
+pub(crate) enum DuplicateMode {
+    /// Keep the duplicated documents, only counting them
+    Count,
+    /// Remove the duplicated documents from the batches before writing them
+    Drop,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+/// Format of the reports printed by the commands
+pub(crate) enum OutputFormat {
+    /// Human readable text
+    Text,
+    /// A single JSON object
+    Json,
+}
+
 pub(crate) const SOLR_COPY_DIR: &str = "SOLR_COPY_DIR";
 pub(crate) const SOLR_COPY_URL: &str = "SOLR_COPY_URL";
 
@@ -399,6 +826,30 @@ fn parse_quantity(src: &str) -> Result<u64, String> {
     }
 }
 
+/// Parses fractions like `0.01` or percentages like `1%` as parts per million
+fn parse_fraction(src: &str) -> Result<u64, String> {
+    lazy_static! {
+        static ref REGFR: Regex = Regex::new("^([0-9]*)(?:\\.([0-9]{1,6}))?\\s*(%)?$").unwrap();
+    }
+    let wrong =
+        || format!("Wrong value: '{}'. Use a fraction like 0.01 or a percentage like 1%", src);
+    let parts = REGFR.get_groups(src.trim()).ok_or_else(wrong)?;
+    let (units, decimals) = (parts.get_as_str(1), parts.get_as_str(2));
+    if units.is_empty() && decimals.is_empty() {
+        return Err(wrong());
+    }
+    let units = if units.is_empty() { 0 } else { units.parse::<u64>().map_err(|_| wrong())? };
+    let decimals = format!("{:0<6}", decimals).parse::<u64>().map_err(|_| wrong())?;
+    let mut fraction = units.checked_mul(MILLION).ok_or_else(wrong)? + decimals;
+    if parts.get_as_str(3) == "%" {
+        fraction /= 100;
+    }
+    if fraction == 0 || fraction > MILLION {
+        return Err(format!("Wrong value: '{}'. The fraction must be between 0 and 1", src));
+    }
+    Ok(fraction)
+}
+
 fn parse_quantity_max(s: &str) -> Result<u64, String> {
     let lower = s.to_ascii_lowercase();
     match lower.as_str() {
@@ -489,12 +940,20 @@ fn parse_compression(s: &str) -> Result<Compression, String> {
     let lower = s.to_ascii_lowercase();
     match lower.as_str() {
         "stored" => Ok(Compression::Stored),
-        "zip" => Ok(Compression::Zip),
+        "zip" | "gzip" => Ok(Compression::Zip),
         "zstd" => Ok(Compression::Zstd),
         _ => Err(format!("'{}'. [alowed: stored zip zstd]", s)),
     }
 }
 
+fn parse_csv_char(s: &str) -> Result<u8, String> {
+    match s.as_bytes() {
+        b"tab" | b"\\t" => Ok(b'\t'),
+        [single] if single.is_ascii() => Ok(*single),
+        _ => Err(format!("'{}'. [alowed: a single ascii character or tab]", s)),
+    }
+}
+
 fn parse_commit_mode(s: &str) -> Result<CommitMode, String> {
     let lower = s.to_ascii_lowercase();
     match lower.as_str() {
@@ -508,6 +967,16 @@ fn parse_commit_mode(s: &str) -> Result<CommitMode, String> {
     }
 }
 
+fn parse_atomic_field(s: &str) -> Result<AtomicField, String> {
+    lazy_static! {
+        static ref REA: Regex = Regex::new("^\\s*(\\w+)\\s*[:=]\\s*([\\w-]+)\\s*$").unwrap();
+    }
+    let wrong = || format!("'{}'. [alowed: field=set|add|add-distinct|inc|remove]", s);
+    let parts = REA.get_groups(s).ok_or_else(wrong)?;
+    let operation = AtomicOperation::from_str(parts.get_as_str(2), true).map_err(|_| wrong())?;
+    Ok(AtomicField { field: parts.get_as_str(1).to_string(), operation })
+}
+
 fn parse_terminal_mode(s: &str) -> Result<TerminalMode, String> {
     let lower = s.to_ascii_lowercase();
     match lower.as_str() {
@@ -535,6 +1004,16 @@ pub(crate) fn validate(&self) -> Result<(), String> {
         match self {
             Self::Backup(get) => get.validate(),
             Self::Restore(put) => put.validate(),
+            Self::Diff(dif) => dif.validate(),
+            Self::Sync(syn) => syn.validate(),
+            Self::Inspect(ins) => {
+                ins.encryption.get_key()?;
+                assert_dir_exists(&ins.dir)
+            }
+            Self::Verify(ver) => {
+                ver.encryption.get_key()?;
+                assert_dir_exists(&ver.dir)
+            }
             _ => Ok(()),
         }
     }
@@ -547,11 +1026,37 @@ pub(crate) fn get_options(&self) -> Option<&CommonArgs> {
             Self::Delete(del) => Some(&del.options),
             Self::Create(cre) => Some(&cre.options),
             Self::Info(inf) => Some(&inf.options),
+            Self::Diff(dif) => Some(&dif.options),
+            Self::Sync(syn) => Some(&syn.options),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn get_options_mut(&mut self) -> Option<&mut CommonArgs> {
+        match self {
+            Self::Backup(get) => Some(&mut get.options),
+            Self::Restore(put) => Some(&mut put.options),
+            Self::Commit(com) => Some(&mut com.options),
+            Self::Delete(del) => Some(&mut del.options),
+            Self::Create(cre) => Some(&mut cre.options),
+            Self::Info(inf) => Some(&mut inf.options),
+            Self::Diff(dif) => Some(&mut dif.options),
+            Self::Sync(syn) => Some(&mut syn.options),
             _ => None,
         }
     }
 
     pub(crate) fn get_logging(&self) -> LoggingArgs {
+        match self {
+            Self::Inspect(ins) => return ins.logging.clone(),
+            Self::Verify(ver) => return ver.logging.clone(),
+            // Stdout carries the backup, so messages must go to stderr
+            Self::Backup(get) if is_stdio(&get.transfer.dir) => {
+                let logging = get.options.get_logging();
+                return LoggingArgs { log_mode: TerminalMode::Stderr, ..logging.clone() };
+            }
+            _ => {}
+        }
         match self.get_options() {
             None => LoggingArgs::default().clone(),
             Some(opt) => opt.get_logging().clone(),
@@ -622,6 +1127,38 @@ fn default() -> Self {
     }
 }
 
+impl Default for CsvArgs {
+    fn default() -> Self {
+        Self { csv_separator: b',', csv_quote: b'"', csv_join: "|".to_string() }
+    }
+}
+
+impl EncryptionArgs {
+    pub(crate) fn is_given(&self) -> bool {
+        self.key_file.is_some() || self.key_env.is_some() || self.passphrase.is_some()
+    }
+
+    /// Reads the key from the source given, or none when the archives are not encrypted
+    pub(crate) fn get_key(&self) -> Result<Option<String>, String> {
+        let key = if let Some(path) = &self.key_file {
+            let contents = fs::read_to_string(path)
+                .map_err(|cause| format!("Error reading the key file {:?}: {}", path, cause))?;
+            contents.lines().next().unwrap_or_default().to_string()
+        } else if let Some(var_name) = &self.key_env {
+            env::var(var_name)
+                .map_err(|_| format!("Missing the encryption key in the variable {}", var_name))?
+        } else if let Some(passphrase) = &self.passphrase {
+            passphrase.clone()
+        } else {
+            return Ok(None);
+        };
+        if key.is_empty() {
+            return Err("The encryption key cannot be empty.".to_string());
+        }
+        Ok(Some(key))
+    }
+}
+
 impl Default for CommitMode {
     fn default() -> Self {
         CommitMode::Within { millis: 40_000 }
@@ -658,6 +1195,19 @@ pub(crate) fn as_param(&self, separator: &str) -> String {
     // }
 }
 
+impl AtomicOperation {
+    /// Name of the operation in the Solr atomic update syntax
+    pub(crate) fn as_key(&self) -> &'static str {
+        match self {
+            AtomicOperation::Set => "set",
+            AtomicOperation::Add => "add",
+            AtomicOperation::AddDistinct => "add-distinct",
+            AtomicOperation::Inc => "inc",
+            AtomicOperation::Remove => "remove",
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
 pub(crate) enum SortDirection {
     Asc,
@@ -708,6 +1258,17 @@ fn from_str(s: &str) -> Result<Self, Self::Err> {
     }
 }
 
+impl Backup {
+    /// Backup of the core in `options` with the default values of all other options,
+    /// for commands that query the core as a backup does
+    pub(crate) fn of_core(options: &CommonArgs) -> Self {
+        let core = format!("--core={}", options.core);
+        let mut params = Self::parse_from(["backup", core.as_str(), "--dir", "."]);
+        params.options = options.clone();
+        params
+    }
+}
+
 impl Generate {
     pub(crate) fn get_shells(&self) -> Vec<Shell> {
         let sh: Option<Shell> = if self.all { None } else { self.shell };
@@ -730,16 +1291,88 @@ fn validate(&self) -> Result<(), String> {
 
 impl Validation for Backup {
     fn validate(&self) -> Result<(), String> {
+        let tabular = matches!(self.archive_format, ArchiveFormat::Csv | ArchiveFormat::Parquet);
+        if tabular && self.nested.is_some() {
+            return Err("Nested documents cannot be written as csv or parquet.".to_string());
+        }
+        if self.encryption.is_given() && self.archive_format != ArchiveFormat::Zip {
+            return Err("Only zip archives can be encrypted.".to_string());
+        }
+        self.encryption.get_key()?;
+        if let Some(dir) = &self.keys_dir {
+            assert_dir_exists(dir)?;
+        }
+        if self.duplicates.is_some() {
+            let key = &self.unique_key;
+            let selected =
+                self.select.is_empty() || self.select.iter().any(|it| it == key || it == "*");
+            if !selected || self.exclude.contains(key) {
+                return Err(format!("Tracking --duplicates requires selecting the field {}.", key));
+            }
+        }
+        if is_stdio(&self.transfer.dir) {
+            if !self.archive_format.is_streamable() {
+                return Err("Only tar, jsonl or csv backups can be written to stdout.".to_string());
+            }
+            if self.transfer.writers > 1 {
+                return Err("Writing the backup to stdout requires --writers 1.".to_string());
+            }
+            return Ok(());
+        }
+        if is_bucket(&self.transfer.dir) {
+            return S3Bucket::open(&self.transfer.dir).map(drop).map_err(|cause| cause.to_string());
+        }
         assert_dir_exists(&self.transfer.dir)
     }
 }
 
 impl Validation for Restore {
     fn validate(&self) -> Result<(), String> {
+        if self.archive_format == ArchiveFormat::Parquet {
+            return Err("Restoring parquet files is not supported.".to_string());
+        }
+        if self.encryption.is_given() && self.archive_format != ArchiveFormat::Zip {
+            return Err("Only zip archives can be encrypted.".to_string());
+        }
+        self.encryption.get_key()?;
+        if self.nested && self.verify && self.verify_query.is_none() {
+            // Counting with *:* would also count the child documents
+            return Err(
+                "Use --verify-query with the filter of the parent documents for verifying \
+                 a --nested restore."
+                    .to_string(),
+            );
+        }
+        if is_stdio(&self.transfer.dir) {
+            if !self.archive_format.is_streamable() {
+                return Err("Only tar, jsonl or csv backups can be read from stdin.".to_string());
+            }
+            return Ok(());
+        }
+        if is_bucket(&self.transfer.dir) {
+            return S3Bucket::open(&self.transfer.dir).map(drop).map_err(|cause| cause.to_string());
+        }
         assert_dir_exists(&self.transfer.dir)
     }
 }
 
+impl Validation for Diff {
+    fn validate(&self) -> Result<(), String> {
+        self.encryption.get_key()?;
+        let folders = [&self.dir, &self.output_dir];
+        folders.into_iter().flatten().try_for_each(|dir| assert_dir_exists(dir))
+    }
+}
+
+impl Validation for Synchronize {
+    fn validate(&self) -> Result<(), String> {
+        match self.state_file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => assert_dir_exists(dir),
+            _ => Ok(()),
+        }
+    }
+}
+
 fn assert_dir_exists(dir: &Path) -> Result<(), String> {
     if !dir.exists() {
         Err(format!("Missing folder of the backuped archive files: {:?}", dir))
@@ -791,11 +1424,15 @@ pub(crate) mod tests {
     // #region Mockup
 
     use super::shared::TEST_SELECT_FIELDS;
-    use super::{Cli, Commands, CommitMode, parse_millis, parse_quantity};
+    use super::{
+        Cli, Commands, CommitMode, parse_csv_char, parse_fraction, parse_millis, parse_quantity,
+    };
+    use crate::helpers::shared::TempDir;
     use clap::Parser;
     use clap_complete::Shell::Bash;
     use log::LevelFilter;
     use pretty_assertions::assert_eq;
+    use simplelog::TerminalMode;
     use std::path::PathBuf;
 
     impl Cli {
@@ -1063,6 +1700,19 @@ fn check_parse_quantity() {
         assert_eq!(parse_quantity("999 mb"), Ok(999_000_000));
     }
 
+    #[test]
+    fn check_parse_fraction() {
+        assert_eq!(parse_fraction("0.01"), Ok(10_000));
+        assert_eq!(parse_fraction(".5"), Ok(500_000));
+        assert_eq!(parse_fraction("1"), Ok(1_000_000));
+        assert_eq!(parse_fraction("1%"), Ok(10_000));
+        assert_eq!(parse_fraction("12.5 %"), Ok(125_000));
+        assert_eq!(parse_fraction("0").is_err(), true);
+        assert_eq!(parse_fraction("1.5").is_err(), true);
+        assert_eq!(parse_fraction("%").is_err(), true);
+        assert_eq!(parse_fraction("abc").is_err(), true);
+    }
+
     #[test]
     fn check_parse_millis() {
         assert_eq!(parse_millis("3ms"), Ok(3));
@@ -1075,6 +1725,67 @@ fn check_parse_millis() {
         assert_eq!(parse_millis("10h"), Ok(36_000_000));
     }
 
+    #[test]
+    fn check_params_stdio() {
+        let backup = ["solrcopy", "backup", "--core", "demo", "--dir", "-"];
+        let restore = ["solrcopy", "restore", "--core", "demo", "--dir", "-"];
+        let with = |args: &[&str], extra: &[&str]| {
+            Cli::parse_from([args, extra].concat()).arguments.validate()
+        };
+        assert!(with(&backup, &["--archive-format", "jsonl"]).is_ok());
+        assert!(with(&backup, &["--archive-format", "zip"]).is_err());
+        assert!(with(&backup, &["--archive-format", "tar", "--writers", "2"]).is_err());
+        let duplicates = ["--archive-format", "jsonl", "--duplicates", "count", "--select"];
+        assert!(with(&backup, &[&duplicates[..], &["id,name"]].concat()).is_ok());
+        assert!(with(&backup, &[&duplicates[..], &["name"]].concat()).is_err());
+        assert!(with(&backup, &[&duplicates[..], &["*", "--exclude", "id"]].concat()).is_err());
+        assert!(with(&restore, &["--archive-format", "csv"]).is_ok());
+        assert!(with(&restore, &["--archive-format", "zip"]).is_err());
+        let nested = ["--archive-format", "jsonl", "--nested", "--verify"];
+        assert!(with(&restore, &nested).is_err());
+        assert!(
+            with(&restore, &[&nested[..], &["--verify-query", "type:parent"]].concat()).is_ok()
+        );
+
+        let logging = Cli::parse_from([&backup[..], &["--archive-format", "tar"]].concat())
+            .arguments
+            .get_logging();
+        assert_eq!(logging.log_mode, TerminalMode::Stderr);
+    }
+
+    #[test]
+    fn check_encryption_key() {
+        let backup = ["solrcopy", "backup", "--core", "demo", "--dir", "."];
+        let dir = TempDir::new("key");
+        let key_file = dir.join("key.txt");
+        std::fs::write(&key_file, "from file\nignored\n").unwrap();
+        let file_arg = key_file.to_str().unwrap();
+        let key_of = |extra: &[&str]| match Cli::parse_from([&backup[..], extra].concat()).arguments
+        {
+            Commands::Backup(get) => get.encryption.get_key(),
+            _ => panic!("command must be 'backup' !"),
+        };
+        let from_file = key_of(&["--key-file", file_arg]);
+
+        assert_eq!(key_of(&[]), Ok(None));
+        assert_eq!(key_of(&["--passphrase", "typed"]), Ok(Some("typed".to_string())));
+        assert_eq!(from_file, Ok(Some("from file".to_string())));
+        assert!(key_of(&["--key-env", "SOLRCOPY_UNDEFINED_KEY_VARIABLE"]).is_err());
+        let tar = ["--archive-format", "tar", "--passphrase", "typed"];
+        assert!(Cli::parse_from([&backup[..], &tar].concat()).arguments.validate().is_err());
+        let both = ["--passphrase", "typed", "--key-env", "KEY"];
+        assert!(Cli::try_parse_from([&backup[..], &both].concat()).is_err());
+    }
+
+    #[test]
+    fn check_parse_csv_char() {
+        assert_eq!(parse_csv_char(";"), Ok(b';'));
+        assert_eq!(parse_csv_char("tab"), Ok(b'\t'));
+        assert_eq!(parse_csv_char("\\t"), Ok(b'\t'));
+        assert!(parse_csv_char(",,").is_err());
+        assert!(parse_csv_char("ç").is_err());
+    }
+
     // #endregion
 }
 
@@ -1,29 +1,76 @@
 use super::{
-    args::Backup,
+    args::{Backup, DuplicateMode},
     bars::{forall_progress, wait_with_progress},
     connection::SolrClient,
-    fails::{BoxedError, raise},
-    helpers::{IntegerHelpers, wait, wait_by},
-    models::{Compression, Documents, SolrCore, Step},
-    save::Archiver,
-    state::{UserInterruption, monitor_term_sinal},
-    steps::{Requests, Slices},
+    fails::{BoxedError, BoxedResult, Error, fail_with},
+    helpers::{EMPTY_STRING, IntegerHelpers, is_stdio, wait, wait_by},
+    keys::KeyTracker,
+    models::{Columns, Documents, SolrCore, Step},
+    report::{READER, RunStats, SliceTally, ThreadTally, WRITER, write_report},
+    save::count_docs,
+    state::UserInterruption,
+    steps::{BucketQuota, Requests, Slices},
+    storage::open_sink,
 };
+use chrono::Local;
 use crossbeam_channel::{Receiver, Sender, bounded};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, AtomicU64, Ordering},
+};
 use std::thread;
-use std::{path::PathBuf, time::Instant};
+use std::time::Instant;
+
+/// Failed requests of all readers, which count together for `--max-errors`
+#[derive(Default)]
+struct ReadFailures {
+    count: AtomicU64,
+    /// First failure, whose kind gives the exit code when going over `--max-errors`
+    first: Mutex<Option<Error>>,
+}
+
+impl ReadFailures {
+    /// Counts the failure and returns how many happened until now
+    fn add(&self, cause: Error) -> u64 {
+        let count = self.count.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut first = self.first.lock().unwrap();
+        if first.is_none() {
+            *first = Some(cause);
+        }
+        count
+    }
+}
 
 pub(crate) fn backup_main(params: &Backup) -> BoxedError {
     debug!("# BACKUP {:?}", params);
 
+    let started = Local::now();
+    let stats = RunStats::default();
+    // Classified once for the exit code in the report and the one of the process
+    let result = backup_with_stats(params, &stats).map_err(Error::from);
+    if let Some(path) = &params.transfer.report {
+        let arguments = params.describe();
+        if let Err(cause) = write_report(path, "backup", arguments, started, stats, &result) {
+            warn!("Error writing the report into {:?}: {}", path, cause);
+        }
+    }
+    Ok(result?)
+}
+
+fn backup_with_stats(params: &Backup, stats: &RunStats) -> BoxedError {
     if params.options.is_quiet() {
         wait_with_progress(params.transfer.delay_before, "Starting the copy...");
     }
     let schema = params.inspect_core()?;
+    let names = params.get_columns(&schema);
+    let columns = Columns { types: params.get_field_types(&names), names, ..Default::default() };
 
     let num_found = schema.num_found;
-    let num_retrieve = params.get_docs_to_retrieve(num_found);
+    let _ = stats.num_found.set(num_found);
+    let quotas = params.query_bucket_quotas()?;
+    let num_retrieve = params.count_docs_to_retrieve(num_found, &quotas);
     let mut retrieved = 0;
 
     info!(
@@ -36,6 +83,8 @@ pub(crate) fn backup_main(params: &Backup) -> BoxedError {
     );
 
     let started = Instant::now();
+    let failures = ReadFailures::default();
+    let tracker = KeyTracker::open(params, num_retrieve)?;
 
     thread::scope(|pool| {
         let transfer = &params.transfer;
@@ -50,18 +99,20 @@ pub(crate) fn backup_main(params: &Backup) -> BoxedError {
         let gen_handle = thread::Builder::new()
             .name("Generator".to_string())
             .spawn_scoped(pool, || {
-                start_querying_core(params, &schema, generator);
+                start_querying_core(params, &schema, &quotas, generator, stats, &failures);
             })
             .unwrap();
 
-        let reader_handles = start_solr_readers(pool, params, sender, sequence);
+        let reader_handles = start_solr_readers(pool, params, sender, sequence, &failures, stats);
 
-        let writer_handles = start_archive_writers(pool, params, receiver, progress, num_retrieve);
+        let context = WriterContext { params, columns: &columns, stats, tracker: tracker.as_ref() };
+        let writer_handles = start_archive_writers(pool, context, receiver, progress, num_retrieve);
 
         let bar_handle = thread::Builder::new()
             .name("Generator".to_string())
             .spawn_scoped(pool, || {
-                retrieved = forall_progress(reporter, num_retrieve, params.options.is_quiet());
+                let (quiet, hooks) = (params.options.is_quiet(), &params.options.hooks);
+                retrieved = forall_progress(reporter, num_retrieve, quiet, hooks);
             })
             .unwrap();
 
@@ -75,21 +126,26 @@ pub(crate) fn backup_main(params: &Backup) -> BoxedError {
         }
     });
 
-    finish_progress(started, num_retrieve, retrieved, params.transfer.delay_after)
+    if let Some(keys) = tracker {
+        count_duplicates(params, keys, stats, num_retrieve);
+    }
+    check_archives(stats)?;
+    finish_progress(params, started, num_retrieve, retrieved, failures)?;
+    check_written_docs(params, &schema, &columns, stats, num_retrieve)
 }
 
 fn start_solr_readers<'scope>(
-    pool: &'scope thread::Scope<'scope, '_>, params: &Backup, sender: Sender<Documents>,
-    sequence: Receiver<Step>,
+    pool: &'scope thread::Scope<'scope, '_>, params: &'scope Backup, sender: Sender<Documents>,
+    sequence: Receiver<Step>, failures: &'scope ReadFailures, stats: &'scope RunStats,
 ) -> Vec<thread::ScopedJoinHandle<'scope, ()>> {
-    let merr = params.transfer.max_errors;
-    let delay = params.transfer.delay_per_request;
+    let ctrl_c = params.options.hooks.aborting();
 
     let mut handles = vec![];
 
     for ir in 0..params.transfer.readers {
         let producer = sender.clone();
         let iterator = sequence.clone();
+        let aborting = ctrl_c.clone();
 
         let reader = ir;
         let thread_name = format!("Reader_{}", reader);
@@ -98,7 +154,9 @@ fn start_solr_readers<'scope>(
             .name(thread_name)
             .spawn_scoped(pool, move || {
                 debug!("Started reader #{}", reader);
-                start_retrieving_docs(reader, iterator, producer, merr, delay);
+                let tally =
+                    start_retrieving_docs(reader, params, iterator, producer, aborting, failures);
+                stats.add_thread(tally);
                 debug!("Finished reader #{}", reader);
             })
             .unwrap();
@@ -109,20 +167,27 @@ fn start_solr_readers<'scope>(
     handles
 }
 
+/// What every writer thread shares for storing the documents retrieved
+#[derive(Clone, Copy)]
+struct WriterContext<'a> {
+    params: &'a Backup,
+    columns: &'a Columns,
+    stats: &'a RunStats,
+    tracker: Option<&'a KeyTracker>,
+}
+
 fn start_archive_writers<'scope>(
-    pool: &'scope thread::Scope<'scope, '_>, params: &Backup, receiver: Receiver<Documents>,
-    progress: Sender<u64>, num_retrieve: u64,
+    pool: &'scope thread::Scope<'scope, '_>, context: WriterContext<'scope>,
+    receiver: Receiver<Documents>, progress: Sender<u64>, num_retrieve: u64,
 ) -> Vec<thread::ScopedJoinHandle<'scope, ()>> {
+    let params = context.params;
     let output_pat = params.get_archive_pattern(num_retrieve);
-    let max = params.archive_files;
-    let comp = params.archive_compression;
 
     let mut handles = vec![];
 
     for iw in 0..params.transfer.writers {
         let consumer = receiver.clone();
         let updater = progress.clone();
-        let dir = params.transfer.dir.clone();
         let name = output_pat.clone();
 
         let writer = iw;
@@ -132,7 +197,8 @@ fn start_archive_writers<'scope>(
             .name(thread_name)
             .spawn_scoped(pool, move || {
                 debug!("Started writer #{}", writer);
-                start_storing_docs(writer, dir, name, comp, max, consumer, updater);
+                let tally = start_storing_docs(writer, context, &name, consumer, updater);
+                context.stats.add_thread(tally);
                 debug!("Finished writer #{}", writer);
             })
             .unwrap();
@@ -144,77 +210,283 @@ fn start_archive_writers<'scope>(
 }
 
 fn finish_progress(
-    started: Instant, num_retrieve: u64, retrieved: u64, delay_after: u64,
+    params: &Backup, started: Instant, num_retrieve: u64, retrieved: u64, failures: ReadFailures,
 ) -> BoxedError {
-    let ctrl_c = monitor_term_sinal();
+    let ctrl_c = params.options.hooks.aborting();
     if ctrl_c.aborted() {
-        raise("# Execution aborted by user!")
+        return fail_with(Error::Cancelled);
+    }
+    if retrieved > 0 {
+        wait_with_progress(
+            params.transfer.delay_after,
+            "Finished exporting documents to archives...",
+        );
+    }
+    let (r, n, s) = (retrieved, num_retrieve, started.elapsed());
+    info!("Downloaded {} of {} documents in {:?}.", r, n, s);
+
+    let max_errors = params.transfer.max_errors;
+    let (count, first) = (failures.count.into_inner(), failures.first.into_inner().unwrap());
+    if count > max_errors {
+        error!(
+            "Stopped after {} failed requests to solr, more than --max-errors {}.",
+            count, max_errors
+        );
+        // Exits with the code of the kind of failure, as when Solr is down
+        fail_with(first.unwrap_or(Error::Failed("Failed requests to solr".into())))
+    } else if count > 0 {
+        let msg = format!("Skipped the documents of {} failed requests to solr.", count);
+        fail_with(Error::PartialSuccess(msg))
     } else {
-        if retrieved > 0 {
-            wait_with_progress(delay_after, "Finished exporting documents to archives...");
-        }
-        let (r, n, s) = (retrieved, num_retrieve, started.elapsed());
-        info!("Downloaded {} of {} documents in {:?}.", r, n, s);
         Ok(())
     }
 }
 
+/// Fails when the writers could not create, write or finish the backup files
+fn check_archives(stats: &RunStats) -> BoxedError {
+    let errors = stats.sum_threads(WRITER, |tally| tally.errors);
+    if errors == 0 {
+        return Ok(());
+    }
+    let msg = format!("Failed writing the backup files with {} errors in the writers.", errors);
+    if stats.sum_threads(WRITER, |tally| tally.batches) > 0 {
+        fail_with(Error::PartialSuccess(msg))
+    } else {
+        fail_with(Error::Archive(msg))
+    }
+}
+
+/// Reports the documents written with a uniqueKey already tracked and the ones never written
+fn count_duplicates(params: &Backup, tracker: KeyTracker, stats: &RunStats, num_retrieve: u64) {
+    let unique = tracker.unique.into_inner();
+    let duplicates = tracker.duplicates.into_inner();
+    let unkeyed = tracker.unkeyed.into_inner();
+    // Each duplicate written in place of another document of the query leaves a gap, as does
+    // each slice dropped after failing, which is never requested
+    let requested = stats.count_requested().max(num_retrieve);
+    let gaps = requested.saturating_sub(unique + unkeyed);
+    let _ = stats.duplicates.set(duplicates);
+    let _ = stats.unkeyed.set(unkeyed);
+    let _ = stats.gaps.set(gaps);
+    if unkeyed > 0 {
+        warn!("Found {} documents without the unique key field {}.", unkeyed, params.unique_key);
+    }
+    let action = if params.duplicates == Some(DuplicateMode::Drop) { "dropped" } else { "kept" };
+    if duplicates > 0 || gaps > 0 {
+        warn!(
+            "Found {} unique keys in field {}, {} duplicated documents {} and {} missing.",
+            unique, params.unique_key, duplicates, action, gaps
+        );
+    } else {
+        info!("Found {} unique keys in field {} without duplicates.", unique, params.unique_key);
+    }
+}
+
+/// Compares the documents written in each slice with the ones requested from it, which differ
+/// when the core changes during the backup or when paging with an unstable `--order`, and the
+/// documents written in all slices with the ones to retrieve, which differ when slices fail.
+/// With `--verify`, the slices that differ are fetched again into archives of their own
+fn check_written_docs(
+    params: &Backup, schema: &SolrCore, columns: &Columns, stats: &RunStats, num_retrieve: u64,
+) -> BoxedError {
+    let mut problems = 0;
+    let mut total = stats.sum_threads(WRITER, |tally| tally.docs);
+    for slice in stats.get_uneven_slices() {
+        let range = if params.iterate_between.is_empty() {
+            "the query".to_string()
+        } else {
+            format!("slice {} to {}", slice.begin, slice.end)
+        };
+        let (requested, written) = (slice.requested, slice.written);
+        let problem = if written < requested {
+            format!("{} is short by {} of {} documents", range, requested - written, requested)
+        } else {
+            format!(
+                "{} has {} documents more than the {} found",
+                range,
+                written - requested,
+                requested
+            )
+        };
+        problems += 1;
+        if !params.verify {
+            warn!("Backup {}.", problem);
+            continue;
+        }
+        // The buckets of --stratify are counted by facets, so only the whole slice could be recounted
+        if let Some(field) = &params.stratify {
+            error!("Backup {} in a bucket of {}.", problem, field);
+            continue;
+        }
+        let found = params.query_num_found(&slice.begin, &slice.end)?;
+        if found != slice.num_found {
+            info!("Backup {}, and now {} documents are found.", problem, found);
+        }
+        // Stdout was already finished as a single stream
+        if is_stdio(&params.transfer.dir) {
+            error!("Backup {}.", problem);
+            continue;
+        }
+        let expected = params.get_docs_to_retrieve(found);
+        let again = fetch_slice_again(params, schema, columns, &slice, expected)?;
+        if again == expected {
+            info!("Backup {}, so its {} documents were fetched again.", problem, again);
+            total = total - written + again;
+            problems -= 1;
+        } else {
+            error!("Backup {}, and fetching it again wrote {} of {}.", problem, again, expected);
+        }
+    }
+    let dropped = match params.duplicates {
+        Some(DuplicateMode::Drop) => stats.duplicates.get().copied().unwrap_or_default(),
+        _ => 0,
+    };
+    // Sampling and --limit round each slice up, so only missing documents are a problem
+    if total + dropped < num_retrieve {
+        let problem = format!("wrote {} of the {} documents to retrieve", total, num_retrieve);
+        if params.verify {
+            error!("Backup {}.", problem);
+        } else {
+            warn!("Backup {}.", problem);
+        }
+        problems += 1;
+    }
+    if problems == 0 {
+        return Ok(());
+    }
+    let msg = format!("The documents written differ from the ones found in {} checks.", problems);
+    if params.verify {
+        return fail_with(Error::Mismatch(msg));
+    }
+    warn!("{}", msg);
+    Ok(())
+}
+
+/// Retrieves again the documents of the `slice` into archives named apart from the other ones,
+/// returning how many were written
+fn fetch_slice_again(
+    params: &Backup, schema: &SolrCore, columns: &Columns, slice: &SliceTally, num_retrieve: u64,
+) -> BoxedResult<u64> {
+    let fields = params.merge_core_fields(schema);
+    let (begin, end) = (&slice.begin, &slice.end);
+    let requests = params.get_requests_for_range(slice.start, num_retrieve, &fields, 0, begin, end);
+    let pattern = params.get_refetch_pattern(num_retrieve);
+    let mut sink = open_sink(params, columns, &pattern)?;
+    let mut client = SolrClient::new();
+    let mut written = 0;
+    for step in requests {
+        let json = client.get_as_json(&step.url)?;
+        let Some(docs) = SolrCore::parse_docs_from_query(&json) else {
+            return fail_with(Error::Solr(format!("Error parsing the documents of {}", step.url)));
+        };
+        written += sink.write_documents(&Documents { step, docs: docs.to_string() })?;
+    }
+    sink.close_archive()?;
+    Ok(written)
+}
+
 // region Channels
 
-fn start_querying_core(params: &Backup, schema: &SolrCore, generator: Sender<Step>) {
-    let ctrl_c = monitor_term_sinal();
+fn start_querying_core(
+    params: &Backup, schema: &SolrCore, quotas: &HashMap<String, BucketQuota>,
+    generator: Sender<Step>, stats: &RunStats, failures: &ReadFailures,
+) {
+    let ctrl_c = params.options.hooks.aborting();
     let core_fields = params.merge_core_fields(schema);
 
     let slices: Slices<String> = params.get_slices();
     let partitions = slices.get_iterator();
     let mut retrieved = 0u64;
+    // Documents found and retrieved from each bucket of --stratify in previous slices
+    let mut taken: HashMap<String, (u64, u64)> = HashMap::new();
 
     'outer: for range in partitions {
-        let num_found = params.query_num_found(&range.begin, &range.end).unwrap_or(0);
-        if num_found == 0 {
-            continue;
-        }
-        let expected = if params.workaround_shards > 0 { num_found } else { 0 };
-        let num_retrieve = params.get_docs_to_retrieve(num_found);
-        let requests: Requests = params.get_requests_for_range(
-            retrieved,
-            num_retrieve,
-            &core_fields,
-            expected,
-            &range.begin,
-            &range.end,
-        );
-        for step in requests {
-            let status = generator.send(step);
-            if status.is_err() || ctrl_c.aborted() {
-                break 'outer;
+        let (begin, end) = (&range.begin, &range.end);
+        let counted = match &params.stratify {
+            None => params.query_num_found(begin, end).map(|found| vec![(found, EMPTY_STRING)]),
+            Some(_) => params.query_strata(begin, end).map(|strata| {
+                strata.into_iter().map(|(value, num_found)| (num_found, value)).collect()
+            }),
+        };
+        let parts = match counted {
+            Ok(parts) => parts,
+            Err(cause) => {
+                error!("Error counting the documents of slice {} to {}: {}", begin, end, cause);
+                // The slice is skipped as the failed requests tolerated by --max-errors
+                if failures.add(Error::from(cause)) > params.transfer.max_errors {
+                    break 'outer;
+                }
+                continue;
             }
+        };
+        for (num_found, bucket) in parts {
+            if num_found == 0 {
+                stats.add_slice(&range.begin, &range.end, 0, retrieved, 0);
+                continue;
+            }
+            let expected = if params.workaround_shards > 0 { num_found } else { 0 };
+            let num_retrieve = if params.stratify.is_some() {
+                let quota = quotas.get(&bucket).copied().unwrap_or_default();
+                let (seen, bucket_taken) = taken.entry(bucket.clone()).or_default();
+                let in_bucket = quota.share_of(*seen, num_found, *bucket_taken);
+                *seen += num_found;
+                *bucket_taken += in_bucket;
+                in_bucket
+            } else {
+                params.get_docs_to_retrieve(num_found)
+            };
+            if num_retrieve == 0 {
+                continue;
+            }
+            let mut requests: Requests = params.get_requests_for_range(
+                retrieved,
+                num_retrieve,
+                &core_fields,
+                expected,
+                &range.begin,
+                &range.end,
+            );
+            if params.stratify.is_some() {
+                requests.url.push_str(&params.get_bucket_filter(&bucket));
+            }
+            // Positions advance by the docs requested, keeping them under the total to retrieve
+            let rows = requests.count_rows();
+            stats.add_slice(&range.begin, &range.end, num_found, retrieved, rows);
+            for step in requests {
+                let status = generator.send(step);
+                if status.is_err() || ctrl_c.aborted() {
+                    break 'outer;
+                }
+            }
+            retrieved += rows;
         }
-        retrieved += num_found;
     }
     drop(generator);
 }
 
 fn start_retrieving_docs(
-    reader: u64, iterator: Receiver<Step>, producer: Sender<Documents>, max_errors: u64, delay: u64,
-) {
-    let ctrl_c = monitor_term_sinal();
-    let mut error_count = 0;
-
+    reader: u64, params: &Backup, iterator: Receiver<Step>, producer: Sender<Documents>,
+    ctrl_c: Arc<AtomicBool>, failures: &ReadFailures,
+) -> ThreadTally {
+    let (max_errors, delay) = (params.transfer.max_errors, params.transfer.delay_per_request);
+    // The documents retrieved are counted only for the report, as it parses each batch
+    let counting = params.transfer.report.is_some();
     let mut client = SolrClient::new();
+    let mut tally = ThreadTally::named(READER, reader);
     loop {
         let received = iterator.recv();
         if ctrl_c.aborted() {
             break;
         }
-        let failed = match received {
-            Ok(step) => retrieve_docs_from_solr(reader, &producer, step, &mut client),
-            Err(_) => true,
+        let Ok(step) = received else {
+            break;
         };
-        if failed {
-            if error_count < max_errors {
-                error_count += 1;
-            } else {
+        let retrieved =
+            retrieve_docs_from_solr(reader, &producer, step, &mut client, &mut tally, counting);
+        if let Err(cause) = retrieved {
+            tally.errors += 1;
+            // The failures of all readers count for --max-errors
+            if failures.add(cause) > max_errors {
                 break;
             }
         }
@@ -225,42 +497,39 @@ fn start_retrieving_docs(
         }
     }
     drop(producer);
+    tally.retries = client.get_retries();
+    tally
 }
 
 fn retrieve_docs_from_solr(
     reader: u64, producer: &Sender<Documents>, step: Step, client: &mut SolrClient,
-) -> bool {
+    tally: &mut ThreadTally, counting: bool,
+) -> Result<(), Error> {
     let query_url = step.url.as_str();
-    let response = fetch_docs_from_solr(reader, client, query_url, step.expected);
-    match response {
-        Err(_) => true,
-        Ok(content) => {
-            let parsed = SolrCore::parse_docs_from_query(&content);
-            match parsed {
-                None => {
-                    error!("Error in thread #{} parsing from solr query: {}", reader, query_url);
-                    true
-                }
-                Some(json) => {
-                    let docs = Documents { step, docs: json.to_string() };
-                    let status = producer.send(docs);
-                    status.is_err()
-                }
-            }
-        }
+    let content = fetch_docs_from_solr(reader, client, query_url, step.expected)?;
+    let Some(json) = SolrCore::parse_docs_from_query(&content) else {
+        error!("Error in thread #{} parsing from solr query: {}", reader, query_url);
+        return Err(Error::Solr(format!("Error parsing the documents of {}", query_url)));
+    };
+    let docs = Documents { step, docs: json.to_string() };
+    let count = if counting { count_docs(&docs.docs) } else { 0 };
+    tally.add_batch(count, docs.docs.len());
+    match producer.send(docs) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(Error::Archive("The writers stopped before storing the documents".into())),
     }
 }
 
 fn fetch_docs_from_solr(
     reader: u64, client: &mut SolrClient, query_url: &str, expected: u64,
-) -> Result<String, ()> {
+) -> Result<String, Error> {
     let mut times = 0;
     loop {
         let response = client.get_as_json(query_url);
         match response {
             Err(cause) => {
                 error!("Error in thread #{} retrieving docs from solr: {}", reader, cause);
-                return Err(());
+                return Err(Error::from(cause));
             }
             Ok(content) => {
                 if expected > 0 {
@@ -278,7 +547,7 @@ fn fetch_docs_from_solr(
                         }
                         Err(cause) => {
                             error!("Error in Solr response: {}", cause);
-                            return Err(());
+                            return Err(Error::Solr(cause.to_string()));
                         }
                     }
                 }
@@ -289,19 +558,40 @@ fn fetch_docs_from_solr(
 }
 
 fn start_storing_docs(
-    writer: u64, dir: PathBuf, name: String, compression: Compression, max: u64,
-    consumer: Receiver<Documents>, progress: Sender<u64>,
-) {
-    let mut archiver = Archiver::write_on(&dir, &name, compression, max.to_usize());
+    writer: u64, context: WriterContext<'_>, name: &str, consumer: Receiver<Documents>,
+    progress: Sender<u64>,
+) -> ThreadTally {
+    let WriterContext { params, columns, stats, tracker } = context;
+    let mut tally = ThreadTally::named(WRITER, writer);
+    let mut archiver = match open_sink(params, columns, name) {
+        Ok(archiver) => archiver,
+        Err(cause) => {
+            error!("Error in thread #{} creating the archive writer: {}", writer, cause);
+            tally.errors += 1;
+            return tally;
+        }
+    };
     loop {
         let received = consumer.recv();
         match received {
-            Ok(docs) => {
-                let failed = archiver.write_documents(&docs);
-                if let Err(cause) = failed {
-                    error!("Error in thread #{} writing file into archive: {}", writer, cause);
+            Ok(mut docs) => {
+                if let Some(keys) = tracker
+                    && let Err(cause) = keys.track(&mut docs)
+                {
+                    error!("Error in thread #{} tracking the uniqueKeys: {}", writer, cause);
+                    tally.errors += 1;
                     break;
                 }
+                let written = match archiver.write_documents(&docs) {
+                    Ok(written) => written,
+                    Err(cause) => {
+                        error!("Error in thread #{} writing file into archive: {}", writer, cause);
+                        tally.errors += 1;
+                        break;
+                    }
+                };
+                tally.add_batch(written, docs.docs.len());
+                stats.add_written(docs.step.curr, written);
                 let num_docs = docs.step.curr;
                 let status = progress.send(num_docs);
                 if status.is_err() {
@@ -312,6 +602,14 @@ fn start_storing_docs(
         }
     }
     drop(consumer);
+    // Publishes the last archive only when all its batches were written
+    let finished =
+        if tally.errors > 0 { archiver.abandon_archive() } else { archiver.close_archive() };
+    if let Err(cause) = finished {
+        error!("Error in thread #{} finishing the archive: {}", writer, cause);
+        tally.errors += 1;
+    }
+    tally
 }
 
 // endregion
@@ -1,5 +1,6 @@
 #![allow(dead_code)]
 
+use super::state::Hooks;
 use crossbeam_channel::Receiver;
 use indicatif::{ProgressBar, ProgressStyle};
 use std::time::{Duration, Instant};
@@ -14,29 +15,70 @@ fn new_wide_style() -> ProgressStyle {
     new_style(" [{elapsed_precise} | {eta_precise} | {pos}/{len} | {percent}%] [{wide_bar}] ")
 }
 
+fn new_docs_style() -> ProgressStyle {
+    new_style(
+        " [{elapsed_precise} | {eta_precise} | {pos}/{len} docs | {percent}%] [{wide_bar}] {msg}",
+    )
+}
+
 fn new_time_style() -> ProgressStyle {
     new_style(" [{elapsed_precise} | {eta_precise} | {percent}%] [{wide_bar}] {msg}")
 }
 
+fn new_counter_style() -> ProgressStyle {
+    ProgressStyle::default_spinner()
+        .template(" [{elapsed_precise} | {pos} docs] {spinner} {msg}")
+        .unwrap()
+}
+
 fn new_wide_bar(len: u64) -> ProgressBar {
     ProgressBar::new(len).with_style(new_wide_style())
 }
 
+fn new_docs_bar(len: u64) -> ProgressBar {
+    ProgressBar::new(len).with_style(new_docs_style())
+}
+
 fn new_time_bar(len: u64) -> ProgressBar {
     ProgressBar::new(len).with_style(new_time_style())
 }
 
+fn new_counter_bar() -> ProgressBar {
+    ProgressBar::new_spinner().with_style(new_counter_style())
+}
+
 // endregion
 
 // region implementarion
 
-pub(crate) fn foreach_progress(reporter: Receiver<u64>, total: u64, quiet: bool) -> u64 {
-    let mut updated = 0;
-    let perc_bar = if quiet { None } else { Some(new_wide_bar(total)) };
-    for num_increment in reporter.iter() {
+/// Receives the number of batches and documents processed and returns the total of batches.
+/// Shows the progress of documents when their total is known or else of batches.
+/// When neither is known, as when reading from stdin, only counts the documents.
+/// The progress goes to the callback of the `hooks` instead when there is one.
+pub(crate) fn foreach_progress(
+    reporter: Receiver<(u64, u64)>, batches: u64, docs: Option<u64>, quiet: bool, hooks: &Hooks,
+) -> u64 {
+    let (mut updated, mut restored) = (0, 0);
+    let perc_bar = match docs {
+        _ if quiet || hooks.progress.is_some() => None,
+        Some(total) => Some(new_docs_bar(total)),
+        None if batches == 0 => Some(new_counter_bar()),
+        None => Some(new_wide_bar(batches)),
+    };
+    for (num_batches, num_docs) in reporter.iter() {
+        updated += num_batches;
+        restored += num_docs;
+        hooks.report(restored, docs);
         if let Some(prog) = &perc_bar {
-            prog.inc(num_increment);
-            updated += num_increment;
+            if docs.is_some() {
+                prog.inc(num_docs);
+                prog.set_message(format!("{}/{} batches", updated, batches));
+            } else if batches == 0 {
+                prog.inc(num_docs);
+                prog.set_message(format!("{} batches", updated));
+            } else {
+                prog.inc(num_batches);
+            }
         }
     }
     if let Some(pg) = perc_bar {
@@ -46,15 +88,20 @@ pub(crate) fn foreach_progress(reporter: Receiver<u64>, total: u64, quiet: bool)
     updated
 }
 
-pub(crate) fn forall_progress(reporter: Receiver<u64>, total: u64, quiet: bool) -> u64 {
+pub(crate) fn forall_progress(
+    reporter: Receiver<u64>, total: u64, quiet: bool, hooks: &Hooks,
+) -> u64 {
     let mut updated = 0;
-    let perc_bar = if quiet { None } else { Some(new_wide_bar(total)) };
+    let hidden = quiet || hooks.progress.is_some();
+    let perc_bar = if hidden { None } else { Some(new_wide_bar(total)) };
     for position in reporter.iter() {
-        if position > updated
-            && let Some(prog) = &perc_bar
-        {
+        if position <= updated {
+            continue;
+        }
+        updated = position;
+        hooks.report(position, Some(total));
+        if let Some(prog) = &perc_bar {
             prog.set_position(position);
-            updated = position;
         }
     }
     if let Some(pg) = perc_bar {
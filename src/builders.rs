@@ -0,0 +1,418 @@
+use super::{
+    args::{Cli, Commands},
+    fails::Error,
+    models::{ArchiveFormat, Compression},
+    state::{CancelToken, Hooks, Progress},
+    wrangle::command_exec,
+};
+use clap::{Parser, ValueEnum};
+use std::{ffi::OsString, path::Path, sync::Arc};
+
+// region Invocation
+
+/// Arguments of the command run by a builder, parsed and validated like the command line ones
+#[derive(Clone, Debug)]
+struct Invocation {
+    args: Vec<OsString>,
+    hooks: Hooks,
+}
+
+impl Invocation {
+    fn new(command: &str, url: &str, core: &str) -> Self {
+        // Progress bars and logging setup are left to the application using the library
+        let args = ["solrcopy", command, "--url", url, "--core", core, "--log-level", "off"];
+        // A token of its own, so that the library never installs a Ctrl-C handler
+        let hooks = Hooks { cancel: Some(CancelToken::new()), progress: None };
+        Self { args: args.iter().map(OsString::from).collect(), hooks }
+    }
+
+    fn arg(mut self, name: &str, value: impl Into<OsString>) -> Self {
+        self.args.push(name.into());
+        self.args.push(value.into());
+        self
+    }
+
+    fn flag(mut self, name: &str) -> Self {
+        self.args.push(name.into());
+        self
+    }
+
+    fn list(self, name: &str, values: &[&str]) -> Self {
+        self.arg(name, values.join(","))
+    }
+
+    fn choice(self, name: &str, value: impl ValueEnum) -> Self {
+        let chosen = value.to_possible_value().map(|it| it.get_name().to_string());
+        self.arg(name, chosen.unwrap_or_default())
+    }
+
+    fn cancel_with(mut self, token: CancelToken) -> Self {
+        self.hooks.cancel = Some(token);
+        self
+    }
+
+    fn on_progress(mut self, callback: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        self.hooks.progress = Some(Arc::new(callback));
+        self
+    }
+
+    fn parse(&self) -> Result<Commands, Error> {
+        let parsed = Cli::try_parse_from(&self.args);
+        let mut cmds = parsed.map_err(|err| Error::InvalidArguments(err.to_string()))?.arguments;
+        cmds.validate().map_err(Error::InvalidArguments)?;
+        if let Some(options) = cmds.get_options_mut() {
+            options.hooks = self.hooks.clone();
+        }
+        Ok(cmds)
+    }
+
+    fn run(&self) -> Result<(), Error> {
+        let cmds = self.parse()?;
+        let result = command_exec(&cmds);
+        if self.hooks.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+            return Err(Error::Cancelled);
+        }
+        result.map_err(Error::from)
+    }
+}
+
+// endregion
+
+// region Backup
+
+/// Dumps documents from a Solr core into backup files like `solrcopy backup`
+#[derive(Clone, Debug)]
+pub struct BackupBuilder {
+    call: Invocation,
+}
+
+impl BackupBuilder {
+    /// Backup of the documents of the `core` into files written in the `dir` folder,
+    /// which can also be a `s3://bucket/prefix` location
+    pub fn new(url: &str, core: &str, dir: impl AsRef<Path>) -> Self {
+        let call = Invocation::new("backup", url, core).arg("--dir", dir.as_ref());
+        Self { call }
+    }
+
+    /// Solr query param `q` for filtering which documents are retrieved
+    pub fn query(self, query: &str) -> Self {
+        Self { call: self.call.arg("--query", query) }
+    }
+
+    /// Solr filter query param `fq` for filtering which documents are retrieved
+    pub fn filter(self, fq: &str) -> Self {
+        Self { call: self.call.arg("--fq", fq) }
+    }
+
+    /// Adds a field for sorting the documents retrieved
+    pub fn sort_by(self, field: &str, ascending: bool) -> Self {
+        let direction = if ascending { "asc" } else { "desc" };
+        Self { call: self.call.arg("--order", format!("{}:{}", field, direction)) }
+    }
+
+    /// Fields retrieved in each document instead of all but the internal ones
+    pub fn select(self, fields: &[&str]) -> Self {
+        Self { call: self.call.list("--select", fields) }
+    }
+
+    /// Fields left out of each document
+    pub fn exclude(self, fields: &[&str]) -> Self {
+        Self { call: self.call.list("--exclude", fields) }
+    }
+
+    pub fn skip(self, docs: u64) -> Self {
+        Self { call: self.call.arg("--skip", docs.to_string()) }
+    }
+
+    pub fn limit(self, docs: u64) -> Self {
+        Self { call: self.call.arg("--limit", docs.to_string()) }
+    }
+
+    /// Documents retrieved from Solr in each request
+    pub fn num_docs(self, docs: u64) -> Self {
+        Self { call: self.call.arg("--num-docs", docs.to_string()) }
+    }
+
+    /// Batches of documents stored in each backup file
+    pub fn archive_files(self, batches: u64) -> Self {
+        Self { call: self.call.arg("--archive-files", batches.to_string()) }
+    }
+
+    pub fn archive_prefix(self, prefix: &str) -> Self {
+        Self { call: self.call.arg("--archive-prefix", prefix) }
+    }
+
+    pub fn archive_format(self, format: ArchiveFormat) -> Self {
+        Self { call: self.call.choice("--archive-format", format) }
+    }
+
+    pub fn compression(self, compression: Compression) -> Self {
+        Self { call: self.call.choice("--archive-compression", compression) }
+    }
+
+    /// Encrypts the zip archives with AES-256 using this passphrase
+    pub fn passphrase(self, passphrase: &str) -> Self {
+        Self { call: self.call.arg("--passphrase", passphrase) }
+    }
+
+    /// Threads retrieving documents from Solr
+    pub fn readers(self, count: u64) -> Self {
+        Self { call: self.call.arg("--readers", count.to_string()) }
+    }
+
+    /// Threads writing the backup files
+    pub fn writers(self, count: u64) -> Self {
+        Self { call: self.call.arg("--writers", count.to_string()) }
+    }
+
+    /// Failed requests tolerated before giving up
+    pub fn max_errors(self, count: u64) -> Self {
+        Self { call: self.call.arg("--max-errors", count.to_string()) }
+    }
+
+    /// Fails when the documents written differ from the ones found by the query
+    pub fn verify(self) -> Self {
+        Self { call: self.call.flag("--verify") }
+    }
+
+    pub fn cancel_with(self, token: CancelToken) -> Self {
+        Self { call: self.call.cancel_with(token) }
+    }
+
+    /// Called from a worker thread each time documents are stored
+    pub fn on_progress(self, callback: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        Self { call: self.call.on_progress(callback) }
+    }
+
+    pub fn run(&self) -> Result<(), Error> {
+        self.call.run()
+    }
+}
+
+// endregion
+
+// region Restore
+
+/// Restores documents from backup files into a Solr core like `solrcopy restore`
+#[derive(Clone, Debug)]
+pub struct RestoreBuilder {
+    call: Invocation,
+}
+
+impl RestoreBuilder {
+    /// Restore of the backup files found in the `dir` folder, or `s3://bucket/prefix`
+    /// location, into the `core`
+    pub fn new(url: &str, core: &str, dir: impl AsRef<Path>) -> Self {
+        let call = Invocation::new("restore", url, core).arg("--dir", dir.as_ref());
+        Self { call }
+    }
+
+    /// Pattern matching the names of the backup files to restore
+    pub fn search(self, pattern: &str) -> Self {
+        Self { call: self.call.arg("--search", pattern) }
+    }
+
+    /// Format of the backup files searched when no pattern is given
+    pub fn archive_format(self, format: ArchiveFormat) -> Self {
+        Self { call: self.call.choice("--archive-format", format) }
+    }
+
+    /// Name of the uniqueKey field of the core
+    pub fn unique_key(self, field: &str) -> Self {
+        Self { call: self.call.arg("--unique-key", field) }
+    }
+
+    /// Skips the entries of the backup files that cannot be read instead of the whole file
+    pub fn skip_corrupted(self) -> Self {
+        Self { call: self.call.flag("--skip-corrupted") }
+    }
+
+    pub fn no_final_commit(self) -> Self {
+        Self { call: self.call.flag("--no-final-commit") }
+    }
+
+    /// Checks the count and a sample of the documents in the core after restoring them
+    pub fn verify(self) -> Self {
+        Self { call: self.call.flag("--verify") }
+    }
+
+    /// Decrypts the zip archives encrypted with this passphrase
+    pub fn passphrase(self, passphrase: &str) -> Self {
+        Self { call: self.call.arg("--passphrase", passphrase) }
+    }
+
+    /// Threads reading the backup files
+    pub fn readers(self, count: u64) -> Self {
+        Self { call: self.call.arg("--readers", count.to_string()) }
+    }
+
+    /// Threads sending documents to Solr
+    pub fn writers(self, count: u64) -> Self {
+        Self { call: self.call.arg("--writers", count.to_string()) }
+    }
+
+    /// Failed updates and unreadable entries tolerated before giving up
+    pub fn max_errors(self, count: u64) -> Self {
+        Self { call: self.call.arg("--max-errors", count.to_string()) }
+    }
+
+    pub fn cancel_with(self, token: CancelToken) -> Self {
+        Self { call: self.call.cancel_with(token) }
+    }
+
+    /// Called from a worker thread each time documents are updated in Solr
+    pub fn on_progress(self, callback: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        Self { call: self.call.on_progress(callback) }
+    }
+
+    pub fn run(&self) -> Result<(), Error> {
+        self.call.run()
+    }
+}
+
+// endregion
+
+// region Copy
+
+/// Copies the documents modified since the last run between cores like `solrcopy sync`
+#[derive(Clone, Debug)]
+pub struct CopyBuilder {
+    call: Invocation,
+}
+
+impl CopyBuilder {
+    /// Copy of the documents of the `core` into the `target_core` whose date `field` changed
+    /// since the value kept in the `state_file` by the last run
+    pub fn new(
+        url: &str, core: &str, target_core: &str, field: &str, state_file: impl AsRef<Path>,
+    ) -> Self {
+        let call = Invocation::new("sync", url, core)
+            .arg("--target-core", target_core)
+            .arg("--field", field)
+            .arg("--state-file", state_file.as_ref());
+        Self { call }
+    }
+
+    /// Url of the Solr cluster of the target core when different
+    pub fn target_url(self, url: &str) -> Self {
+        Self { call: self.call.arg("--target-url", url) }
+    }
+
+    /// Date in ISO 8601 format for the first run, before the state file exists
+    pub fn since(self, date: &str) -> Self {
+        Self { call: self.call.arg("--since", date) }
+    }
+
+    /// Solr query param `q` for filtering which documents are copied
+    pub fn query(self, query: &str) -> Self {
+        Self { call: self.call.arg("--query", query) }
+    }
+
+    /// Solr filter query param `fq` for filtering which documents are copied
+    pub fn filter(self, fq: &str) -> Self {
+        Self { call: self.call.arg("--fq", fq) }
+    }
+
+    pub fn select(self, fields: &[&str]) -> Self {
+        Self { call: self.call.list("--select", fields) }
+    }
+
+    /// Removes from the target core the documents not found anymore in the source core
+    pub fn delete_missing(self) -> Self {
+        Self { call: self.call.flag("--delete-missing") }
+    }
+
+    pub fn cancel_with(self, token: CancelToken) -> Self {
+        Self { call: self.call.cancel_with(token) }
+    }
+
+    pub fn run(&self) -> Result<(), Error> {
+        self.call.run()
+    }
+}
+
+// endregion
+
+// region Admin
+
+/// Administrative operations on a Solr core like `solrcopy commit`, `create` and `delete`
+#[derive(Clone, Debug)]
+pub struct AdminBuilder {
+    url: String,
+    core: String,
+}
+
+impl AdminBuilder {
+    pub fn new(url: &str, core: &str) -> Self {
+        Self { url: url.to_string(), core: core.to_string() }
+    }
+
+    /// Persists the documents updated in the core
+    pub fn commit(&self) -> Result<(), Error> {
+        Invocation::new("commit", &self.url, &self.core).run()
+    }
+
+    /// Creates the core with the default configset
+    pub fn create(&self) -> Result<(), Error> {
+        Invocation::new("create", &self.url, &self.core).run()
+    }
+
+    /// Removes definitively the documents matching the `query` from the core
+    pub fn delete(&self, query: &str) -> Result<(), Error> {
+        Invocation::new("delete", &self.url, &self.core).arg("--query", query).run()
+    }
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use super::{BackupBuilder, RestoreBuilder};
+    use crate::{
+        args::{Commands, SortDirection},
+        fails::Error,
+        models::{ArchiveFormat, Compression},
+        state::CancelToken,
+    };
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn check_builder_args() {
+        let token = CancelToken::new();
+        let builder = BackupBuilder::new("http://localhost:8983/solr", "demo", "target")
+            .query("price:[1 TO *]")
+            .sort_by("id", false)
+            .select(&["id", "price"])
+            .archive_format(ArchiveFormat::Jsonl)
+            .compression(Compression::Zstd)
+            .cancel_with(token.clone())
+            .on_progress(|_| {});
+        let Ok(Commands::Backup(get)) = builder.call.parse() else {
+            panic!("Backup builder should give a backup command");
+        };
+        token.cancel();
+
+        assert_eq!(get.query.as_deref(), Some("price:[1 TO *]"));
+        assert_eq!(get.order[0].field, "id");
+        assert_eq!(get.order[0].direction, SortDirection::Desc);
+        assert_eq!(get.select, vec!["id", "price"]);
+        assert_eq!(get.archive_format, ArchiveFormat::Jsonl);
+        assert_eq!(get.archive_compression, Compression::Zstd);
+        assert_eq!(get.options.is_quiet(), true);
+        assert_eq!(get.options.hooks.progress.is_some(), true);
+        assert_eq!(get.options.hooks.aborting().load(std::sync::atomic::Ordering::SeqCst), true);
+    }
+
+    #[test]
+    fn check_builder_errors() {
+        let invalid =
+            RestoreBuilder::new("http://localhost:8983/solr", "demo", "target").readers(0).run();
+        let missing =
+            RestoreBuilder::new("http://localhost:8983/solr", "demo", "target/missing").run();
+
+        assert!(matches!(invalid, Err(Error::InvalidArguments(_))));
+        assert!(matches!(missing, Err(Error::InvalidArguments(_))));
+    }
+}
+
+// end of file \\
@@ -0,0 +1,569 @@
+use super::{
+    args::Backup,
+    fails::{BoxedResult, throw},
+    helpers::IntegerHelpers,
+    models::{ArchiveFormat, Columns, Compression, Documents},
+    save::{partial_path, publish_file, warn_unpublished},
+    storage::DocumentSink,
+};
+use arrow_array::{
+    Array, ArrayRef, BooleanArray, Float32Array, Float64Array, Int32Array, Int64Array, ListArray,
+    RecordBatch, StringArray, TimestampMillisecondArray,
+};
+use arrow_buffer::{NullBuffer, OffsetBuffer};
+use arrow_schema::{DataType, Field, FieldRef, Schema, SchemaRef, TimeUnit};
+use chrono::DateTime;
+use log::{error, warn};
+use parquet::{
+    arrow::ArrowWriter,
+    basic::{Compression as Codec, GzipLevel, ZstdLevel},
+    file::properties::WriterProperties,
+};
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+type Doc = Map<String, Value>;
+
+// region Schema
+
+impl Backup {
+    /// Arrow types of the columns declared in the Solr schema, needed only for writing parquet.
+    /// The columns missing here are inferred later from the documents retrieved
+    pub(crate) fn get_field_types(&self, names: &[String]) -> HashMap<String, DataType> {
+        if self.archive_format != ArchiveFormat::Parquet {
+            return HashMap::new();
+        }
+        match self.options.query_schema() {
+            Ok(schema) => resolve_field_types(&schema, names),
+            Err(cause) => {
+                warn!("Inferring the parquet columns from the documents: {}", cause);
+                HashMap::new()
+            }
+        }
+    }
+}
+
+/// Maps the columns to the Arrow type of the field, or dynamic field, declared in the `schema`
+fn resolve_field_types(schema: &Value, names: &[String]) -> HashMap<String, DataType> {
+    let list = |key: &str| schema.get(key).and_then(Value::as_array).cloned().unwrap_or_default();
+    let (fields, mut dynamic, field_types) =
+        (list("fields"), list("dynamicFields"), list("fieldTypes"));
+    // Solr matches the longest dynamic field pattern first
+    dynamic.sort_by_key(|field| std::cmp::Reverse(get_text(field, "name").len()));
+
+    let mut res = HashMap::new();
+    for name in names {
+        let declared = fields.iter().find(|field| get_text(field, "name") == name);
+        let matched = declared.or_else(|| {
+            dynamic.iter().find(|field| matches_dynamic(get_text(field, "name"), name))
+        });
+        let Some(field) = matched else {
+            continue;
+        };
+        let type_name = get_text(field, "type");
+        let field_type = field_types.iter().find(|found| get_text(found, "name") == type_name);
+        let multi_valued = field
+            .get("multiValued")
+            .or_else(|| field_type.and_then(|found| found.get("multiValued")))
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let class = field_type.map_or("", |found| get_text(found, "class"));
+        let scalar = get_class_type(class);
+        let data_type = if multi_valued { to_list(scalar) } else { scalar };
+        res.insert(name.clone(), data_type);
+    }
+    res
+}
+
+fn get_text<'a>(item: &'a Value, key: &str) -> &'a str {
+    item.get(key).and_then(Value::as_str).unwrap_or_default()
+}
+
+fn matches_dynamic(pattern: &str, name: &str) -> bool {
+    match (pattern.strip_prefix('*'), pattern.strip_suffix('*')) {
+        (Some(suffix), _) => name.ends_with(suffix),
+        (_, Some(prefix)) => name.starts_with(prefix),
+        _ => false,
+    }
+}
+
+/// Arrow type for the values of a Solr field type class like `solr.IntPointField`
+fn get_class_type(class: &str) -> DataType {
+    let simple = class.rsplit('.').next().unwrap_or_default();
+    match simple {
+        "IntPointField" | "TrieIntField" => DataType::Int32,
+        "LongPointField" | "TrieLongField" => DataType::Int64,
+        "FloatPointField" | "TrieFloatField" => DataType::Float32,
+        "DoublePointField" | "TrieDoubleField" => DataType::Float64,
+        "BoolField" => DataType::Boolean,
+        "DatePointField" | "TrieDateField" => get_timestamp_type(),
+        _ => DataType::Utf8,
+    }
+}
+
+fn get_timestamp_type() -> DataType {
+    DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into()))
+}
+
+fn to_list(item: DataType) -> DataType {
+    DataType::List(Arc::new(Field::new_list_field(item, true)))
+}
+
+/// Builds the Arrow schema with the types known and the ones sampled from the `docs`
+fn infer_schema(columns: &Columns, docs: &[Doc]) -> SchemaRef {
+    let fields: Vec<Field> = columns
+        .names
+        .iter()
+        .map(|name| {
+            let known = columns.types.get(name).cloned();
+            let data_type = known.unwrap_or_else(|| sample_type(docs, name));
+            Field::new(name, data_type, true)
+        })
+        .collect();
+    Arc::new(Schema::new(fields))
+}
+
+/// Infers the type of the column from the values found in the `docs`, defaulting to text
+fn sample_type(docs: &[Doc], name: &str) -> DataType {
+    let mut multi_valued = false;
+    let mut sampled: Option<DataType> = None;
+    for value in docs.iter().filter_map(|doc| doc.get(name)) {
+        let items = match value {
+            Value::Array(items) => {
+                multi_valued = true;
+                items.iter().collect()
+            }
+            single => vec![single],
+        };
+        for item in items.into_iter().filter(|item| !item.is_null()) {
+            let found = match item {
+                Value::Bool(_) => DataType::Boolean,
+                Value::Number(num) if num.is_i64() => DataType::Int64,
+                Value::Number(_) => DataType::Float64,
+                _ => DataType::Utf8,
+            };
+            sampled = Some(match sampled {
+                None => found,
+                Some(prev) if prev == found => prev,
+                Some(DataType::Int64 | DataType::Float64)
+                    if matches!(found, DataType::Int64 | DataType::Float64) =>
+                {
+                    DataType::Float64
+                }
+                Some(_) => DataType::Utf8,
+            });
+        }
+    }
+    let scalar = sampled.unwrap_or(DataType::Utf8);
+    if multi_valued { to_list(scalar) } else { scalar }
+}
+
+/// Schema with the columns widened for holding all values of the `docs`, if any needs it
+fn widen_schema(schema: &SchemaRef, docs: &[Doc]) -> Option<SchemaRef> {
+    let mut changed = false;
+    let fields: Vec<Field> = schema
+        .fields()
+        .iter()
+        .map(|field| {
+            let values = docs.iter().filter_map(|doc| doc.get(field.name()));
+            let widened = values.fold(field.data_type().clone(), |prev, it| widen_type(&prev, it));
+            if &widened == field.data_type() {
+                field.as_ref().clone()
+            } else {
+                warn!("Widening the parquet column {} to {}.", field.name(), widened);
+                changed = true;
+                Field::new(field.name(), widened, true)
+            }
+        })
+        .collect();
+    if changed { Some(Arc::new(Schema::new(fields))) } else { None }
+}
+
+/// Narrowest type holding both the values of the `data_type` and the `value`:
+/// integers widen to Int64 and then to Float64, and other mismatches to text
+fn widen_type(data_type: &DataType, value: &Value) -> DataType {
+    match (data_type, value) {
+        (_, Value::Null) => data_type.clone(),
+        (DataType::List(item), Value::Array(items)) => {
+            let scalar = item.data_type();
+            let widened = items.iter().fold(scalar.clone(), |prev, it| widen_type(&prev, it));
+            if &widened == scalar { data_type.clone() } else { to_list(widened) }
+        }
+        (DataType::List(item), single) => {
+            let widened = widen_type(item.data_type(), single);
+            if &widened == item.data_type() { data_type.clone() } else { to_list(widened) }
+        }
+        (scalar, Value::Array(items)) => {
+            to_list(items.iter().fold(scalar.clone(), |prev, it| widen_type(&prev, it)))
+        }
+        (scalar, single) if fits_type(scalar, single) => scalar.clone(),
+        (DataType::Int32, single) if to_i64(single).is_some() => DataType::Int64,
+        (DataType::Int32 | DataType::Int64, single) if to_f64(single).is_some() => {
+            DataType::Float64
+        }
+        _ => DataType::Utf8,
+    }
+}
+
+/// Tells if the single `value` can be converted to the scalar `data_type`
+fn fits_type(data_type: &DataType, value: &Value) -> bool {
+    match data_type {
+        DataType::Boolean => to_bool(value).is_some(),
+        DataType::Int32 => to_i64(value).is_some_and(|num| i32::try_from(num).is_ok()),
+        DataType::Int64 => to_i64(value).is_some(),
+        DataType::Float32 | DataType::Float64 => to_f64(value).is_some(),
+        DataType::Timestamp(_, _) => to_millis(value).is_some(),
+        _ => true,
+    }
+}
+
+// endregion
+
+// region Conversion
+
+fn to_record_batch(schema: &SchemaRef, docs: &[Doc]) -> BoxedResult<RecordBatch> {
+    let mut arrays: Vec<ArrayRef> = vec![];
+    for field in schema.fields() {
+        let values: Vec<Option<&Value>> = docs.iter().map(|doc| doc.get(field.name())).collect();
+        let array = match field.data_type() {
+            DataType::List(item) => build_list(item, &values)?,
+            scalar => build_scalar(scalar, &values)?,
+        };
+        arrays.push(array);
+    }
+    Ok(RecordBatch::try_new(schema.clone(), arrays)?)
+}
+
+fn build_list(item: &FieldRef, values: &[Option<&Value>]) -> BoxedResult<ArrayRef> {
+    let mut items: Vec<Option<&Value>> = vec![];
+    let mut lengths = Vec::with_capacity(values.len());
+    let mut valid = Vec::with_capacity(values.len());
+    for value in values {
+        match value {
+            None | Some(Value::Null) => lengths.push(0),
+            Some(Value::Array(list)) => {
+                items.extend(list.iter().map(Some));
+                lengths.push(list.len());
+            }
+            Some(single) => {
+                items.push(Some(single));
+                lengths.push(1);
+            }
+        }
+        valid.push(value.is_some_and(|found| !found.is_null()));
+    }
+    let offsets = OffsetBuffer::from_lengths(lengths);
+    let array = build_scalar(item.data_type(), &items)?;
+    let list = ListArray::try_new(item.clone(), offsets, array, Some(NullBuffer::from(valid)))?;
+    Ok(Arc::new(list))
+}
+
+/// Converts the values to the `data_type`, failing when any of them does not fit in it
+fn build_scalar(data_type: &DataType, values: &[Option<&Value>]) -> BoxedResult<ArrayRef> {
+    let found = values.iter().map(|value| value.filter(|item| !item.is_null()));
+    let nulls = found.clone().filter(Option::is_none).count();
+    let array: ArrayRef = match data_type {
+        DataType::Boolean => {
+            Arc::new(found.map(|value| value.and_then(to_bool)).collect::<BooleanArray>())
+        }
+        DataType::Int32 => {
+            let parsed =
+                found.map(|value| value.and_then(to_i64).and_then(|num| num.try_into().ok()));
+            Arc::new(parsed.collect::<Int32Array>())
+        }
+        DataType::Int64 => {
+            Arc::new(found.map(|value| value.and_then(to_i64)).collect::<Int64Array>())
+        }
+        DataType::Float32 => {
+            let parsed = found.map(|value| value.and_then(to_f64).map(|num| num as f32));
+            Arc::new(parsed.collect::<Float32Array>())
+        }
+        DataType::Float64 => {
+            Arc::new(found.map(|value| value.and_then(to_f64)).collect::<Float64Array>())
+        }
+        DataType::Timestamp(_, zone) => {
+            let parsed = found.map(|value| value.and_then(to_millis));
+            Arc::new(parsed.collect::<TimestampMillisecondArray>().with_timezone_opt(zone.clone()))
+        }
+        _ => Arc::new(found.map(|value| value.map(to_text)).collect::<StringArray>()),
+    };
+    if array.null_count() > nulls {
+        return throw(format!("Found values not fitting in the parquet type {}", data_type));
+    }
+    Ok(array)
+}
+
+fn to_bool(value: &Value) -> Option<bool> {
+    value.as_bool().or_else(|| value.as_str()?.parse().ok())
+}
+
+fn to_i64(value: &Value) -> Option<i64> {
+    value.as_i64().or_else(|| value.as_str()?.parse().ok())
+}
+
+fn to_f64(value: &Value) -> Option<f64> {
+    value.as_f64().or_else(|| value.as_str()?.parse().ok())
+}
+
+fn to_millis(value: &Value) -> Option<i64> {
+    let parsed = DateTime::parse_from_rfc3339(value.as_str()?).ok()?;
+    Some(parsed.timestamp_millis())
+}
+
+fn to_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+// endregion
+
+// region ParquetWriter
+
+/// Writes the documents as parquet files for being read by analytics tools
+pub(crate) struct ParquetWriter {
+    writer: Option<ArrowWriter<File>>,
+    /// Path of the file being written
+    current: Option<PathBuf>,
+    /// Schema of the file being written
+    schema: Option<SchemaRef>,
+    columns: Columns,
+    folder: PathBuf,
+    compression: Compression,
+    file_pattern: String,
+    max_files: usize,
+    file_count: usize,
+    row_group_size: usize,
+    file_size: usize,
+}
+
+impl ParquetWriter {
+    pub(crate) fn write_on(
+        output_dir: &Path, output_pattern: &str, compression: Compression, max: usize,
+        columns: &Columns, row_group_size: usize, file_size: usize,
+    ) -> Self {
+        ParquetWriter {
+            writer: None,
+            current: None,
+            schema: None,
+            columns: columns.clone(),
+            folder: output_dir.to_owned(),
+            compression,
+            file_pattern: output_pattern.to_string(),
+            max_files: max,
+            file_count: 0,
+            row_group_size,
+            file_size,
+        }
+    }
+
+    fn create_file(&mut self, suffix: &str, schema: SchemaRef) -> BoxedResult<()> {
+        self.close_archive()?;
+
+        let file_name = self.file_pattern.replace("{}", suffix);
+        let file_path = self.folder.join(file_name);
+        let codec = match self.compression {
+            Compression::Stored => Codec::UNCOMPRESSED,
+            Compression::Zip => Codec::GZIP(GzipLevel::default()),
+            Compression::Zstd => Codec::ZSTD(ZstdLevel::default()),
+        };
+        let props = WriterProperties::builder().set_compression(codec).build();
+        let writer = ArrowWriter::try_new(
+            File::create(partial_path(&file_path))?,
+            schema.clone(),
+            Some(props),
+        )?;
+
+        self.writer = Some(writer);
+        self.current = Some(file_path);
+        self.schema = Some(schema);
+        self.file_count = 0;
+        Ok(())
+    }
+
+    /// Schema shared by all writers, inferred from the first batch of the backup and widened
+    /// until all values of the `docs` fit in it
+    fn resolve_schema(&self, docs: &[Doc]) -> SchemaRef {
+        let mut shared = self.columns.schema.lock().unwrap();
+        let current = shared.get_or_insert_with(|| infer_schema(&self.columns, docs));
+        if let Some(widened) = widen_schema(current, docs) {
+            *current = widened;
+        }
+        current.clone()
+    }
+}
+
+impl DocumentSink for ParquetWriter {
+    /// Writes the parquet footer, renames the current file and writes its `.sha256` file
+    fn close_archive(&mut self) -> BoxedResult<()> {
+        if let Some(writer) = self.writer.take() {
+            writer.close()?;
+        }
+        if let Some(file_path) = self.current.take() {
+            publish_file(file_path)?;
+        }
+        Ok(())
+    }
+
+    fn abandon_archive(&mut self) -> BoxedResult<()> {
+        warn_unpublished(self.current.take());
+        if let Some(writer) = self.writer.take() {
+            writer.close()?;
+        }
+        Ok(())
+    }
+
+    fn write_documents(&mut self, docs: &Documents) -> BoxedResult<u64> {
+        let json = &docs.docs;
+        if json.len() <= 2 {
+            return Ok(0);
+        }
+        let parsed: Vec<Doc> = serde_json::from_str(json)?;
+        let schema = self.resolve_schema(&parsed);
+
+        self.file_count += 1;
+        let too_big = self.writer.as_ref().is_some_and(|wr| wr.bytes_written() >= self.file_size);
+        // A parquet file has a single schema, so a widened column starts a new file
+        let widened = self.schema.as_ref().is_some_and(|current| *current != schema);
+        let wrap = self.file_count >= self.max_files || too_big || widened;
+
+        if self.writer.is_none() || wrap {
+            let suffix = format!("{:09}", docs.step.curr + 1);
+            self.create_file(&suffix, schema.clone())?;
+        }
+        let batch = to_record_batch(&schema, &parsed)?;
+        let writer = self.writer.as_mut().unwrap();
+        writer.write(&batch)?;
+        if writer.in_progress_size() >= self.row_group_size {
+            writer.flush()?;
+        }
+        Ok(parsed.len().to_u64())
+    }
+}
+
+impl Drop for ParquetWriter {
+    fn drop(&mut self) {
+        let fail = self.abandon_archive();
+        if let Err(cause) = fail {
+            error!("> Dropping {}", cause);
+        }
+    }
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use super::{ParquetWriter, get_timestamp_type, resolve_field_types, to_list, widen_type};
+    use crate::helpers::{IntegerHelpers, shared::TempDir};
+    use crate::models::{Columns, Compression, Documents, Step};
+    use crate::storage::DocumentSink;
+    use arrow_array::{Array, Float64Array, StringArray};
+    use arrow_schema::DataType;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use std::{collections::HashMap, fs};
+
+    #[test]
+    fn check_resolve_field_types() {
+        let schema = json!({
+            "fields": [
+                {"name": "id", "type": "string"},
+                {"name": "price", "type": "pfloat"},
+                {"name": "tags", "type": "string", "multiValued": true},
+            ],
+            "dynamicFields": [
+                {"name": "*_dt", "type": "pdate"},
+                {"name": "*_is", "type": "pints"},
+            ],
+            "fieldTypes": [
+                {"name": "string", "class": "solr.StrField"},
+                {"name": "pfloat", "class": "solr.FloatPointField"},
+                {"name": "pdate", "class": "solr.DatePointField"},
+                {"name": "pints", "class": "solr.IntPointField", "multiValued": true},
+            ],
+        });
+        let names = ["id", "price", "tags", "born_dt", "sizes_is", "other"].map(String::from);
+        let resolved = resolve_field_types(&schema, &names);
+
+        let expected = HashMap::from([
+            ("id".to_string(), DataType::Utf8),
+            ("price".to_string(), DataType::Float32),
+            ("tags".to_string(), to_list(DataType::Utf8)),
+            ("born_dt".to_string(), get_timestamp_type()),
+            ("sizes_is".to_string(), to_list(DataType::Int32)),
+        ]);
+        assert_eq!(resolved, expected);
+    }
+
+    #[test]
+    fn check_parquet_writer() {
+        let dir = TempDir::new("parquet");
+
+        let names = ["id", "born_dt", "score", "tags", "missing"].map(String::from).to_vec();
+        let types = HashMap::from([("born_dt".to_string(), get_timestamp_type())]);
+        let columns = Columns { names, types, ..Default::default() };
+        let batches = [
+            json!([{"id": "1", "born_dt": "2020-01-02T03:04:05Z", "score": 3, "tags": ["a", "b"]}]),
+            json!([{"id": "2", "born_dt": "unknown", "score": 4.5, "tags": ["c"]}, {"id": "3"}]),
+        ];
+        let pattern = "demo_seq_{}.parquet";
+        let mut writer =
+            ParquetWriter::write_on(&dir, pattern, Compression::Zstd, 10, &columns, 1, 1 << 30);
+        for (curr, batch) in batches.iter().enumerate() {
+            let step = Step { curr: curr.to_u64(), expected: 0, url: String::new() };
+            writer.write_documents(&Documents { step, docs: batch.to_string() }).unwrap();
+        }
+        writer.close_archive().unwrap();
+
+        let file = fs::File::open(dir.join("demo_seq_000000001.parquet")).unwrap();
+        let reader = SerializedFileReader::new(file).unwrap();
+        let meta = reader.metadata();
+        let schema = meta.file_metadata().schema_descr();
+        let leaves: Vec<String> = schema.columns().iter().map(|col| col.path().string()).collect();
+        let rows = meta.file_metadata().num_rows();
+        // The second batch does not fit in the columns inferred from the first one
+        let widened = fs::File::open(dir.join("demo_seq_000000002.parquet")).unwrap();
+        let mut batches =
+            ParquetRecordBatchReaderBuilder::try_new(widened).unwrap().build().unwrap();
+        let batch = batches.next().unwrap().unwrap();
+
+        let score = batch.column_by_name("score").unwrap();
+        let score = score.as_any().downcast_ref::<Float64Array>().unwrap();
+        let born = batch.column_by_name("born_dt").unwrap();
+        let born = born.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(rows, 1);
+        assert_eq!(leaves, ["id", "born_dt", "score", "tags.list.item", "missing"]);
+        assert_eq!(batch.num_rows(), 2);
+        assert_eq!((score.value(0), score.is_null(1)), (4.5, true));
+        assert_eq!(born.value(0), "unknown");
+        let shared = columns.schema.lock().unwrap().clone().unwrap();
+        assert_eq!(shared.field_with_name("score").unwrap().data_type(), &DataType::Float64);
+    }
+
+    #[test]
+    fn check_widen_type() {
+        let int32 = DataType::Int32;
+        assert_eq!(widen_type(&int32, &json!(7)), DataType::Int32);
+        assert_eq!(widen_type(&int32, &json!(1i64 << 40)), DataType::Int64);
+        assert_eq!(widen_type(&DataType::Int64, &json!(4.5)), DataType::Float64);
+        assert_eq!(widen_type(&DataType::Boolean, &json!("maybe")), DataType::Utf8);
+        assert_eq!(widen_type(&get_timestamp_type(), &json!("NOW")), DataType::Utf8);
+        assert_eq!(
+            widen_type(&to_list(int32.clone()), &json!([1, 2.5])),
+            to_list(DataType::Float64)
+        );
+        assert_eq!(widen_type(&DataType::Utf8, &json!(["a"])), to_list(DataType::Utf8));
+        assert_eq!(widen_type(&int32, &json!(null)), DataType::Int32);
+    }
+}
+
+// end of file \\
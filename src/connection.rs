@@ -9,7 +9,7 @@
 
 // region SolrError
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub(crate) struct SolrError {
     pub details: String,
     pub code: Option<u16>,
@@ -71,6 +71,8 @@ pub(crate) struct SolrClient {
     http: ureq::Agent,
     max_retries: usize,
     retry_count: usize,
+    /// Requests repeated since the client was created
+    retried: u64,
 }
 
 // TODO: authentication, proxy, etc...
@@ -101,7 +103,11 @@ pub(crate) fn new() -> Self {
         let builder = Agent::config_builder().timeout_global(duration).build();
         let client = builder.into();
 
-        SolrClient { http: client, max_retries: retries.to_usize(), retry_count: 0 }
+        SolrClient { http: client, max_retries: retries.to_usize(), retry_count: 0, retried: 0 }
+    }
+
+    pub(crate) fn get_retries(&self) -> u64 {
+        self.retried
     }
 
     fn get_timeout() -> u64 {
@@ -194,6 +200,7 @@ fn handle_response(
             Err(failure) => {
                 if !failure.is_fatal() && self.retry_count < self.max_retries {
                     self.retry_count += 1;
+                    self.retried += 1;
                     debug!(
                         "Retry {}/{}: Response Error: {}",
                         self.retry_count, self.max_retries, failure
@@ -0,0 +1,372 @@
+use super::{
+    args::{CommonArgs, Diff, SortOrder},
+    connection::SolrClient,
+    fails::{BoxedError, BoxedResult, Error, fail_with, raise, throw},
+    fetch::{json_to_key, without_internal_fields},
+    helpers::{BRACKETS, COMMA, EMPTY_STRING, IntegerHelpers, solr_query},
+    ingest::{find_archives, get_search_pattern, read_backup_file},
+    models::{Compression, Documents, Step},
+    save::Archiver,
+    state::UserInterruption,
+    storage::DocumentSink,
+};
+use log::{debug, info};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, hash_map::DefaultHasher};
+use std::hash::{Hash, Hasher};
+use std::io::{BufWriter, Write};
+use std::{fs::File, path::Path, time::Instant};
+use url::form_urlencoded::byte_serialize;
+
+// region Data Structures
+
+/// Ids of the documents that differ between the compared core and the other core/backup
+#[derive(Debug, Default)]
+pub(crate) struct Differences {
+    /// Documents found only in the `--core`
+    pub missing: Vec<String>,
+    /// Documents found only in the `--target-core` or backup
+    pub extra: Vec<String>,
+    /// Documents with distinct content in both
+    pub changed: Vec<String>,
+}
+
+/// Content hash of each document by its uniqueKey and if it was also found in the other source
+type Digests = HashMap<String, (u64, bool)>;
+
+const ARCHIVE_FILES: usize = 40;
+const LISTED_IDS: usize = 10;
+
+// endregion
+
+pub(crate) fn diff_main(params: &Diff) -> BoxedError {
+    debug!("# DIFF {:?}", params);
+
+    let started = Instant::now();
+    let diffs = params.compare()?;
+    diffs.log_ids();
+
+    if let Some(path) = &params.ids_file {
+        diffs.write_ids(path)?;
+    }
+    let other = params.describe_other();
+    let written = params.write_differences(&diffs)?;
+    if written > 0 {
+        info!("Written {} documents missing or changed in {} to archives.", written, other);
+    }
+    info!(
+        "Compared core {} with {} in {:?}: {} missing, {} extra and {} changed documents.",
+        params.options.core,
+        other,
+        started.elapsed(),
+        diffs.missing.len(),
+        diffs.extra.len(),
+        diffs.changed.len()
+    );
+    let found = diffs.missing.len() + diffs.extra.len() + diffs.changed.len();
+    if found > 0 {
+        let msg = format!("Found {} documents differing between the sources.", found);
+        return fail_with(Error::Mismatch(msg));
+    }
+    Ok(())
+}
+
+// region Scanning
+
+impl Diff {
+    /// Reads the documents of both sources and returns the ids of the documents that differ
+    pub(crate) fn compare(&self) -> BoxedResult<Differences> {
+        let mut digests = Digests::new();
+        let num_source = self.scan_core(&self.options, |key, digest| {
+            digests.insert(key, (digest, false));
+        })?;
+        info!("Read {} documents from core {}.", num_source, self.options.core);
+
+        let mut diffs = Differences::default();
+        let visit = |key: String, digest: u64| match digests.get_mut(&key) {
+            None => diffs.extra.push(key),
+            Some((_, true)) => {}
+            Some((found, seen)) => {
+                *seen = true;
+                if *found != digest {
+                    diffs.changed.push(key);
+                }
+            }
+        };
+        let num_other = match (&self.target_core, &self.dir) {
+            (Some(core), _) => self.scan_core(&self.get_target_options(core), visit)?,
+            (None, Some(dir)) => self.scan_archives(dir, visit)?,
+            (None, None) => raise("Missing the core or the backup to compare with!")?,
+        };
+        info!("Read {} documents from {}.", num_other, self.describe_other());
+
+        let missing = digests.into_iter().filter(|(_, (_, seen))| !seen).map(|(k, _)| k);
+        diffs.missing = missing.collect();
+        diffs.sort();
+        Ok(diffs)
+    }
+
+    fn describe_other(&self) -> String {
+        match (&self.target_core, &self.dir) {
+            (Some(core), _) => format!("core {}", core),
+            (None, Some(dir)) => format!("archives in {:?}", dir),
+            (None, None) => EMPTY_STRING,
+        }
+    }
+
+    pub(crate) fn get_target_options(&self, core: &str) -> CommonArgs {
+        let mut target = self.options.clone();
+        target.core = core.to_string();
+        if let Some(url) = &self.target_url {
+            target.url = url.clone();
+        }
+        target
+    }
+
+    /// Streams the uniqueKey and content hash of all documents matching the query in the core
+    fn scan_core(&self, core: &CommonArgs, mut visit: impl FnMut(String, u64)) -> BoxedResult<u64> {
+        let ctrl_c = self.options.hooks.aborting();
+        let mut client = SolrClient::new();
+        let mut cursor = "*".to_string();
+        let mut count = 0;
+        loop {
+            let url = self.get_cursor_url(core, &cursor);
+            let json = client.get_as_json(&url)?;
+            let mut response: Value = serde_json::from_str(&json)?;
+            let next = response.get("nextCursorMark").and_then(Value::as_str).map(String::from);
+            let (Some(next), Some(Value::Array(docs))) =
+                (next, response.pointer_mut("/response/docs").map(Value::take))
+            else {
+                return throw(format!("Error parsing docs from solr query: {}", url));
+            };
+            for doc in docs {
+                if let Some((key, digest)) = self.digest_doc(doc) {
+                    visit(key, digest);
+                    count += 1;
+                }
+            }
+            if ctrl_c.aborted() {
+                return fail_with(Error::Cancelled);
+            }
+            if next == cursor {
+                break;
+            }
+            debug!("Read {} documents from core {}", count, core.core);
+            cursor = next;
+        }
+        Ok(count)
+    }
+
+    /// Streams the uniqueKey and content hash of all documents stored in the backup archives
+    fn scan_archives(&self, dir: &Path, mut visit: impl FnMut(String, u64)) -> BoxedResult<u64> {
+        let ctrl_c = self.options.hooks.aborting();
+        let ext = self.archive_format.get_search_ext();
+        let pattern = get_search_pattern(dir, &self.search, &self.options.core, ext);
+        let found = find_archives(&pattern, SortOrder::None)?;
+        if found.is_empty() {
+            throw(format!("Found no archives to compare with: {}", pattern))?;
+        }
+        let key = self.encryption.get_key()?;
+        let mut count = 0;
+        for archive in found {
+            for read in read_backup_file(&archive, &self.csv, &[], key.as_deref())? {
+                let (entry, json, _) = match read {
+                    Ok(contents) => contents,
+                    Err(cause) => return throw(format!("In archive {:?}: {}", archive, cause)),
+                };
+                let Ok(docs) = serde_json::from_str::<Vec<Value>>(&json) else {
+                    return throw(format!("Error parsing docs in {} of {:?}", entry, archive));
+                };
+                for doc in docs {
+                    if let Some((key, digest)) = self.digest_doc(doc) {
+                        visit(key, digest);
+                        count += 1;
+                    }
+                }
+                if ctrl_c.aborted() {
+                    return fail_with(Error::Cancelled);
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    fn get_cursor_url(&self, core: &CommonArgs, cursor: &str) -> String {
+        let query = solr_query(self.query.as_deref().unwrap_or("*:*"));
+        let filter = solr_query(self.fq.as_deref().unwrap_or("*:*"));
+        let fields = if self.select.is_empty() {
+            "*".to_string()
+        } else {
+            let others = self.select.iter().filter(|&name| name != &self.unique_key);
+            let names: Vec<&str> = others.map(String::as_str).collect();
+            [self.unique_key.as_str()].iter().chain(&names).copied().collect::<Vec<_>>().join(COMMA)
+        };
+        let mark: String = byte_serialize(cursor.as_bytes()).collect();
+        format!(
+            "{}?wt=json&indent=off&omitHeader=true&q={}&fq={}&sort={}%20asc&rows={}&fl={}&cursorMark={}",
+            core.get_core_handler_url("/select"),
+            query,
+            filter,
+            self.unique_key,
+            self.num_docs,
+            fields,
+            mark
+        )
+    }
+
+    /// Returns the uniqueKey and a hash of the compared fields of the document
+    fn digest_doc(&self, doc: Value) -> Option<(String, u64)> {
+        let key = json_to_key(doc.get(&self.unique_key)?);
+        let compared = self.get_compared_fields(doc)?;
+        let mut hasher = DefaultHasher::new();
+        Value::from_iter(compared).to_string().hash(&mut hasher);
+        Some((key, hasher.finish()))
+    }
+
+    /// Keeps only the selected fields, or the ones not starting with `_`, sorted by name
+    fn get_compared_fields(&self, doc: Value) -> Option<BTreeMap<String, Value>> {
+        let Value::Object(fields) = doc else {
+            return None;
+        };
+        let kept = fields.into_iter().filter(|(name, _)| {
+            if self.select.is_empty() {
+                !name.starts_with('_')
+            } else {
+                name == &self.unique_key || self.select.contains(name)
+            }
+        });
+        Some(kept.collect())
+    }
+}
+
+// endregion
+
+// region Output
+
+impl Diff {
+    /// Writes all stored fields of the documents missing or changed in the other core/backup
+    /// as archives that can be used with `solrcopy restore`
+    fn write_differences(&self, diffs: &Differences) -> BoxedResult<u64> {
+        let Some(output) = &self.output_dir else {
+            return Ok(0);
+        };
+        let keys: Vec<String> = diffs.missing.iter().chain(&diffs.changed).cloned().collect();
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        let core = &self.options.core;
+        let pattern = format!("{}_diff_docs_{}_seq_{}.zip", core, keys.len(), BRACKETS);
+        let key = self.encryption.get_key()?;
+        let mut archiver = Archiver::write_on(output, &pattern, Compression::Zip, ARCHIVE_FILES)
+            .encrypted_with(key);
+
+        let mut client = SolrClient::new();
+        let mut written = 0;
+        for chunk in keys.chunks(self.num_docs.to_usize()) {
+            let found =
+                self.options.query_docs_by_keys(&mut client, &self.unique_key, chunk, "*")?;
+            let docs = without_internal_fields(found);
+            let step = Step { curr: written, expected: 0, url: EMPTY_STRING };
+            written += docs.len().to_u64();
+            archiver.write_documents(&Documents { step, docs: Value::Array(docs).to_string() })?;
+        }
+        archiver.close_archive()?;
+        Ok(written)
+    }
+}
+
+impl Differences {
+    fn sort(&mut self) {
+        self.missing.sort_unstable();
+        self.extra.sort_unstable();
+        self.changed.sort_unstable();
+    }
+
+    fn categories(&self) -> [(&str, &Vec<String>); 3] {
+        [("missing", &self.missing), ("extra", &self.extra), ("changed", &self.changed)]
+    }
+
+    fn log_ids(&self) {
+        for (category, ids) in self.categories() {
+            if !ids.is_empty() {
+                let listed = ids.iter().take(LISTED_IDS).cloned().collect::<Vec<_>>().join(", ");
+                let more = if ids.len() > LISTED_IDS { ", ..." } else { "" };
+                info!("{} {} documents: {}{}", ids.len(), category, listed, more);
+            }
+        }
+    }
+
+    /// Writes a line with the category and the uniqueKey separated by a tab for each document
+    fn write_ids(&self, path: &Path) -> BoxedError {
+        let mut out = BufWriter::new(File::create(path)?);
+        for (category, ids) in self.categories() {
+            for id in ids {
+                writeln!(out, "{}\t{}", category, id)?;
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use crate::args::{Cli, Commands, Diff};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    fn mockup_diff(extra: &[&str]) -> Diff {
+        let args = [&["solrcopy", "diff", "--core", "demo", "--target-core", "target"], extra];
+        match Cli::mockup_from(&args.concat()) {
+            Commands::Diff(dif) => dif,
+            _ => panic!("command must be 'diff' !"),
+        }
+    }
+
+    #[test]
+    fn check_diff_requires_other_source() {
+        Cli::mockup_for_help(&["solrcopy", "diff", "--core", "demo"]);
+    }
+
+    #[test]
+    fn check_digest_doc_ignores_field_order_and_internals() {
+        let dif = mockup_diff(&[]);
+        let doc1 = json!({"id": "1", "name": "a", "price": 2.5, "_version_": 1});
+        let doc2 = json!({"price": 2.5, "name": "a", "id": "1", "_version_": 2});
+        let doc3 = json!({"price": 3.5, "name": "a", "id": "1"});
+        let (key1, digest1) = dif.digest_doc(doc1).unwrap();
+        let (key2, digest2) = dif.digest_doc(doc2).unwrap();
+        let (_, digest3) = dif.digest_doc(doc3).unwrap();
+        assert_eq!(key1, "1");
+        assert_eq!(key2, "1");
+        assert_eq!(digest1, digest2);
+        assert!(digest1 != digest3);
+    }
+
+    #[test]
+    fn check_digest_doc_with_selected_fields() {
+        let dif = mockup_diff(&["--select", "name"]);
+        let doc1 = json!({"id": 7, "name": "a", "price": 2.5});
+        let doc2 = json!({"id": 7, "name": "a", "price": 3.5});
+        let (key1, digest1) = dif.digest_doc(doc1).unwrap();
+        let (_, digest2) = dif.digest_doc(doc2).unwrap();
+        assert_eq!(key1, "7");
+        assert_eq!(digest1, digest2);
+        assert_eq!(dif.digest_doc(json!({"name": "no id"})), None);
+    }
+
+    #[test]
+    fn check_cursor_url() {
+        let dif = mockup_diff(&["--url", "http://solr:8983/solr", "--num-docs", "10"]);
+        let url = dif.get_cursor_url(&dif.options, "AoE+x/1=");
+        assert_eq!(
+            url,
+            "http://solr:8983/solr/demo/select?wt=json&indent=off&omitHeader=true&q=*:*&fq=*:*\
+             &sort=id%20asc&rows=10&fl=*&cursorMark=AoE%2Bx%2F1%3D"
+        );
+    }
+}
+
+// end of file \\
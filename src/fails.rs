@@ -1,10 +1,12 @@
 #![allow(dead_code)]
 
-use std::{error::Error, fmt};
+use super::connection::SolrError;
+use std::{error, fmt, io};
+use zip::result::ZipError;
 
-pub type BoxedResult<T> = Result<T, Box<dyn Error>>;
+pub type BoxedResult<T> = Result<T, Box<dyn error::Error>>;
 
-pub type BoxedError = Result<(), Box<dyn Error>>;
+pub type BoxedError = Result<(), Box<dyn error::Error>>;
 
 pub(crate) struct Failed {
     details: String,
@@ -36,26 +38,155 @@ fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     }
 }
 
-impl Error for Failed {
+impl error::Error for Failed {
     fn description(&self) -> &str {
         &self.details
     }
 }
 
+// region Typed errors
+
+/// Failures of the commands and of the operations run by the library builders.
+///
+/// Each kind of failure ends the `solrcopy` process with its own exit code:
+///
+/// | Code | Failure                                                                                  |
+/// |------|------------------------------------------------------------------------------------------|
+/// | 0    | Success                                                                                  |
+/// | 1    | Any other failure                                                                        |
+/// | 2    | Invalid arguments                                                                        |
+/// | 3    | Solr could not be reached                                                                |
+/// | 4    | Solr refused the credentials                                                             |
+/// | 5    | The core was not found                                                                   |
+/// | 6    | Solr answered with an error                                                              |
+/// | 7    | Backup files could not be found, read or written                                         |
+/// | 8    | Finished, but with failures tolerated by `--max-errors`                                  |
+/// | 9    | The documents checked by `--verify` or `diff` differ, or `verify` found damaged archives |
+/// | 130  | Aborted by Ctrl-C or cancelled                                                           |
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The options given were rejected
+    InvalidArguments(String),
+    /// Solr could not be reached, as when the url is wrong or the server is down
+    Connection(String),
+    /// Solr answered with HTTP status 401 or 403
+    Unauthorized(String),
+    /// Solr answered with HTTP status 404, as when the core does not exist
+    CoreNotFound(String),
+    /// Solr answered with other error status
+    Solr(String),
+    /// The backup files could not be found, read or written
+    Archive(String),
+    /// Reading or writing the backup files failed
+    Io(io::Error),
+    /// The operation finished, but skipped the failures tolerated by `--max-errors`
+    PartialSuccess(String),
+    /// The documents checked after copying them differ from the source
+    Mismatch(String),
+    /// The operation was stopped by Ctrl-C or by its cancel token
+    Cancelled,
+    /// Any other failure
+    Failed(String),
+}
+
+impl Error {
+    /// Exit code of the `solrcopy` process failing with this error
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Failed(_) => 1,
+            Self::InvalidArguments(_) => 2,
+            Self::Connection(_) => 3,
+            Self::Unauthorized(_) => 4,
+            Self::CoreNotFound(_) => 5,
+            Self::Solr(_) => 6,
+            Self::Archive(_) | Self::Io(_) => 7,
+            Self::PartialSuccess(_) => 8,
+            Self::Mismatch(_) => 9,
+            Self::Cancelled => 130,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidArguments(msg) => write!(f, "Invalid arguments: {}", msg),
+            Self::Connection(msg) => write!(f, "Failed connecting to Solr: {}", msg),
+            Self::Unauthorized(msg) => write!(f, "Access denied by Solr: {}", msg),
+            Self::CoreNotFound(msg) => write!(f, "Core not found: {}", msg),
+            Self::Io(cause) => write!(f, "{}", cause),
+            Self::Mismatch(msg) => write!(f, "Verification failed: {}", msg),
+            Self::Cancelled => write!(f, "Execution aborted by user!"),
+            Self::Solr(msg)
+            | Self::Archive(msg)
+            | Self::PartialSuccess(msg)
+            | Self::Failed(msg) => {
+                write!(f, "{}", msg)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(cause) => Some(cause),
+            _ => None,
+        }
+    }
+}
+
+impl From<SolrError> for Error {
+    fn from(failure: SolrError) -> Self {
+        match failure.code {
+            None => Self::Connection(failure.details),
+            Some(401 | 403) => Self::Unauthorized(failure.details),
+            Some(404) => Self::CoreNotFound(failure.details),
+            Some(_) => Self::Solr(failure.details),
+        }
+    }
+}
+
+impl From<Box<dyn error::Error>> for Error {
+    /// Classifies the failures returned by the commands by their type
+    fn from(failure: Box<dyn error::Error>) -> Self {
+        let failure = match failure.downcast::<Error>() {
+            Ok(typed) => return *typed,
+            Err(other) => other,
+        };
+        let failure = match failure.downcast::<SolrError>() {
+            Ok(solr) => return Self::from(*solr),
+            Err(other) => other,
+        };
+        let failure = match failure.downcast::<io::Error>() {
+            Ok(cause) => return Self::Io(*cause),
+            Err(other) => other,
+        };
+        if failure.is::<ZipError>() || failure.is::<csv::Error>() {
+            Self::Archive(failure.to_string())
+        } else {
+            Self::Failed(failure.to_string())
+        }
+    }
+}
+
+// endregion
+
 // region utilities
 
 pub(crate) fn throw<T>(message: String) -> BoxedResult<T> {
     Err(Box::new(Failed::new(&message)))
 }
 
-pub(crate) fn failed(message: String) -> impl FnOnce() -> Box<dyn Error> {
-    let res: Box<dyn Error> = Box::new(Failed::new(&message));
+pub(crate) fn failed(message: String) -> impl FnOnce() -> Box<dyn error::Error> {
+    let res: Box<dyn error::Error> = Box::new(Failed::new(&message));
     move || res
 }
 
 pub(crate) fn rethrow<T, E>(failure: E) -> BoxedResult<T>
 where
-    E: Error + 'static,
+    E: error::Error + 'static,
 {
     Err(Box::new(failure))
 }
@@ -64,6 +195,11 @@ pub(crate) fn raise<T>(message: &str) -> BoxedResult<T> {
     Err(Box::new(Failed::new(message)))
 }
 
+/// Fails with the typed `error` for telling its exit code apart
+pub(crate) fn fail_with<T>(error: Error) -> BoxedResult<T> {
+    Err(Box::new(error))
+}
+
 macro_rules! throws {
     ($($arg:tt)*) => {
         Err(Box::new(crate::fails::Failed::from( format!($($arg)*) )))
@@ -82,8 +218,10 @@ macro_rules! should_fail {
 
 #[cfg(test)]
 mod tests {
-    use crate::fails::{raise, throw};
+    use crate::connection::SolrError;
+    use crate::fails::{BoxedResult, Error, fail_with, raise, throw};
     use pretty_assertions::assert_eq;
+    use std::io;
 
     #[test]
     fn check_throw_and_raise() {
@@ -96,4 +234,20 @@ fn check_throw_and_raise() {
 
         assert_eq!(raise::<usize>("fail").is_ok(), false);
     }
+
+    #[test]
+    fn check_error_exit_codes() {
+        let exit_code = |failed: BoxedResult<()>| Error::from(failed.unwrap_err()).exit_code();
+
+        assert_eq!(exit_code(throw("fail".to_string())), 1);
+        assert_eq!(exit_code(fail_with(Error::InvalidArguments("bad".into()))), 2);
+        assert_eq!(exit_code(Err(Box::new(SolrError::from("refused".into())))), 3);
+        assert_eq!(exit_code(Err(Box::new(SolrError::of("denied", 401)))), 4);
+        assert_eq!(exit_code(Err(Box::new(SolrError::of("no core", 404)))), 5);
+        assert_eq!(exit_code(Err(Box::new(SolrError::of("broken", 500)))), 6);
+        assert_eq!(exit_code(Err(Box::new(io::Error::other("disk full")))), 7);
+        assert_eq!(exit_code(fail_with(Error::PartialSuccess("skipped".into()))), 8);
+        assert_eq!(exit_code(fail_with(Error::Mismatch("differs".into()))), 9);
+        assert_eq!(exit_code(fail_with(Error::Cancelled)), 130);
+    }
 }
@@ -1,6 +1,15 @@
-use super::{args::Backup, connection::SolrClient, fails::*, helpers::*, models::SolrCore};
+use super::{
+    args::{Backup, CommonArgs, Restore},
+    connection::SolrClient,
+    fails::*,
+    helpers::*,
+    models::SolrCore,
+};
 use log::{debug, trace};
 use regex::Regex;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use url::form_urlencoded::byte_serialize;
 
 // region Solr Core
 
@@ -33,6 +42,17 @@ pub(crate) fn inspect_core(&self) -> BoxedResult<SolrCore> {
         Ok(res)
     }
 
+    /// Counts the documents in each value of the `--stratify` field between `begin` and `end`
+    pub(crate) fn query_strata(&self, begin: &str, end: &str) -> BoxedResult<Vec<(String, u64)>> {
+        let field = self.stratify.as_deref().unwrap_or_default();
+        let encoded: String = byte_serialize(field.as_bytes()).collect();
+        let facets =
+            format!("&rows=0&facet=true&facet.field={}&facet.limit=-1&facet.mincount=1", encoded);
+        let query_url = self.get_query_url(&facets, begin, end);
+        let json = SolrClient::send_get_as_json(&query_url)?;
+        SolrCore::parse_facet_counts(&json, field)
+    }
+
     pub(crate) fn query_num_found(&self, begin: &str, end: &str) -> BoxedResult<u64> {
         // try sometimes for finding the greatest num_found of docs answered by the core
         // Used for fixing problems with corrupted replicas of cores with more than 1 shard
@@ -55,6 +75,110 @@ pub(crate) fn query_num_found(&self, begin: &str, end: &str) -> BoxedResult<u64>
     }
 }
 
+impl Restore {
+    /// Queries the target core for the value of `field` in the documents with the given keys
+    pub(crate) fn query_target_values(
+        &self, client: &mut SolrClient, field: &str, keys: &[String],
+    ) -> BoxedResult<HashMap<String, Value>> {
+        let key_field = self.unique_key.as_str();
+        let fields = format!("{},{}", key_field, field);
+        let found = self.options.query_docs_by_keys(client, key_field, keys, &fields)?;
+
+        let mut res = HashMap::with_capacity(found.len());
+        for doc in found {
+            if let (Some(key), Some(value)) = (doc.get(key_field), doc.get(field)) {
+                res.insert(json_to_key(key), value.clone());
+            }
+        }
+        Ok(res)
+    }
+}
+
+impl CommonArgs {
+    /// Retrieves the `fields` of the documents in the core having the given uniqueKeys
+    pub(crate) fn query_docs_by_keys(
+        &self, client: &mut SolrClient, key_field: &str, keys: &[String], fields: &str,
+    ) -> BoxedResult<Vec<Value>> {
+        // Uses the JSON Request API for not hitting the maximum length of the url
+        let request = json!({
+            "query": get_terms_query(key_field, keys),
+            "fields": fields,
+            "limit": keys.len(),
+            "params": { "wt": "json", "omitHeader": true },
+        });
+        let url = self.get_core_handler_url("/select");
+        let json = client.post_as_json(&url, &request.to_string())?;
+        SolrCore::parse_docs_as_values(&json)
+    }
+
+    /// Retrieves the latest version of the documents with the given uniqueKeys using the
+    /// real-time get handler, which sees even the documents not committed yet
+    pub(crate) fn real_time_get(
+        &self, client: &mut SolrClient, keys: &[String],
+    ) -> BoxedResult<Vec<Value>> {
+        let ids = keys.iter().map(|key| byte_serialize(key.as_bytes()).collect::<String>());
+        let params = ids.map(|id| format!("id={}", id)).collect::<Vec<_>>().join("&");
+        let url = self.get_core_handler_url(&format!("/get?wt=json&{}", params));
+        let json = client.get_as_json(&url)?;
+        // A single id is answered with `{"doc": {..}}` instead of a list of docs
+        let response: Value = serde_json::from_str(&json)?;
+        match response.get("doc") {
+            Some(Value::Null) => Ok(vec![]),
+            Some(doc) => Ok(vec![doc.clone()]),
+            None => SolrCore::parse_docs_as_values(&json),
+        }
+    }
+
+    /// Retrieves the fields, dynamic fields and field types of the core from the Schema API
+    pub(crate) fn query_schema(&self) -> BoxedResult<Value> {
+        let url = self.get_core_handler_url("/schema?wt=json");
+        let json = SolrClient::send_get_as_json(&url)?;
+        let mut response: Value = serde_json::from_str(&json)?;
+        match response.get_mut("schema").map(Value::take) {
+            Some(schema @ Value::Object(_)) => Ok(schema),
+            _ => throws!("Error parsing the schema of the core: {}", json),
+        }
+    }
+
+    /// Counts the documents stored in the core matching the `query`
+    pub(crate) fn query_num_docs(&self, query: &str) -> BoxedResult<u64> {
+        let query: String = byte_serialize(query.as_bytes()).collect();
+        let handler = format!("/select?q={}&rows=0&wt=json&omitHeader=true", query);
+        let url = self.get_core_handler_url(&handler);
+        let json = SolrClient::send_get_as_json(&url)?;
+        SolrCore::parse_num_found(&json)
+    }
+}
+
+/// Text representation of a uniqueKey value for comparing keys from distinct sources
+pub(crate) fn json_to_key(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Separator of the `{!terms}` query, as the default comma can appear inside the uniqueKeys
+const TERMS_SEPARATOR: char = '\u{1f}';
+
+/// Query matching the documents with any of the given uniqueKeys
+fn get_terms_query(key_field: &str, keys: &[String]) -> String {
+    let joined = keys.join(&TERMS_SEPARATOR.to_string());
+    format!("{{!terms f={} separator='{}'}}{}", key_field, TERMS_SEPARATOR, joined)
+}
+
+/// Removes the fields starting with `_` like `_version_` that Solr refuses from another core
+pub(crate) fn without_internal_fields(docs: Vec<Value>) -> Vec<Value> {
+    let stripped = docs.into_iter().filter_map(|doc| match doc {
+        Value::Object(mut fields) => {
+            fields.retain(|name, _| !name.starts_with('_'));
+            Some(Value::Object(fields))
+        }
+        _ => None,
+    });
+    stripped.collect()
+}
+
 impl SolrCore {
     fn parse_core_schema(gets: &Backup, json: &str) -> BoxedResult<Self> {
         let core_name = &gets.options.core;
@@ -108,6 +232,29 @@ fn parse_field_names(json: &str) -> Option<Vec<String>> {
         Some(filtered)
     }
 
+    /// Parses the values and counts of the `field` from `{"facet_counts":{"facet_fields":{..}}}`
+    pub(crate) fn parse_facet_counts(json: &str, field: &str) -> BoxedResult<Vec<(String, u64)>> {
+        let response: Value = serde_json::from_str(json)?;
+        let facets = response.pointer("/facet_counts/facet_fields").and_then(|it| it.get(field));
+        let Some(Value::Array(pairs)) = facets else {
+            return throws!("Error parsing the facet counts of field {}: {}", field, json);
+        };
+        let counts = pairs.chunks(2).filter_map(|pair| match pair {
+            [value, Value::Number(count)] => Some((json_to_key(value), count.as_u64()?)),
+            _ => None,
+        });
+        Ok(counts.collect())
+    }
+
+    /// Parses the documents returned by a Solr query as json values
+    pub(crate) fn parse_docs_as_values(json: &str) -> BoxedResult<Vec<Value>> {
+        let mut response: Value = serde_json::from_str(json)?;
+        match response.pointer_mut("/response/docs").map(Value::take) {
+            Some(Value::Array(docs)) => Ok(docs),
+            _ => throws!("Error parsing docs from solr query: {}", json),
+        }
+    }
+
     /// Strips out: `[{  "a": "b", "c": "d" }]` from Solr json response
     /// ``` json
     /// {"response":{"numFound":46,"start":0,"docs":_____}}
@@ -121,7 +268,7 @@ pub(crate) fn parse_docs_from_query(json: &str) -> Option<&str> {
 
 #[cfg(test)]
 mod tests {
-    use super::{SolrCore, StringHelpers};
+    use super::{SolrCore, StringHelpers, get_terms_query};
     use pretty_assertions::assert_eq;
 
     const CORE_1ROW: &str = r#"{
@@ -140,6 +287,25 @@ mod tests {
                 {"id":"EN7800GTX/2DHTV/256M","name":["ASUS Extreme N7800GTX/2DHTV (256 MB)"],"cat":["electronics","graphics card"],"price":[479.95]}
             ]}}"#;
 
+    #[test]
+    fn check_terms_query() {
+        let keys = vec!["1".to_string(), "a,b".to_string()];
+        let query = get_terms_query("id", &keys);
+
+        assert_eq!(query, "{!terms f=id separator='\u{1f}'}1\u{1f}a,b");
+    }
+
+    #[test]
+    fn check_facet_counts() {
+        let json = r#"{"response":{"numFound":6,"start":0,"docs":[]},
+            "facet_counts":{"facet_fields":{"cat":["electronics",4,"memory",2]}}}"#;
+        let counts = SolrCore::parse_facet_counts(json, "cat").unwrap();
+        let expected = vec![("electronics".to_string(), 4), ("memory".to_string(), 2)];
+
+        assert_eq!(counts, expected);
+        assert_eq!(SolrCore::parse_facet_counts(json, "price").is_err(), true);
+    }
+
     #[test]
     fn check_schema_num_found() {
         let num_found = SolrCore::parse_num_found(CORE_1ROW);
@@ -1,7 +1,15 @@
 #![allow(dead_code)]
 
 use regex::{Captures, Regex};
-use std::{convert::TryInto, env, path::Path, str::FromStr};
+use sha2::{Digest, Sha256};
+use std::{
+    convert::TryInto,
+    env,
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    path::Path,
+    str::FromStr,
+};
 
 // region Constants
 
@@ -13,6 +21,12 @@
 pub(crate) const SEMICOLON: &str = ";";
 pub(crate) const BRACKETS: &str = "{}";
 
+/// Value of `--dir` for writing the backup to stdout or restoring it from stdin
+pub(crate) const STDIO: &str = "-";
+
+/// Denominator of the fractions of `--sample`, stored as parts per million
+pub(crate) const MILLION: u64 = 1_000_000;
+
 pub(crate) const SPACE: char = ' ';
 pub(crate) const ZERO: char = '0';
 
@@ -33,7 +47,7 @@ pub(crate) fn solr_query(query: &str) -> String {
 
 pub(crate) fn replace_solr_date(query: &str, pattern: &str, value: &str) -> String {
     let vlen = value.len();
-    let suffix = &ISO_DATE[vlen..];
+    let suffix = ISO_DATE.get(vlen..).unwrap_or(EMPTY_STR);
 
     let value2 = value.append(suffix);
     query.replace(pattern, &value2)
@@ -68,6 +82,41 @@ pub(crate) fn get_filename(file_path: &Path) -> Result<String, ()> {
     file_path.file_name().ok_or(())?.to_os_string().into_string().or(Err(()))
 }
 
+/// Tells if the path is `-` standing for stdout or stdin
+pub(crate) fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == STDIO
+}
+
+/// SHA-256 of the bytes as lowercase hexadecimal text
+pub(crate) fn sha256_hex(bytes: &[u8]) -> String {
+    to_hex(&Sha256::digest(bytes))
+}
+
+/// SHA-256 of the file as lowercase hexadecimal text, read as a stream for huge backup files
+pub(crate) fn sha256_file(path: &Path) -> io::Result<String> {
+    sha256_stream(File::open(path)?)
+}
+
+/// SHA-256 of the contents read as a stream as lowercase hexadecimal text
+pub(crate) fn sha256_stream(source: impl Read) -> io::Result<String> {
+    let mut reader = BufReader::with_capacity(1 << 16, source);
+    let mut hasher = Sha256::new();
+    loop {
+        let chunk = reader.fill_buf()?;
+        if chunk.is_empty() {
+            break;
+        }
+        hasher.update(chunk);
+        let read = chunk.len();
+        reader.consume(read);
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+fn to_hex(hash: &[u8]) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
 // endregion
 
 // region Type Method Extensions
@@ -328,7 +377,7 @@ pub(crate) trait CapturesHelpers {
     /// Get the text of the match with a default of an empty string if this
     /// group didn't participate in the match:
     ///
-    /// ```rust
+    /// ```ignore
     /// # use regex::Regex;
     /// let re = Regex::new(r"[a-z]+(?:([0-9]+)|([A-Z]+))").unwrap();
     /// let caps = re.captures("abc123").unwrap();
@@ -349,7 +398,7 @@ pub(crate) trait CapturesHelpers {
     /// Get the text of the match with a default of an empty string if this
     /// group didn't participate in the match:
     ///
-    /// ```rust
+    /// ```ignore
     /// # use regex::Regex;
     /// let re = Regex::new(r"[a-z]+(?:([0-9]+)|([A-Z]+))").unwrap();
     /// let caps = re.captures("abc123").unwrap();
@@ -489,6 +538,43 @@ pub(crate) fn print_env_vars() {
 
 // endregion
 
+#[cfg(test)]
+pub(crate) mod shared {
+    use std::{
+        env, fs,
+        ops::Deref,
+        path::{Path, PathBuf},
+        process,
+    };
+
+    /// Folder for the files of a test, removed when dropped even if the test fails
+    pub(crate) struct TempDir {
+        path: PathBuf,
+    }
+
+    impl TempDir {
+        pub(crate) fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("solrcopy_{}_{}", name, process::id()));
+            fs::create_dir_all(&path).unwrap();
+            Self { path }
+        }
+    }
+
+    impl Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
 #[cfg(test)]
 mod test_utility_helpers {
     use crate::helpers::{
@@ -511,6 +597,10 @@ fn check_replace_solr_date() {
             replace_solr_date(query, "{hello}", "2025-01-01"),
             "started in 2025-01-01T00:00:00Z"
         );
+        assert_eq!(
+            replace_solr_date(query, "{hello}", "2025-01-01T10:11:12.345Z"),
+            "started in 2025-01-01T10:11:12.345Z"
+        );
     }
 
     #[test]
@@ -1,62 +1,106 @@
 use super::{
-    args::{Restore, SortOrder},
-    fails::BoxedResult,
-    helpers::IntegerHelpers,
+    args::{CsvArgs, MissingDocs, Restore, SortOrder},
+    fails::{BoxedResult, Error, fail_with},
+    helpers::{EMPTY_STR, IntegerHelpers},
+    models::{ArchiveFormat, CHECKSUM_EXT, MANIFEST_ENTRY, PARTIAL_EXT},
+    tabular::CsvReader,
 };
+use flate2::read::MultiGzDecoder;
 use glob::{PatternError, glob};
-use log::error;
-use std::{fmt, fs::File, io::prelude::*, path::Path, path::PathBuf};
-use zip::ZipArchive;
+use log::warn;
+use serde::de::IgnoredAny;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::{
+    fmt,
+    fs::File,
+    io::{BufReader, Lines, prelude::*},
+    path::Path,
+    path::PathBuf,
+};
+use zip::{
+    ZipArchive,
+    read::{ZipFile, ZipReadOptions},
+    result::ZipResult,
+};
 
 type Decompressor = ZipArchive<File>;
 
+/// Size of the blocks of headers and contents in tar archives
+const TAR_BLOCK: u64 = 512;
+
+/// Number of documents sent to Solr in each update when restoring json lines or csv files
+pub(crate) const STREAM_BATCH_DOCS: usize = 4000;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Name, json array and number of documents of each batch read from a backup file
+pub(crate) type BackupEntries = Box<dyn Iterator<Item = BoxedResult<(String, String, u64)>>>;
+
 #[derive(Debug)]
 pub(crate) struct ArchiveReader {
     pub archive: Decompressor,
     pub entry_index: usize,
+    /// Key for decrypting the entries encrypted by backup, when given
+    key: Option<String>,
+}
+
+/// Reads the entries of a tar archive as a stream, what works with archives left unfinished
+pub(crate) struct TarReader {
+    stream: Box<dyn Read>,
+    entry_count: u64,
+    finished: bool,
+}
+
+/// Reads the documents of a json lines file in batches of `STREAM_BATCH_DOCS` lines
+pub(crate) struct JsonLinesReader {
+    lines: Lines<BufReader<Box<dyn Read>>>,
+    line_count: u64,
+    /// Error deferred until the documents read before it are yielded
+    failure: Option<String>,
+    finished: bool,
 }
 
 pub(crate) struct Docs {
     pub json: String,
     pub archive: String,
     pub entry: String,
+    /// Number of documents recorded in the manifest or read from json lines, 0 when unknown
+    pub count: u64,
 }
 
-impl Restore {
-    pub(crate) fn find_archives(&self) -> Result<Vec<PathBuf>, PatternError> {
-        let wilcard = self.get_pattern();
-        let listed = glob(&wilcard)?;
-        let mut found = listed.filter_map(Result::ok).collect::<Vec<_>>();
-        if self.order != SortOrder::None {
-            found.sort_unstable();
-        }
-        if self.order == SortOrder::Desc {
-            found.reverse();
-        }
-        Ok(found)
-    }
+/// Number of batches and documents in the archives to restore
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct ArchiveTotals {
+    pub batches: u64,
+    /// Known only when all archives have a manifest
+    pub docs: Option<u64>,
+}
 
+impl Restore {
     pub(crate) fn get_pattern(&self) -> String {
-        let wilcard: String = match &self.search {
-            Some(pat) => {
-                if pat.ends_with(".zip") || pat.contains('*') {
-                    pat.to_owned()
-                } else {
-                    format!("{}*", pat)
-                }
-            }
-            None => format!("{}*.zip", self.options.core),
-        };
-        let mut path = self.transfer.dir.clone();
-        path.push(wilcard);
-        let res = path.to_str().unwrap();
-        res.to_string()
+        let ext = self.archive_format.get_search_ext();
+        get_search_pattern(&self.transfer.dir, &self.search, &self.options.core, ext)
     }
 
     pub(crate) fn get_update_url(&self) -> String {
         // E.g: http://localhost:8983/solr/mycore/update?wt=json&overwrite=true&commitWithin=1000&useParams=my_params
+        // Nested documents and atomic updates are only handled by the standard update handler
+        let handler = if self.nested || self.atomic.is_some() {
+            "/update?overwrite=true"
+        } else {
+            "/update/json/docs?overwrite=true"
+        };
+        // Updates of missing documents are refused by the `_version_` constraint
+        let skip_missing = if self.atomic.is_some() && self.atomic_missing == MissingDocs::Skip {
+            "&failOnVersionConflicts=false"
+        } else {
+            EMPTY_STR
+        };
         let parts: Vec<String> = vec![
-            self.options.get_core_handler_url("/update/json/docs?overwrite=true"),
+            self.options.get_core_handler_url(handler),
+            skip_missing.to_string(),
             self.flush.as_param("&"),
             self.transfer.get_param("&"),
         ];
@@ -64,6 +108,129 @@ pub(crate) fn get_update_url(&self) -> String {
     }
 }
 
+/// Lists the archive files matching the `wilcard` in the desired order.
+/// Files left unfinished by an interrupted backup are reported and skipped
+pub(crate) fn find_archives(wilcard: &str, order: SortOrder) -> Result<Vec<PathBuf>, PatternError> {
+    let listed = glob(wilcard)?;
+    Ok(select_archives(listed.filter_map(Result::ok), order))
+}
+
+/// Leaves out the checksum and unfinished files of the backup files listed and sorts them
+pub(crate) fn select_archives(
+    listed: impl Iterator<Item = PathBuf>, order: SortOrder,
+) -> Vec<PathBuf> {
+    let archives = listed.filter(|path| !is_checksum_file(path));
+    let archives = archives.filter(|path| {
+        let partial = has_suffix(path, PARTIAL_EXT);
+        if partial {
+            warn!("Skipping unfinished backup file left by an interrupted backup: {:?}", path);
+        }
+        !partial
+    });
+    let mut found = archives.collect::<Vec<_>>();
+    if order != SortOrder::None {
+        found.sort_unstable();
+    }
+    if order == SortOrder::Desc {
+        found.reverse();
+    }
+    found
+}
+
+fn is_checksum_file(path: &Path) -> bool {
+    has_suffix(path, CHECKSUM_EXT)
+}
+
+fn has_suffix(path: &Path, suffix: &str) -> bool {
+    path.to_str().is_some_and(|name| name.ends_with(suffix))
+}
+
+/// Builds the glob pattern for searching the archives in `dir` defaulting to the ones of `core`
+/// with the extension `ext`
+pub(crate) fn get_search_pattern(
+    dir: &Path, search: &Option<String>, core: &str, ext: &str,
+) -> String {
+    let wilcard: String = match search {
+        Some(pat) => {
+            if pat.ends_with(".zip") || pat.contains('*') {
+                pat.to_owned()
+            } else {
+                format!("{}*", pat)
+            }
+        }
+        None => format!("{}*.{}", core, ext),
+    };
+    let mut path = dir.to_path_buf();
+    path.push(wilcard);
+    let res = path.to_str().unwrap();
+    res.to_string()
+}
+
+/// Opens the backup file with the reader of its format.
+/// The `key` decrypts the zip archives encrypted by backup
+pub(crate) fn read_backup_file(
+    path: &Path, csv: &CsvArgs, split: &[String], key: Option<&str>,
+) -> BoxedResult<BackupEntries> {
+    let format = ArchiveFormat::of_file(path);
+    if format.is_streamable() {
+        return read_backup_stream(open_decoder(path)?, format, csv, split);
+    }
+    if format == ArchiveFormat::Parquet {
+        return fail_with(Error::Archive("Restoring parquet files is not supported".into()));
+    }
+    let mut reader = ArchiveReader::create_reader(path)?.decrypted_with(key);
+    let counts = reader.read_entry_counts();
+    let entries = reader.map(move |read| {
+        let (name, json) = read?;
+        let count = counts.get(&name).copied().unwrap_or(0);
+        Ok((name, json, count))
+    });
+    Ok(Box::new(entries))
+}
+
+/// Reads the already uncompressed `source` with the reader of the streamable `format`
+pub(crate) fn read_backup_stream(
+    source: Box<dyn Read>, format: ArchiveFormat, csv: &CsvArgs, split: &[String],
+) -> BoxedResult<BackupEntries> {
+    match format {
+        ArchiveFormat::Tar => Ok(Box::new(TarReader::from_stream(source))),
+        ArchiveFormat::Jsonl => Ok(Box::new(JsonLinesReader::from_stream(source))),
+        ArchiveFormat::Csv => Ok(Box::new(CsvReader::from_stream(source, csv, split)?)),
+        ArchiveFormat::Zip | ArchiveFormat::Parquet => fail_with(Error::Archive(format!(
+            "Reading {:?} backups as a stream is not supported",
+            format
+        ))),
+    }
+}
+
+/// Uncompresses the `input` when it starts with the magic bytes of gzip or zstd
+pub(crate) fn decode_stream(mut input: impl BufRead + 'static) -> BoxedResult<Box<dyn Read>> {
+    let head = input.fill_buf()?;
+    let decoder: Box<dyn Read> = if head.starts_with(GZIP_MAGIC) {
+        Box::new(MultiGzDecoder::new(input))
+    } else if head.starts_with(ZSTD_MAGIC) {
+        Box::new(zstd::Decoder::with_buffer(input)?)
+    } else {
+        Box::new(input)
+    };
+    Ok(decoder)
+}
+
+/// Opens the stream of a json lines or csv file uncompressing it according to its extension
+pub(crate) fn open_decoder(path: &Path) -> BoxedResult<Box<dyn Read>> {
+    let file = File::open(path)?;
+    // Unfinished files keep the compression of their final name
+    let name = path.to_str().unwrap_or_default().trim_end_matches(PARTIAL_EXT);
+    let decoder: Box<dyn Read> = if name.ends_with(".gz") {
+        Box::new(MultiGzDecoder::new(file))
+    } else if name.ends_with(".zst") {
+        Box::new(zstd::Decoder::new(file)?)
+    } else {
+        Box::new(file)
+    };
+    Ok(decoder)
+}
+
 impl ArchiveReader {
     pub(crate) fn open_archive(archive_path: &Path) -> BoxedResult<Decompressor> {
         let zipfile = File::open(archive_path)?;
@@ -75,43 +242,277 @@ pub(crate) fn create_reader(archive_path: &Path) -> BoxedResult<ArchiveReader> {
         let success = Self::open_archive(archive_path);
         match success {
             Err(cause) => Err(cause),
-            Ok(zip) => Ok(ArchiveReader { archive: zip, entry_index: 0 }),
+            Ok(zip) => Ok(ArchiveReader { archive: zip, entry_index: 0, key: None }),
         }
     }
 
-    pub(crate) fn get_archive_file_count(archive_path: &Path) -> Option<usize> {
-        let success = Self::open_archive(archive_path);
-        match success {
-            Err(_) => None,
-            Ok(archive) => {
-                let file_count = archive.len();
-                Some(file_count)
+    pub(crate) fn decrypted_with(mut self, key: Option<&str>) -> Self {
+        self.key = key.map(str::to_string);
+        self
+    }
+
+    /// Opens the entry for reading its contents, decrypting it when it was encrypted
+    pub(crate) fn open_entry(&mut self, index: usize) -> ZipResult<ZipFile<'_, File>> {
+        let key = self.key.as_ref().map(String::as_bytes);
+        self.archive.by_index_with_options(index, ZipReadOptions::new().password(key))
+    }
+
+    /// Parses the manifest entry written by backup when the archive has one
+    pub(crate) fn read_manifest(&mut self) -> Option<Value> {
+        let index = self.archive.index_for_name(MANIFEST_ENTRY)?;
+        let mut entry = self.open_entry(index).ok()?;
+        let mut contents = String::new();
+        entry.read_to_string(&mut contents).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    /// Number of documents of each entry recorded in the manifest
+    pub(crate) fn read_entry_counts(&mut self) -> HashMap<String, u64> {
+        let manifest = self.read_manifest().unwrap_or_default();
+        let Some(Value::Array(entries)) = manifest.get("entries") else {
+            return HashMap::new();
+        };
+        let counts = entries.iter().filter_map(|entry| {
+            let name = entry.get("name").and_then(Value::as_str)?;
+            Some((name.to_string(), entry.get("docs").and_then(Value::as_u64)?))
+        });
+        counts.collect()
+    }
+
+    /// Number of entries with documents, read only from the zip central directory
+    pub(crate) fn get_entry_count(&self) -> u64 {
+        let has_manifest = self.archive.index_for_name(MANIFEST_ENTRY).is_some();
+        (self.archive.len() - usize::from(has_manifest)).to_u64()
+    }
+}
+
+impl ArchiveTotals {
+    /// Sums the entries of all archives and the documents recorded in their manifests.
+    /// Archives that cannot be opened are left for the readers to report
+    pub(crate) fn count(found: &[PathBuf], key: Option<&str>) -> Self {
+        let mut totals = ArchiveTotals { batches: 0, docs: Some(0) };
+        for archive in found {
+            if ArchiveFormat::of_file(archive) == ArchiveFormat::Tar {
+                let (batches, recorded) = TarReader::count_entries(archive);
+                totals.batches += batches;
+                totals.docs = totals.docs.zip(recorded).map(|(sum, docs)| sum + docs);
+                continue;
+            }
+            if matches!(ArchiveFormat::of_file(archive), ArchiveFormat::Jsonl | ArchiveFormat::Csv)
+            {
+                // Counting the lines would uncompress the files twice, so they are counted
+                // only while restoring them, as when reading from stdin
+                return ArchiveTotals::default();
+            }
+            match ArchiveReader::create_reader(archive) {
+                Ok(reader) => {
+                    let mut reader = reader.decrypted_with(key);
+                    totals.batches += reader.get_entry_count();
+                    let recorded = reader.read_manifest().and_then(|found| found["docs"].as_u64());
+                    totals.docs = totals.docs.zip(recorded).map(|(sum, docs)| sum + docs);
+                }
+                Err(cause) => warn!("Error opening archive {:?}: {}", archive, cause),
+            }
+        }
+        totals
+    }
+}
+
+impl TarReader {
+    pub(crate) fn create_reader(path: &Path) -> BoxedResult<TarReader> {
+        Ok(Self::from_stream(open_decoder(path)?))
+    }
+
+    pub(crate) fn from_stream(stream: Box<dyn Read>) -> TarReader {
+        TarReader { stream, entry_count: 0, finished: false }
+    }
+
+    /// Number of entries with documents and the documents recorded in the manifest.
+    /// Counts until the first error when the archive is damaged or unfinished
+    pub(crate) fn count_entries(path: &Path) -> (u64, Option<u64>) {
+        let mut reader = match Self::create_reader(path) {
+            Ok(reader) => reader,
+            Err(cause) => {
+                warn!("Error opening archive {:?}: {}", path, cause);
+                return (0, None);
+            }
+        };
+        let (mut batches, mut docs) = (0, None);
+        loop {
+            match reader.read_entry() {
+                Ok(None) => break,
+                Ok(Some((name, contents))) if name == MANIFEST_ENTRY => {
+                    let manifest: Option<Value> = serde_json::from_slice(&contents).ok();
+                    docs = manifest.and_then(|found| found["docs"].as_u64());
+                }
+                Ok(Some(_)) => batches += 1,
+                Err(cause) => {
+                    warn!("Error reading archive {:?}: {}", path, cause);
+                    break;
+                }
+            }
+        }
+        (batches, docs)
+    }
+
+    /// Reads the next file entry as its name and contents or none at the end of the archive.
+    /// A new `tar::Archive` is opened for each entry, as its entries borrow the archive, and it
+    /// resolves the GNU long names and pax headers preceding the file entry
+    pub(crate) fn read_entry(&mut self) -> BoxedResult<Option<(String, Vec<u8>)>> {
+        let (name, contents) = {
+            let mut archive = tar::Archive::new(&mut self.stream);
+            let mut entries = archive.entries()?;
+            loop {
+                let Some(entry) = entries.next() else {
+                    return Ok(None);
+                };
+                let entry = entry?;
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let size = entry.size();
+                let name = entry.path()?.to_string_lossy().to_string();
+                let mut contents = Vec::new();
+                entry.take(size).read_to_end(&mut contents)?;
+                if contents.len().to_u64() < size {
+                    return fail_with(Error::Archive(format!("unexpected end of entry {}", name)));
+                }
+                break (name, contents);
+            }
+        };
+        // The archive skips the padding of an entry only when reading the next one
+        let size = contents.len().to_u64();
+        let padding = (TAR_BLOCK - size % TAR_BLOCK) % TAR_BLOCK;
+        std::io::copy(&mut (&mut self.stream).take(padding), &mut std::io::sink())?;
+        Ok(Some((name, contents)))
+    }
+}
+
+/// Yields the name, contents and document count of each entry with documents, or why it could
+/// not be read. After an error the iteration ends, as a broken stream cannot be resumed.
+impl Iterator for TarReader {
+    type Item = BoxedResult<(String, String, u64)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        self.entry_count += 1;
+        let index = self.entry_count;
+        match self.read_entry() {
+            Ok(None) => {
+                self.finished = true;
+                None
+            }
+            Ok(Some((name, _))) if name == MANIFEST_ENTRY => self.next(),
+            Ok(Some((name, contents))) => match String::from_utf8(contents) {
+                Ok(json) => {
+                    let parsed = serde_json::from_str::<Vec<IgnoredAny>>(&json);
+                    let count = parsed.map_or(0, |docs| docs.len().to_u64());
+                    Some(Ok((name, json, count)))
+                }
+                Err(cause) => Some(fail_with(Error::Archive(format!(
+                    "error reading entry {}: {}",
+                    name, cause
+                )))),
+            },
+            Err(cause) => {
+                self.finished = true;
+                Some(fail_with(Error::Archive(format!(
+                    "error reading entry #{}: {}",
+                    index, cause
+                ))))
             }
         }
     }
 }
 
+impl JsonLinesReader {
+    pub(crate) fn from_stream(stream: Box<dyn Read>) -> JsonLinesReader {
+        let lines = BufReader::new(stream).lines();
+        JsonLinesReader { lines, line_count: 0, failure: None, finished: false }
+    }
+}
+
+/// Yields the lines read as a json array, or why the file could not be read further.
+/// After an error the iteration ends, as a broken compressed stream cannot be resumed.
+impl Iterator for JsonLinesReader {
+    type Item = BoxedResult<(String, String, u64)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(cause) = self.failure.take() {
+            return Some(fail_with(Error::Archive(cause)));
+        }
+        if self.finished {
+            return None;
+        }
+        let first = self.line_count + 1;
+        let mut docs: Vec<String> = Vec::with_capacity(STREAM_BATCH_DOCS);
+        while docs.len() < STREAM_BATCH_DOCS {
+            match self.lines.next() {
+                None => {
+                    self.finished = true;
+                    break;
+                }
+                Some(Err(cause)) => {
+                    self.finished = true;
+                    self.failure =
+                        Some(format!("error reading line {}: {}", self.line_count + 1, cause));
+                    break;
+                }
+                Some(Ok(line)) => {
+                    self.line_count += 1;
+                    if !line.trim().is_empty() {
+                        docs.push(line);
+                    }
+                }
+            }
+        }
+        if docs.is_empty() {
+            return self.next();
+        }
+        let name = format!("lines_{}_to_{}", first, self.line_count);
+        let count = docs.len().to_u64();
+        Some(Ok((name, format!("[{}]", docs.join(",")), count)))
+    }
+}
+
+/// Yields the name and contents of each entry with documents, or why it could not be read.
+/// After an error the next call continues with the following entry.
 impl Iterator for ArchiveReader {
-    type Item = (String, String);
+    type Item = BoxedResult<(String, String)>;
 
     fn next(&mut self) -> Option<Self::Item> {
         let file_count = self.archive.len();
         if self.entry_index >= file_count {
             return None;
         }
-        let mut compressed = self.archive.by_index(self.entry_index).unwrap();
+        let index = self.entry_index;
+        self.entry_index += 1;
+        if self.archive.name_for_index(index) == Some(MANIFEST_ENTRY) {
+            return self.next();
+        }
+        let mut compressed = match self.open_entry(index) {
+            Ok(entry) => entry,
+            Err(cause) => {
+                return Some(fail_with(Error::Archive(format!(
+                    "error reading entry #{}: {}",
+                    index + 1,
+                    cause
+                ))));
+            }
+        };
         let zip_name = compressed.name().to_string();
         let mut zip_contents = String::new();
         let reading = compressed.read_to_string(&mut zip_contents);
         match reading {
-            Err(cause) => {
-                error!("error reading archive #{} {}: {}", self.entry_index + 1, zip_name, cause);
-                None
-            }
-            Ok(_) => {
-                self.entry_index += 1;
-                Some((zip_name, zip_contents))
-            }
+            Err(cause) => Some(fail_with(Error::Archive(format!(
+                "error reading entry #{} {}: {}",
+                index + 1,
+                zip_name,
+                cause
+            )))),
+            Ok(_) => Some(Ok((zip_name, zip_contents))),
         }
     }
 
@@ -127,8 +528,10 @@ fn size_hint(&self) -> (usize, Option<usize>) {
 }
 
 impl Docs {
-    pub(crate) fn new(archive_name: String, entry_name: String, documents: String) -> Self {
-        Docs { archive: archive_name, entry: entry_name, json: documents }
+    pub(crate) fn new(
+        archive_name: String, entry_name: String, documents: String, count: u64,
+    ) -> Self {
+        Docs { archive: archive_name, entry: entry_name, json: documents, count }
     }
 }
 
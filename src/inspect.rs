@@ -0,0 +1,305 @@
+use super::{
+    args::CsvArgs,
+    args::{Inspect, OutputFormat},
+    fails::{BoxedError, BoxedResult, throw},
+    helpers::{EMPTY_STR, IntegerHelpers},
+    ingest::{ArchiveReader, find_archives, get_search_pattern, read_backup_file},
+    models::ArchiveFormat,
+    rewrite::compare_values,
+};
+use log::{debug, warn};
+use serde_json::{Map, Value, json};
+use std::collections::{BTreeMap, BTreeSet};
+use std::{fs, path::Path};
+
+// region Data Structures
+
+/// What was found inside a single archive file
+#[derive(Debug, Default)]
+pub(crate) struct ArchiveSummary {
+    pub name: String,
+    pub size: u64,
+    pub entries: u64,
+    pub docs: u64,
+    /// Entries that could not be read or parsed as a json array of documents
+    pub invalid: u64,
+    pub compression: BTreeSet<String>,
+    pub manifest: Option<Value>,
+}
+
+/// Totals of all archives inspected
+#[derive(Debug, Default)]
+pub(crate) struct Inventory {
+    pub archives: Vec<ArchiveSummary>,
+    /// Count of documents having each field
+    pub fields: BTreeMap<String, u64>,
+    pub min: Option<Value>,
+    pub max: Option<Value>,
+}
+
+// endregion
+
+pub(crate) fn inspect_main(params: &Inspect) -> BoxedError {
+    debug!("# INSPECT {:?}", params);
+
+    let ext = params.archive_format.get_search_ext();
+    let pattern = get_search_pattern(&params.dir, &params.search, EMPTY_STR, ext);
+    let found = find_archives(&pattern, params.order)?;
+    if found.is_empty() {
+        throw(format!("Found no archives to inspect: {}", pattern))?;
+    }
+    let key = params.encryption.get_key()?;
+    let mut inventory = Inventory::default();
+    for archive in found {
+        inventory.add_archive(&archive, params.field.as_deref(), &params.csv, key.as_deref())?;
+    }
+    let report = match params.format {
+        OutputFormat::Text => inventory.to_text(params.field.as_deref()),
+        OutputFormat::Json => serde_json::to_string_pretty(&inventory.to_json(params))?,
+    };
+    println!("{}", report);
+    Ok(())
+}
+
+// region Scanning
+
+impl Inventory {
+    fn add_archive(
+        &mut self, archive: &Path, field: Option<&str>, csv: &CsvArgs, key: Option<&str>,
+    ) -> BoxedResult<()> {
+        let mut summary = ArchiveSummary {
+            name: archive.file_name().unwrap_or_default().to_string_lossy().to_string(),
+            size: fs::metadata(archive)?.len(),
+            ..Default::default()
+        };
+        if ArchiveFormat::of_file(archive) == ArchiveFormat::Zip {
+            let mut reader = ArchiveReader::create_reader(archive)?.decrypted_with(key);
+            for index in 0..reader.archive.len() {
+                let entry = reader.archive.by_index_raw(index)?;
+                summary.compression.insert(entry.compression().to_string());
+            }
+            summary.manifest = reader.read_manifest();
+        } else {
+            summary.compression.insert(get_stream_compression(archive).to_string());
+        }
+        for read in read_backup_file(archive, csv, &[], key)? {
+            summary.entries += 1;
+            let (entry, json, _) = match read {
+                Ok(contents) => contents,
+                Err(cause) => {
+                    warn!("Error in archive {}: {}", summary.name, cause);
+                    summary.invalid += 1;
+                    continue;
+                }
+            };
+            match serde_json::from_str::<Vec<Value>>(&json) {
+                Ok(docs) => {
+                    summary.docs += docs.len().to_u64();
+                    docs.iter().for_each(|doc| self.add_doc(doc, field));
+                }
+                Err(cause) => {
+                    warn!("Error parsing docs in {} of {}: {}", entry, summary.name, cause);
+                    summary.invalid += 1;
+                }
+            }
+        }
+        self.archives.push(summary);
+        Ok(())
+    }
+
+    fn add_doc(&mut self, doc: &Value, field: Option<&str>) {
+        let Value::Object(fields) = doc else {
+            return;
+        };
+        for name in fields.keys() {
+            *self.fields.entry(name.clone()).or_default() += 1;
+        }
+        let Some(value) = field.and_then(|name| fields.get(name)) else {
+            return;
+        };
+        let values = match value {
+            Value::Array(items) => items.iter().collect(),
+            _ => vec![value],
+        };
+        for value in values {
+            if self.min.as_ref().is_none_or(|min| compare_values(value, min).is_lt()) {
+                self.min = Some(value.clone());
+            }
+            if self.max.as_ref().is_none_or(|max| compare_values(value, max).is_gt()) {
+                self.max = Some(value.clone());
+            }
+        }
+    }
+
+    fn total(&self, count: impl Fn(&ArchiveSummary) -> u64) -> u64 {
+        self.archives.iter().map(count).sum()
+    }
+}
+
+/// Compression of the backup files written as a single stream, told by their extension
+fn get_stream_compression(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz") => "Gzip",
+        Some("zst") => "Zstd",
+        _ => "Stored",
+    }
+}
+
+// endregion
+
+// region Output
+
+impl Inventory {
+    fn to_text(&self, field: Option<&str>) -> String {
+        let mut lines = vec![];
+        for archive in &self.archives {
+            let methods = archive.compression.iter().cloned().collect::<Vec<_>>().join(", ");
+            lines.push(format!("Archive: {}", archive.name));
+            lines.push(format!(
+                "  size: {} bytes, entries: {}, documents: {}, compression: {}",
+                archive.size, archive.entries, archive.docs, methods
+            ));
+            if archive.invalid > 0 {
+                lines.push(format!("  invalid entries: {}", archive.invalid));
+            }
+            if let Some(manifest) = &archive.manifest {
+                lines.push(format!("  manifest: {}", manifest));
+            }
+        }
+        lines.push(format!(
+            "Total: {} archives, {} entries, {} documents, {} bytes",
+            self.archives.len(),
+            self.total(|arc| arc.entries),
+            self.total(|arc| arc.docs),
+            self.total(|arc| arc.size)
+        ));
+        let width = self.fields.keys().map(String::len).max().unwrap_or(0);
+        lines.push("Fields:".to_string());
+        for (name, count) in &self.fields {
+            lines.push(format!("  {:width$}  {}", name, count, width = width));
+        }
+        if let Some(name) = field {
+            match (&self.min, &self.max) {
+                (Some(min), Some(max)) => {
+                    lines.push(format!("Range of '{}': {} to {}", name, min, max))
+                }
+                _ => lines.push(format!("Range of '{}': not found in any document", name)),
+            }
+        }
+        lines.join("\n")
+    }
+
+    fn to_json(&self, params: &Inspect) -> Value {
+        let archives: Vec<Value> = self
+            .archives
+            .iter()
+            .map(|arc| {
+                json!({
+                    "name": arc.name,
+                    "size": arc.size,
+                    "entries": arc.entries,
+                    "docs": arc.docs,
+                    "invalid": arc.invalid,
+                    "compression": arc.compression,
+                    "manifest": arc.manifest,
+                })
+            })
+            .collect();
+        let fields: Map<String, Value> =
+            self.fields.iter().map(|(name, count)| (name.clone(), json!(count))).collect();
+        let mut report = json!({
+            "dir": params.dir,
+            "archives": archives,
+            "totals": {
+                "archives": self.archives.len(),
+                "entries": self.total(|arc| arc.entries),
+                "docs": self.total(|arc| arc.docs),
+                "size": self.total(|arc| arc.size),
+            },
+            "fields": fields,
+        });
+        if let Some(name) = &params.field {
+            report["range"] = json!({ "field": name, "min": self.min, "max": self.max });
+        }
+        report
+    }
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use super::Inventory;
+    use crate::args::{Cli, Commands, CsvArgs};
+    use crate::helpers::{IntegerHelpers, shared::TempDir};
+    use crate::models::{Compression, Documents, Step};
+    use crate::save::{Archiver, JsonLinesWriter};
+    use crate::storage::DocumentSink;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn check_inspect_args() {
+        let parsed = Cli::mockup_from(&["solrcopy", "inspect", "--dir", ".", "--format", "json"]);
+        match parsed {
+            Commands::Inspect(ins) => assert_eq!(ins.field, None),
+            _ => panic!("command must be 'inspect' !"),
+        }
+    }
+
+    #[test]
+    fn check_inventory_of_archive() {
+        let dir = TempDir::new("inspect");
+
+        let mut archiver = Archiver::write_on(&dir, "demo_seq_{}.zip", Compression::Zip, 10);
+        let docs = [
+            json!([{"id": "1", "price": 5}, {"id": "2", "price": [3, 9], "name": "b"}]),
+            json!([{"id": "3", "name": "c"}]),
+        ];
+        for (curr, batch) in docs.iter().enumerate() {
+            let step = Step { curr: curr.to_u64(), expected: 0, url: String::new() };
+            archiver.write_documents(&Documents { step, docs: batch.to_string() }).unwrap();
+        }
+        archiver.close_archive().unwrap();
+        let archive = dir.join("demo_seq_000000001.zip");
+
+        let mut inventory = Inventory::default();
+        inventory.add_archive(&archive, Some("price"), &CsvArgs::default(), None).unwrap();
+
+        let summary = &inventory.archives[0];
+        assert_eq!((summary.entries, summary.docs, summary.invalid), (2, 3, 0));
+        assert_eq!(summary.compression.iter().collect::<Vec<_>>(), vec!["Deflated"]);
+        assert_eq!(inventory.fields.get("id"), Some(&3));
+        assert_eq!(inventory.fields.get("name"), Some(&2));
+        assert_eq!(inventory.min, Some(json!(3)));
+        assert_eq!(inventory.max, Some(json!(9)));
+        assert_eq!(summary.manifest.as_ref().map(|found| found["docs"].clone()), Some(json!(3)));
+
+        let text = inventory.to_text(Some("price"));
+        assert!(text.contains("Total: 1 archives, 2 entries, 3 documents"));
+        assert!(text.contains("Range of 'price': 3 to 9"));
+    }
+
+    #[test]
+    fn check_inventory_of_json_lines() {
+        let dir = TempDir::new("inspect_jl");
+
+        let mut writer =
+            JsonLinesWriter::write_on(&dir, "demo_seq_{}.jsonl.gz", Compression::Zip, 10);
+        let docs = json!([{"id": "1", "price": 5}, {"id": "2", "price": 7}]);
+        let step = Step { curr: 0, expected: 0, url: String::new() };
+        writer.write_documents(&Documents { step, docs: docs.to_string() }).unwrap();
+        writer.close_archive().unwrap();
+        let file = dir.join("demo_seq_000000001.jsonl.gz");
+
+        let mut inventory = Inventory::default();
+        inventory.add_archive(&file, Some("price"), &CsvArgs::default(), None).unwrap();
+
+        let summary = &inventory.archives[0];
+        assert_eq!((summary.entries, summary.docs, summary.invalid), (1, 2, 0));
+        assert_eq!(summary.compression.iter().collect::<Vec<_>>(), vec!["Gzip"]);
+        assert_eq!(inventory.max, Some(json!(7)));
+    }
+}
+
+// end of file \\
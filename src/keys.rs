@@ -0,0 +1,355 @@
+use super::{
+    args::{Backup, DuplicateMode},
+    fails::BoxedResult,
+    fetch::json_to_key,
+    helpers::IntegerHelpers,
+    models::Documents,
+};
+use log::warn;
+use serde::de::{DeserializeSeed, Deserializer, Error as _, IgnoredAny, MapAccess, Visitor};
+use serde_json::{Value, value::RawValue};
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::{self, File, OpenOptions},
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// Number of files the uniqueKeys stored on disk are spread across by their hash
+const PARTITIONS: usize = 256;
+
+/// Number of uniqueKeys of a partition kept in memory before appending them to its file
+const PENDING_KEYS: usize = 1024;
+
+/// Bits of the bloom filter for each expected uniqueKey, giving about 1% of false positives
+const BITS_PER_KEY: u64 = 10;
+
+/// Number of bits set in the bloom filter for each uniqueKey
+const BLOOM_HASHES: u64 = 7;
+
+// region SeenKeys
+
+/// Set of the uniqueKeys of the documents already written by backup, shared by the writers
+pub(crate) trait SeenKeys: Send + Sync {
+    /// Adds the `key` and tells if it was not in the set yet
+    fn insert(&self, key: &str) -> BoxedResult<bool>;
+}
+
+/// Keeps all the uniqueKeys in memory
+#[derive(Debug, Default)]
+struct MemoryKeys {
+    seen: Mutex<HashSet<String>>,
+}
+
+impl SeenKeys for MemoryKeys {
+    fn insert(&self, key: &str) -> BoxedResult<bool> {
+        Ok(self.seen.lock().unwrap().insert(key.to_string()))
+    }
+}
+
+/// Keeps the uniqueKeys in files partitioned by their hash.
+/// A bloom filter in memory avoids reading the files for the keys never seen before.
+/// Each partition has its own lock, so the writers search the files of others meanwhile
+struct DiskKeys {
+    dir: PathBuf,
+    filter: BloomFilter,
+    /// Keys of each partition not yet appended to its file
+    pending: Vec<Mutex<Vec<String>>>,
+}
+
+impl DiskKeys {
+    fn create_in(parent: &Path, expected: u64) -> BoxedResult<Self> {
+        let dir = parent.join(format!("solrcopy_keys_{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let pending = (0..PARTITIONS).map(|_| Mutex::new(vec![])).collect();
+        Ok(Self { dir, filter: BloomFilter::with_capacity(expected), pending })
+    }
+
+    fn get_partition_path(&self, partition: usize) -> PathBuf {
+        self.dir.join(format!("keys_{:03}.jsonl", partition))
+    }
+
+    /// Searches the `line` of the encoded key in the `pending` keys and in the file of the partition
+    fn contains(&self, partition: usize, pending: &[String], line: &str) -> BoxedResult<bool> {
+        if pending.iter().any(|found| found == line) {
+            return Ok(true);
+        }
+        let path = self.get_partition_path(partition);
+        if !path.exists() {
+            return Ok(false);
+        }
+        for stored in BufReader::new(File::open(path)?).lines() {
+            if stored? == line {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn flush(&self, partition: usize, pending: &mut Vec<String>) -> BoxedResult<()> {
+        let path = self.get_partition_path(partition);
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut writer = BufWriter::new(file);
+        for line in pending.drain(..) {
+            writeln!(writer, "{}", line)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl SeenKeys for DiskKeys {
+    fn insert(&self, key: &str) -> BoxedResult<bool> {
+        let hash = hash_key(key);
+        let partition = (hash % PARTITIONS.to_u64()).to_usize();
+        // Keys are stored as json strings for keeping line breaks inside them in a single line
+        let line = serde_json::to_string(key)?;
+        // The same key always falls in the same partition, so its lock orders the inserts of it
+        let mut pending = self.pending[partition].lock().unwrap();
+        if self.filter.insert(hash) && self.contains(partition, &pending, &line)? {
+            return Ok(false);
+        }
+        pending.push(line);
+        if pending.len() >= PENDING_KEYS {
+            self.flush(partition, &mut pending)?;
+        }
+        Ok(true)
+    }
+}
+
+impl Drop for DiskKeys {
+    fn drop(&mut self) {
+        if let Err(cause) = fs::remove_dir_all(&self.dir) {
+            warn!("Error removing the folder of the tracked keys {:?}: {}", self.dir, cause);
+        }
+    }
+}
+
+/// Bloom filter with atomic bits, so the writers set them without locking the whole filter
+struct BloomFilter {
+    bits: Vec<AtomicU64>,
+    size: u64,
+}
+
+impl BloomFilter {
+    fn with_capacity(expected: u64) -> Self {
+        let size = expected.max(1024) * BITS_PER_KEY;
+        let bits = (0..size.div_ceil(64)).map(|_| AtomicU64::new(0)).collect();
+        Self { bits, size }
+    }
+
+    /// Sets the bits of the `hash` and tells if all of them were already set
+    fn insert(&self, hash: u64) -> bool {
+        let (first, second) = (hash & 0xffff_ffff, hash >> 32);
+        let mut present = true;
+        for round in 0..BLOOM_HASHES {
+            let bit = first.wrapping_add(round.wrapping_mul(second)) % self.size;
+            let (word, mask) = ((bit / 64).to_usize(), 1u64 << (bit % 64));
+            present &= self.bits[word].fetch_or(mask, Ordering::SeqCst) & mask != 0;
+        }
+        present
+    }
+}
+
+fn hash_key(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+// endregion
+
+// region KeyTracker
+
+/// Tracks the uniqueKeys of the documents written by all writer threads of backup
+pub(crate) struct KeyTracker {
+    unique_key: String,
+    mode: DuplicateMode,
+    seen: Box<dyn SeenKeys>,
+    pub unique: AtomicU64,
+    pub duplicates: AtomicU64,
+    /// Documents written without the uniqueKey field
+    pub unkeyed: AtomicU64,
+}
+
+impl KeyTracker {
+    /// Creates the tracker chosen by `--duplicates` for `expected` documents, if any
+    pub(crate) fn open(params: &Backup, expected: u64) -> BoxedResult<Option<Self>> {
+        let Some(mode) = params.duplicates else {
+            return Ok(None);
+        };
+        let seen: Box<dyn SeenKeys> = match &params.keys_dir {
+            None => Box::new(MemoryKeys::default()),
+            Some(dir) => Box::new(DiskKeys::create_in(dir, expected)?),
+        };
+        Ok(Some(Self {
+            unique_key: params.unique_key.clone(),
+            mode,
+            seen,
+            unique: AtomicU64::new(0),
+            duplicates: AtomicU64::new(0),
+            unkeyed: AtomicU64::new(0),
+        }))
+    }
+
+    /// Counts the uniqueKeys of the batch, removing the duplicated documents with `--duplicates drop`.
+    /// Documents without the uniqueKey are kept and counted apart.
+    /// Only the uniqueKey of each document is parsed, the other fields are skipped
+    pub(crate) fn track(&self, docs: &mut Documents) -> BoxedResult<()> {
+        let parsed: Vec<&RawValue> = serde_json::from_str(&docs.docs)?;
+        let total = parsed.len();
+        let mut kept = Vec::with_capacity(total);
+        for doc in parsed {
+            let mut reader = serde_json::Deserializer::from_str(doc.get());
+            let Some(key) = KeyOf(&self.unique_key).deserialize(&mut reader)? else {
+                self.unkeyed.fetch_add(1, Ordering::SeqCst);
+                kept.push(doc.get());
+                continue;
+            };
+            if self.seen.insert(&json_to_key(&key))? {
+                self.unique.fetch_add(1, Ordering::SeqCst);
+            } else {
+                self.duplicates.fetch_add(1, Ordering::SeqCst);
+                if self.mode == DuplicateMode::Drop {
+                    continue;
+                }
+            }
+            kept.push(doc.get());
+        }
+        if kept.len() < total {
+            docs.docs = format!("[{}]", kept.join(","));
+        }
+        Ok(())
+    }
+}
+
+/// Reads the value of the field named as it from a json document, skipping the other fields
+struct KeyOf<'a>(&'a str);
+
+impl<'de> DeserializeSeed<'de> for KeyOf<'_> {
+    type Value = Option<Value>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for KeyOf<'_> {
+    type Value = Option<Value>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str("a json document")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut found = None;
+        while let Some(matched) = map.next_key_seed(IsField(self.0))? {
+            if !matched {
+                map.next_value::<IgnoredAny>()?;
+            } else if found.is_some() {
+                return Err(A::Error::duplicate_field("uniqueKey"));
+            } else {
+                found = Some(map.next_value::<Value>()?);
+            }
+        }
+        Ok(found)
+    }
+}
+
+/// Tells if the name of a field of a json document is the one given, without allocating it
+struct IsField<'a>(&'a str);
+
+impl<'de> DeserializeSeed<'de> for IsField<'_> {
+    type Value = bool;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_str(self)
+    }
+}
+
+impl Visitor<'_> for IsField<'_> {
+    type Value = bool;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str("a field name")
+    }
+
+    fn visit_str<E>(self, name: &str) -> Result<Self::Value, E> {
+        Ok(name == self.0)
+    }
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use super::{DiskKeys, KeyTracker, MemoryKeys, SeenKeys};
+    use crate::{
+        args::Cli,
+        helpers::shared::TempDir,
+        models::{Documents, Step},
+    };
+    use clap::Parser;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use std::{fs, sync::atomic::Ordering};
+
+    fn insert_all(seen: &dyn SeenKeys, keys: &[String]) -> Vec<bool> {
+        keys.iter().map(|key| seen.insert(key).unwrap()).collect()
+    }
+
+    #[test]
+    fn check_seen_keys() {
+        let dir = TempDir::new("seen");
+
+        // Enough keys for appending some partitions to their files
+        let keys: Vec<String> = (0..5000).map(|it| format!("doc\n{}", it)).collect();
+        let memory = MemoryKeys::default();
+        let disk = DiskKeys::create_in(&dir, 100).unwrap();
+        let first_in_memory = insert_all(&memory, &keys);
+        let first_on_disk = insert_all(&disk, &keys);
+        let again_in_memory = insert_all(&memory, &keys[..100]);
+        let again_on_disk = insert_all(&disk, &keys[..100]);
+        drop(disk);
+        let left = fs::read_dir(&*dir).unwrap().count();
+
+        assert!(first_in_memory.iter().all(|&new| new));
+        assert!(first_on_disk.iter().all(|&new| new));
+        assert!(again_in_memory.iter().all(|&new| !new));
+        assert!(again_on_disk.iter().all(|&new| !new));
+        assert_eq!(left, 0);
+    }
+
+    #[test]
+    fn check_drop_duplicates() {
+        let args = &["solrcopy", "backup", "--url", "http://localhost:8983/solr", "--core"];
+        let args = [args.as_slice(), &["demo", "--dir", "target", "--duplicates", "drop"]].concat();
+        let parsed = Cli::parse_from(args).arguments;
+        let tracker = KeyTracker::open(parsed.get().unwrap(), 10).unwrap().unwrap();
+
+        let mut written = vec![];
+        let nested = json!({"id": "4", "items": [{"id": "2"}]});
+        for batch in [
+            json!([{"id": 1}, {"id": "2"}]),
+            json!([{"id": "2"}, {"id": 3}, {"x": 4}, nested.clone()]),
+        ] {
+            let step = Step { curr: 0, expected: 0, url: String::new() };
+            let mut docs = Documents { step, docs: batch.to_string() };
+            tracker.track(&mut docs).unwrap();
+            written.push(docs.docs);
+        }
+
+        assert_eq!(written[1], json!([{"id": 3}, {"x": 4}, nested]).to_string());
+        assert_eq!(tracker.unique.load(Ordering::SeqCst), 4);
+        assert_eq!(tracker.duplicates.load(Ordering::SeqCst), 1);
+        assert_eq!(tracker.unkeyed.load(Ordering::SeqCst), 1);
+    }
+}
+
+// end of file \\
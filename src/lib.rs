@@ -0,0 +1,162 @@
+//! Library for migration, transformations, backup and restore of documents stored in cores
+//! of Apache Solr, the same used by the `solrcopy` command line tool.
+//!
+//! Each operation is configured by a builder and runs in the calling thread:
+//!
+//! ```no_run
+//! use solrcopy::{ArchiveFormat, BackupBuilder, CancelToken};
+//!
+//! let token = CancelToken::new();
+//! BackupBuilder::new("http://localhost:8983/solr", "demo", "/tmp/backups")
+//!     .query("price:[1 TO *]")
+//!     .archive_format(ArchiveFormat::Jsonl)
+//!     .cancel_with(token.clone())
+//!     .on_progress(|progress| println!("{} documents", progress.docs))
+//!     .run()?;
+//! # Ok::<(), solrcopy::Error>(())
+//! ```
+
+// region Module and crate references
+
+// region Strict Linting
+
+#![deny(warnings)]
+#![deny(anonymous_parameters)]
+#![deny(bare_trait_objects)]
+#![deny(elided_lifetimes_in_paths)]
+#![deny(missing_debug_implementations)]
+#![deny(single_use_lifetimes)]
+#![deny(trivial_casts)]
+#![deny(trivial_numeric_casts)]
+#![deny(unsafe_code)]
+#![deny(unused_extern_crates)]
+#![deny(unused_must_use)]
+#![deny(unused_import_braces)]
+#![deny(unused_imports)]
+// Lints of the tests written before linting them with clippy
+#![cfg_attr(
+    test,
+    allow(
+        clippy::redundant_static_lifetimes,
+        clippy::get_first,
+        clippy::len_zero,
+        clippy::module_inception,
+        clippy::needless_borrow,
+        clippy::bool_assert_comparison
+    )
+)]
+
+// endregion
+
+// region Switches for develoment only (do not commit enabled)
+
+// #![allow(unused_variables)]
+// #![allow(unused_imports)]
+// #![allow(unused_import_braces)]
+// #![allow(dead_code)]
+// #![allow(unreachable_code)]
+// #![allow(unused)]
+
+// endregion
+
+// region Imported Modules
+
+#[macro_use]
+extern crate lazy_static;
+
+mod args;
+mod assets;
+mod backup;
+mod bars;
+mod bucket;
+mod builders;
+mod columnar;
+mod commit;
+mod connection;
+mod create;
+mod delete;
+mod diff;
+#[macro_use]
+mod fails;
+mod fetch;
+mod helpers;
+mod information;
+mod ingest;
+mod inspect;
+mod keys;
+mod models;
+mod report;
+mod restore;
+mod rewrite;
+mod save;
+mod state;
+mod steps;
+mod storage;
+mod sync;
+mod tabular;
+mod testsolr;
+mod verify;
+
+// endregion
+
+// endregion
+
+use crate::args::Cli;
+
+// region Public API
+
+pub use crate::builders::{AdminBuilder, BackupBuilder, CopyBuilder, RestoreBuilder};
+pub use crate::fails::Error;
+pub use crate::models::{ArchiveFormat, Compression};
+pub use crate::state::{CancelToken, Progress, handle_ctrl_c};
+
+/// Parses the command line arguments and runs the command like the `solrcopy` binary.
+/// The failure tells the exit code of the process by [`Error::exit_code`].
+/// Call [`handle_ctrl_c`] before it for stopping the command on Ctrl-C
+pub fn run_from_args() -> Result<(), Error> {
+    Cli::parse_from_args().and_then(|parsed| wrangle::command_exec(&parsed)).map_err(Error::from)
+}
+
+// endregion
+
+// region Command line parsing
+
+mod wrangle {
+
+    use crate::args::{Cli, Commands};
+    use crate::fails::{BoxedResult, Error, fail_with};
+    use crate::{
+        assets, backup, commit, create, delete, diff, information, inspect, restore, sync, verify,
+    };
+    use clap::Parser;
+
+    pub(crate) fn command_exec(args: &Commands) -> Result<(), Box<dyn std::error::Error>> {
+        match args {
+            Commands::Backup(get) => backup::backup_main(get),
+            Commands::Restore(put) => restore::restore_main(put),
+            Commands::Commit(cmd) => commit::commit_main(cmd),
+            Commands::Delete(del) => delete::delete_main(del),
+            Commands::Create(cre) => create::create_main(cre),
+            Commands::Info(inf) => information::info_main(inf),
+            Commands::Diff(dif) => diff::diff_main(dif),
+            Commands::Sync(syn) => sync::sync_main(syn),
+            Commands::Inspect(ins) => inspect::inspect_main(ins),
+            Commands::Verify(ver) => verify::verify_main(ver),
+            Commands::Generate(cpl) => assets::gen_assets(cpl),
+        }
+    }
+
+    impl Cli {
+        pub(crate) fn parse_from_args() -> BoxedResult<Commands> {
+            let parsed = Self::parse();
+            let cmds = parsed.arguments;
+            if let Err(msg) = cmds.validate() {
+                fail_with(Error::InvalidArguments(msg))?;
+            }
+            cmds.get_logging().start_log()?;
+            Ok(cmds)
+        }
+    }
+}
+
+// endregion
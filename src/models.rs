@@ -1,6 +1,12 @@
 // region Data Structures
 
+use arrow_schema::{DataType, SchemaRef};
 use clap::ValueEnum;
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+};
 
 #[derive(Debug)]
 pub(crate) struct Documents {
@@ -21,17 +27,129 @@ pub(crate) struct SolrCore {
     pub fields: Vec<String>,
 }
 
+/// Compression of the backup files. For tar, jsonl and csv files zip means gzip
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
-pub(crate) enum Compression {
+pub enum Compression {
     Stored,
     Zip,
     Zstd,
 }
 
+/// Name of the archive entry with metadata about the backup instead of documents
+pub(crate) const MANIFEST_ENTRY: &str = "manifest.json";
+
+/// Extension appended to the archive name for the file with its SHA-256 checksum
+pub(crate) const CHECKSUM_EXT: &str = ".sha256";
+
+/// Suffix of the backup files still being written, which restore ignores
+pub(crate) const PARTIAL_EXT: &str = ".partial";
+
+/// Format of the files where backup stores the documents and restore reads them from
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum ArchiveFormat {
+    /// Zip archive with an entry containing a json array for each batch of documents
+    Zip,
+    /// Tar archive with the same entries of zip, but written and read as a compressed stream
+    Tar,
+    /// Newline-delimited json with one document per line, gzip or zstd compressed
+    Jsonl,
+    /// Comma-separated values with a column for each field, gzip or zstd compressed
+    Csv,
+    /// Apache Parquet with a column for each field for analytics tools
+    Parquet,
+}
+
+/// Fields written as columns in csv and parquet files
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Columns {
+    pub names: Vec<String>,
+    /// Arrow types of the columns declared in the Solr schema
+    pub types: HashMap<String, DataType>,
+    /// Parquet schema shared by all writers, widened when a value does not fit in it
+    pub schema: Arc<Mutex<Option<SchemaRef>>>,
+}
+
 impl Compression {
-    pub(crate) fn get_ext(&self) -> &str {
+    pub(crate) fn get_ext(&self) -> &'static str {
         if *self == Compression::Zstd { "zstd" } else { "zip" }
     }
 }
 
+impl ArchiveFormat {
+    /// Extension of the backup files written in this format with the `compression`
+    pub(crate) fn get_ext(&self, compression: Compression) -> &'static str {
+        match (self, compression) {
+            (ArchiveFormat::Zip, _) => compression.get_ext(),
+            (ArchiveFormat::Tar, Compression::Stored) => "tar",
+            (ArchiveFormat::Tar, Compression::Zip) => "tar.gz",
+            (ArchiveFormat::Tar, Compression::Zstd) => "tar.zst",
+            (ArchiveFormat::Jsonl, Compression::Stored) => "jsonl",
+            (ArchiveFormat::Jsonl, Compression::Zip) => "jsonl.gz",
+            (ArchiveFormat::Jsonl, Compression::Zstd) => "jsonl.zst",
+            (ArchiveFormat::Csv, Compression::Stored) => "csv",
+            (ArchiveFormat::Csv, Compression::Zip) => "csv.gz",
+            (ArchiveFormat::Csv, Compression::Zstd) => "csv.zst",
+            (ArchiveFormat::Parquet, _) => "parquet",
+        }
+    }
+
+    /// Extension for searching the backup files of this format with any compression
+    pub(crate) fn get_search_ext(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar*",
+            ArchiveFormat::Jsonl => "jsonl*",
+            ArchiveFormat::Csv => "csv*",
+            ArchiveFormat::Parquet => "parquet",
+        }
+    }
+
+    /// Tells if the format can be written to stdout and read from stdin as a single stream
+    pub(crate) fn is_streamable(&self) -> bool {
+        matches!(self, ArchiveFormat::Tar | ArchiveFormat::Jsonl | ArchiveFormat::Csv)
+    }
+
+    /// Detects the format of the backup file by its extension, after the ones of unfinished or
+    /// compressed files
+    pub(crate) fn of_file(path: &Path) -> Self {
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let name = name.strip_suffix(PARTIAL_EXT).unwrap_or(name);
+        let name = [".gz", ".zst"].iter().find_map(|ext| name.strip_suffix(ext)).unwrap_or(name);
+        if name.ends_with(".jsonl") {
+            ArchiveFormat::Jsonl
+        } else if name.ends_with(".tar") {
+            ArchiveFormat::Tar
+        } else if name.ends_with(".csv") {
+            ArchiveFormat::Csv
+        } else if name.ends_with(".parquet") {
+            ArchiveFormat::Parquet
+        } else {
+            ArchiveFormat::Zip
+        }
+    }
+}
+
 // endregion
+
+#[cfg(test)]
+mod tests {
+    use super::ArchiveFormat;
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+
+    #[test]
+    fn check_archive_format_of_file() {
+        let formats = [
+            ("demo_seq_000000001.zip", ArchiveFormat::Zip),
+            ("demo_seq_000000001.tar.zst", ArchiveFormat::Tar),
+            ("demo_seq_000000001.jsonl.gz.partial", ArchiveFormat::Jsonl),
+            ("demo_seq_000000001.csv", ArchiveFormat::Csv),
+            ("demo_seq_000000001.parquet.partial", ArchiveFormat::Parquet),
+            ("demo.csv.tar.gz", ArchiveFormat::Tar),
+            ("demo.tar.backup.zip", ArchiveFormat::Zip),
+        ];
+        for (name, expected) in formats {
+            assert_eq!(ArchiveFormat::of_file(Path::new(name)), expected, "{}", name);
+        }
+    }
+}
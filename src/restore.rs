@@ -1,16 +1,21 @@
 use super::{
-    args::{ParallelArgs, Restore},
+    args::Restore,
     bars::*,
     connection::SolrClient,
     fails::*,
     helpers::*,
     ingest::*,
+    report::{READER, RunStats, ThreadTally, WRITER, write_report},
+    save::count_docs,
     state::*,
+    storage::{DocumentSource, open_source},
+    verify::{DocSampler, RestoredKeys},
 };
+use chrono::Local;
 use crossbeam_channel::{Receiver, Sender, bounded};
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use std::sync::{
-    Arc,
+    Arc, Mutex,
     atomic::{AtomicBool, AtomicU64, Ordering},
 };
 use std::thread;
@@ -19,23 +24,48 @@
 pub(crate) fn restore_main(params: &Restore) -> BoxedError {
     debug!("# RESTORE {:?}", params);
 
-    let found = params.find_archives()?;
+    let started = Local::now();
+    let stats = RunStats::default();
+    // Classified once for the exit code in the report and the one of the process
+    let result = restore_with_stats(params, &stats).map_err(Error::from);
+    if let Some(path) = &params.transfer.report {
+        match params.options.query_num_docs("*:*") {
+            Ok(num_found) => {
+                let _ = stats.num_found.set(num_found);
+            }
+            Err(cause) => warn!("Error counting the documents in the core: {}", cause),
+        }
+        let arguments = params.describe();
+        if let Err(cause) = write_report(path, "restore", arguments, started, stats, &result) {
+            warn!("Error writing the report into {:?}: {}", path, cause);
+        }
+    }
+    Ok(result?)
+}
+
+fn restore_with_stats(params: &Restore, stats: &RunStats) -> BoxedError {
+    let source = open_source(params)?;
+    let found = source.find_archives()?;
 
     if found.is_empty() {
-        throw(format!(
+        fail_with(Error::Archive(format!(
             "Found no archives to restore from: {}\n note: try to specify the option --pattern \
              with the source core name",
             params.get_pattern()
-        ))?;
+        )))?;
     }
 
     let core = params.options.core.clone();
-    info!(
-        "Found {} zip archives in {:?} for updating into core {:?}",
-        found.len(),
-        params.transfer.dir,
-        core
-    );
+    if is_stdio(&params.transfer.dir) {
+        info!("Reading the backup from stdin for updating into core {:?}", core);
+    } else {
+        info!(
+            "Found {} backup files in {:?} for updating into core {:?}",
+            found.len(),
+            params.transfer.dir,
+            core
+        );
+    }
 
     if params.options.is_quiet() {
         wait_with_progress(
@@ -47,7 +77,7 @@ pub(crate) fn restore_main(params: &Restore) -> BoxedError {
 
     let started = Instant::now();
 
-    let updated = unzip_archives_and_send(params, &found)?;
+    let (updated, mut tallies) = unzip_archives_and_send(params, source.as_ref(), &found, stats)?;
 
     info!("Updated {} batches in solr core {} in {:?}.", updated, core, started.elapsed());
 
@@ -56,17 +86,102 @@ pub(crate) fn restore_main(params: &Restore) -> BoxedError {
     if updated > 0 {
         wait_with_progress(params.transfer.delay_after, "Restoring documents...");
     }
+    let unreadable = tallies.unreadable.lock().unwrap().len().to_u64();
+    stats.skipped_entries.store(unreadable, Ordering::SeqCst);
+    stats.skipped_docs.store(tallies.skipped.load(Ordering::SeqCst), Ordering::SeqCst);
+    // Documents skipped by --only-if-newer were already in the core, so they count as restored
+    let (restored, repeated) = tallies.keys.count_distinct();
+    let samples = std::mem::take(&mut tallies.samples).into_docs();
+    tallies.check_failures(params.transfer.max_errors)?;
+    if params.verify {
+        if repeated > 0 {
+            info!("Found {} documents restored again with the same uniqueKey.", repeated);
+        }
+        params.verify_restored(restored, samples)?;
+    }
     Ok(())
 }
 
 // region Processing
 
-fn unzip_archives_and_send(params: &Restore, found: &[PathBuf]) -> BoxedResult<u64> {
-    let doc_count = estimate_batch_count(found)?;
+/// Counters of the documents handled by all reader and writer threads
+#[derive(Debug, Default)]
+pub(crate) struct Tallies {
+    pub errors: AtomicU64,
+    pub skipped: AtomicU64,
+    pub overwritten: AtomicU64,
+    /// Archives and entries that could not be read
+    pub unreadable: Mutex<Vec<String>>,
+    /// Documents sent to the core
+    pub sent: AtomicU64,
+    /// Documents sent that are compared with the core by `--verify`
+    pub samples: DocSampler,
+    /// UniqueKeys of the documents sent or skipped, counted by `--verify`
+    pub keys: RestoredKeys,
+    /// First failure, whose kind gives the exit code when going over `--max-errors`
+    first: Mutex<Option<Error>>,
+}
+
+impl Tallies {
+    /// Counts the failure, keeping the first one, and returns how many happened before it
+    fn add_failure(&self, cause: Error) -> u64 {
+        let current = self.errors.fetch_add(1, Ordering::SeqCst);
+        let mut first = self.first.lock().unwrap();
+        if first.is_none() {
+            *first = Some(cause);
+        }
+        current
+    }
+
+    /// Records a unreadable archive or entry and tells if it exceeded `max_errors`
+    fn add_unreadable(&self, failure: String, max_errors: u64) -> bool {
+        self.unreadable.lock().unwrap().push(failure.clone());
+        self.add_failure(Error::Archive(failure)) > max_errors
+    }
+
+    /// Reports the archives and updates skipped, failing when there were any
+    fn check_failures(self, max_errors: u64) -> BoxedError {
+        let unreadable = self.unreadable.into_inner().unwrap();
+        for failure in &unreadable {
+            error!("Skipped {}", failure);
+        }
+        let (errors, first) = (self.errors.into_inner(), self.first.into_inner().unwrap());
+        if errors > max_errors {
+            error!(
+                "Stopped after {} unreadable archives or failed updates, more than --max-errors {}.",
+                errors, max_errors
+            );
+            // Exits with the code of the kind of failure, as when Solr is down
+            fail_with(first.unwrap_or(Error::Failed("Failed to restore the archives".into())))
+        } else if errors > 0 {
+            let failed = errors - unreadable.len().to_u64();
+            fail_with(Error::PartialSuccess(format!(
+                "Skipped {} unreadable archives or entries and {} failed updates while restoring.",
+                unreadable.len(),
+                failed
+            )))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn unzip_archives_and_send(
+    params: &Restore, source: &dyn DocumentSource, found: &[PathBuf], stats: &RunStats,
+) -> BoxedResult<(u64, Tallies)> {
+    let totals = source.count_totals(found);
     let mut updated = 0;
+    let sampled = if params.verify { params.verify_samples } else { 0 };
+    let tallies = Tallies { samples: DocSampler::new(sampled), ..Default::default() };
 
     let core = params.options.core.clone();
-    info!("Estimated {} batches for indexing in solr core {}", doc_count, core);
+    match totals.docs {
+        Some(docs) => info!(
+            "Found {} batches with {} documents for indexing in solr core {}",
+            totals.batches, docs, core
+        ),
+        None => info!("Found {} batches for indexing in solr core {}", totals.batches, core),
+    }
 
     thread::scope(|pool| {
         let transfer = &params.transfer;
@@ -75,7 +190,7 @@ fn unzip_archives_and_send(params: &Restore, found: &[PathBuf]) -> BoxedResult<u
 
         let (generator, sequence) = bounded::<&Path>(readers_channel.to_usize());
         let (sender, receiver) = bounded::<Docs>(writers_channel.to_usize());
-        let (progress, reporter) = bounded::<u64>(transfer.writers.to_usize());
+        let (progress, reporter) = bounded::<(u64, u64)>(transfer.writers.to_usize());
 
         let scan_handle = thread::Builder::new()
             .name("Scanner".to_string())
@@ -84,18 +199,28 @@ fn unzip_archives_and_send(params: &Restore, found: &[PathBuf]) -> BoxedResult<u
             })
             .unwrap();
 
-        let reader_handles = start_archive_readers(pool, transfer, sequence, sender);
+        let reader_handles =
+            start_archive_readers(pool, params, source, sequence, sender, &tallies, stats);
 
         let update_hadler_url = params.get_update_url();
         debug!("Solr Update Handler: {}", update_hadler_url);
 
-        let writer_handles =
-            start_archive_writers(pool, transfer, receiver, progress, update_hadler_url);
+        let writer_handles = start_archive_writers(
+            pool,
+            params,
+            receiver,
+            progress,
+            update_hadler_url,
+            &tallies,
+            stats,
+        );
 
         let bar_handle = thread::Builder::new()
             .name("Generator".to_string())
             .spawn_scoped(pool, || {
-                updated = foreach_progress(reporter, doc_count, params.options.is_quiet());
+                let quiet = params.options.is_quiet();
+                let hooks = &params.options.hooks;
+                updated = foreach_progress(reporter, totals.batches, totals.docs, quiet, hooks);
             })
             .unwrap();
 
@@ -112,17 +237,26 @@ fn unzip_archives_and_send(params: &Restore, found: &[PathBuf]) -> BoxedResult<u
     if updated > 0 && !params.no_final_commit {
         crate::commit::commit_main(&params.options.to_command())?;
     }
-
-    finish_progress(updated)
+    if let Some(field) = &params.only_if_newer {
+        info!(
+            "Skipped {} documents with a greater '{}' in the core and overwritten {} older ones.",
+            tallies.skipped.load(Ordering::SeqCst),
+            field,
+            tallies.overwritten.load(Ordering::SeqCst)
+        );
+    }
+    let updated = finish_progress(params, updated)?;
+    Ok((updated, tallies))
 }
 
 fn start_archive_readers<'scope>(
-    pool: &'scope thread::Scope<'scope, '_>, transfer: &ParallelArgs,
-    sequence: Receiver<&'scope Path>, sender: Sender<Docs>,
+    pool: &'scope thread::Scope<'scope, '_>, params: &'scope Restore,
+    source: &'scope dyn DocumentSource, sequence: Receiver<&'scope Path>, sender: Sender<Docs>,
+    tallies: &'scope Tallies, stats: &'scope RunStats,
 ) -> Vec<thread::ScopedJoinHandle<'scope, ()>> {
     let mut handles = vec![];
 
-    for ir in 0..transfer.readers {
+    for ir in 0..params.transfer.readers {
         let producer = sender.clone();
         let iterator = sequence.clone();
 
@@ -133,7 +267,9 @@ fn start_archive_readers<'scope>(
             .name(thread_name)
             .spawn_scoped(pool, move || {
                 debug!("Started reader #{}", reader);
-                start_reading_archive(reader, iterator, producer);
+                let tally =
+                    start_reading_archive(reader, params, source, iterator, producer, tallies);
+                stats.add_thread(tally);
                 debug!("Finished reader #{}", reader);
             })
             .unwrap();
@@ -145,10 +281,11 @@ fn start_archive_readers<'scope>(
 }
 
 fn start_archive_writers<'scope>(
-    pool: &'scope thread::Scope<'scope, '_>, transfer: &ParallelArgs, receiver: Receiver<Docs>,
-    progress: Sender<u64>, update_hadler_url: String,
+    pool: &'scope thread::Scope<'scope, '_>, params: &'scope Restore, receiver: Receiver<Docs>,
+    progress: Sender<(u64, u64)>, update_hadler_url: String, tallies: &'scope Tallies,
+    stats: &'scope RunStats,
 ) -> Vec<thread::ScopedJoinHandle<'scope, ()>> {
-    let update_errors = Arc::new(AtomicU64::new(0));
+    let transfer = &params.transfer;
     let merr = transfer.max_errors;
     let delay = transfer.delay_per_request;
 
@@ -157,7 +294,6 @@ fn start_archive_writers<'scope>(
     for iw in 0..transfer.writers {
         let consumer = receiver.clone();
         let updater = progress.clone();
-        let arcerr = Arc::clone(&update_errors);
         let url = update_hadler_url.clone();
 
         let writer = iw;
@@ -167,7 +303,10 @@ fn start_archive_writers<'scope>(
             .name(thread_name)
             .spawn_scoped(pool, move || {
                 debug!("Started writer #{}", writer);
-                start_indexing_docs(writer, &url, consumer, updater, &arcerr, merr, delay);
+                let tally = start_indexing_docs(
+                    writer, params, &url, consumer, updater, tallies, merr, delay,
+                );
+                stats.add_thread(tally);
                 debug!("Finished writer #{}", writer);
             })
             .unwrap();
@@ -178,25 +317,10 @@ fn start_archive_writers<'scope>(
     handles
 }
 
-fn finish_progress(updated: u64) -> BoxedResult<u64> {
-    let ctrl_c = monitor_term_sinal();
+fn finish_progress(params: &Restore, updated: u64) -> BoxedResult<u64> {
+    let ctrl_c = params.options.hooks.aborting();
 
-    if ctrl_c.aborted() { raise("# Execution aborted by user!") } else { Ok(updated) }
-}
-
-fn estimate_batch_count(found: &[PathBuf]) -> BoxedResult<u64> {
-    // Estimate number of json files inside all zip files
-    let zip_count = found.len();
-
-    let first = found.first().unwrap();
-    let file_count = ArchiveReader::get_archive_file_count(first);
-    match file_count {
-        None => throw(format!("Error opening archive: {:?}", first))?,
-        Some(doc_count) => {
-            let doc_total = doc_count * zip_count;
-            Ok(doc_total.to_u64())
-        }
-    }
+    if ctrl_c.aborted() { fail_with(Error::Cancelled) } else { Ok(updated) }
 }
 
 fn pre_post_processing(params: &Restore, enable: bool) -> BoxedResult<()> {
@@ -231,8 +355,12 @@ fn start_listing_archives<'a>(found: &'a [PathBuf], generator: Sender<&'a Path>)
     drop(generator);
 }
 
-fn start_reading_archive(reader: u64, iterator: Receiver<&Path>, producer: Sender<Docs>) {
-    let ctrl_c = monitor_term_sinal();
+fn start_reading_archive(
+    reader: u64, params: &Restore, source: &dyn DocumentSource, iterator: Receiver<&Path>,
+    producer: Sender<Docs>, tallies: &Tallies,
+) -> ThreadTally {
+    let ctrl_c = params.options.hooks.aborting();
+    let mut tally = ThreadTally::named(READER, reader);
 
     loop {
         let received = iterator.recv();
@@ -240,26 +368,55 @@ fn start_reading_archive(reader: u64, iterator: Receiver<&Path>, producer: Sende
             break;
         }
         let archive_path = received.unwrap();
-        let failed = handle_reading_archive(reader, &producer, archive_path, &ctrl_c);
+        let failed = handle_reading_archive(
+            reader,
+            params,
+            source,
+            &producer,
+            archive_path,
+            tallies,
+            &ctrl_c,
+            &mut tally,
+        );
         if failed || ctrl_c.aborted() {
             break;
         }
     }
     drop(producer);
+    tally
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handle_reading_archive(
-    reader: u64, producer: &Sender<Docs>, archive_path: &Path, ctrl_c: &Arc<AtomicBool>,
+    reader: u64, params: &Restore, source: &dyn DocumentSource, producer: &Sender<Docs>,
+    archive_path: &Path, tallies: &Tallies, ctrl_c: &Arc<AtomicBool>, tally: &mut ThreadTally,
 ) -> bool {
+    let max_errors = params.transfer.max_errors;
     let zip_name: String = get_filename(archive_path).unwrap();
-    trace!("Reading zip archive: {}", zip_name);
-    let can_open = ArchiveReader::create_reader(archive_path);
+    trace!("Reading backup file: {}", zip_name);
+    let can_open = source.read_archive(archive_path);
     match can_open {
         Ok(archive_reader) => {
-            for (entry_name, entry_contents) in archive_reader {
+            for read in archive_reader {
+                let (entry_name, entry_contents, count) = match read {
+                    Ok(contents) => contents,
+                    Err(cause) => {
+                        error!("Error in thread #{} reading zip {}: {}", reader, zip_name, cause);
+                        let failure = format!("{} in archive {}", cause, zip_name);
+                        tally.errors += 1;
+                        if tallies.add_unreadable(failure, max_errors) {
+                            return true;
+                        } else if params.skip_corrupted {
+                            continue;
+                        } else {
+                            return false;
+                        }
+                    }
+                };
                 trace!("  Uncompressing json: '{}' from '{}'", entry_name, zip_name);
 
-                let docs = Docs::new(zip_name.clone(), entry_name, entry_contents);
+                tally.add_batch(count, entry_contents.len());
+                let docs = Docs::new(zip_name.clone(), entry_name, entry_contents, count);
                 let status = producer.send(docs);
                 if status.is_err() || ctrl_c.aborted() {
                     return true;
@@ -269,26 +426,39 @@ fn handle_reading_archive(
         }
         Err(cause) => {
             error!("Error in thread #{} while reading docs in zip: {}", reader, cause);
-            true
+            let failure = format!("archive {}: {}", zip_name, cause);
+            tally.errors += 1;
+            tallies.add_unreadable(failure, max_errors)
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn start_indexing_docs(
-    writer: u64, url: &str, consumer: Receiver<Docs>, progress: Sender<u64>,
-    error_count: &Arc<AtomicU64>, max_errors: u64, delay: u64,
-) {
-    let ctrl_c = monitor_term_sinal();
+    writer: u64, params: &Restore, url: &str, consumer: Receiver<Docs>,
+    progress: Sender<(u64, u64)>, tallies: &Tallies, max_errors: u64, delay: u64,
+) -> ThreadTally {
+    let ctrl_c = params.options.hooks.aborting();
 
     let mut client = SolrClient::new();
+    let mut tally = ThreadTally::named(WRITER, writer);
     loop {
         let received = consumer.recv();
         if received.is_err() || ctrl_c.aborted() {
             break;
         }
         let docs = received.unwrap();
-        let failed =
-            send_to_solr(docs, writer, url, &mut client, &progress, error_count, max_errors);
+        let failed = send_to_solr(
+            docs,
+            writer,
+            params,
+            url,
+            &mut client,
+            &progress,
+            tallies,
+            max_errors,
+            &mut tally,
+        );
         if failed || ctrl_c.aborted() {
             break;
         } else if delay > 0 {
@@ -296,22 +466,52 @@ fn start_indexing_docs(
         }
     }
     drop(consumer);
+    tally.retries = client.get_retries();
+    tally
 }
 
+#[allow(clippy::too_many_arguments)]
 fn send_to_solr(
-    docs: Docs, writer: u64, url: &str, client: &mut SolrClient, progress: &Sender<u64>,
-    error_count: &Arc<AtomicU64>, max_errors: u64,
+    docs: Docs, writer: u64, params: &Restore, url: &str, client: &mut SolrClient,
+    progress: &Sender<(u64, u64)>, tallies: &Tallies, max_errors: u64, tally: &mut ThreadTally,
 ) -> bool {
-    let failed = client.post_as_json(url, docs.json.as_str());
+    let (prepared, failed) = match params.prepare_docs(client, &docs.json) {
+        Ok(batch) => {
+            let posted = match &batch.json {
+                None => Ok(()),
+                Some(json) => client.post_as_json(url, json).map(|_| ()).map_err(Error::from),
+            };
+            (Some(batch), posted)
+        }
+        Err(cause) => (None, Err(Error::from(cause))),
+    };
     if let Err(cause) = failed {
-        let current = error_count.fetch_add(1, Ordering::SeqCst);
+        tally.errors += 1;
+        let message = cause.to_string();
+        let current = tallies.add_failure(cause);
         error!(
             "Error #{}/{} in thread #{} when indexing solr core:\n{}{:?}",
-            current, max_errors, writer, cause, docs
+            current, max_errors, writer, message, docs
         );
         current > max_errors
     } else {
-        let status = progress.send(1);
+        if let Some(batch) = &prepared {
+            let sent = batch.json.as_deref().unwrap_or_default();
+            let sent_docs = count_docs(sent);
+            tally.add_batch(sent_docs, sent.len());
+            tallies.sent.fetch_add(sent_docs, Ordering::SeqCst);
+            if params.verify {
+                tallies.keys.add(&params.unique_key, &docs.json);
+            }
+            if tallies.samples.is_sampling()
+                && let Ok(docs) = serde_json::from_str(sent)
+            {
+                tallies.samples.offer(&params.unique_key, docs);
+            }
+            tallies.skipped.fetch_add(batch.skipped, Ordering::SeqCst);
+            tallies.overwritten.fetch_add(batch.overwritten, Ordering::SeqCst);
+        }
+        let status = progress.send((1, docs.count));
         status.is_err()
     }
 }
@@ -321,11 +521,24 @@ fn send_to_solr(
 #[cfg(test)]
 mod tests {
     use crate::{
-        args::{Cli, Commands, Restore},
+        args::{Cli, Commands, CsvArgs, Restore, SortOrder},
         fails::{BoxedResult, raise},
+        helpers::{IntegerHelpers, shared::TempDir},
+        ingest::{
+            ArchiveReader, ArchiveTotals, decode_stream, find_archives, read_backup_file,
+            read_backup_stream,
+        },
+        models::{ArchiveFormat, Compression, Documents, Step},
+        save::{Archiver, JsonLinesWriter, TarArchiver},
+        storage::{DocumentSink, open_source},
     };
     use log::debug;
     use pretty_assertions::assert_eq;
+    use std::{
+        fs,
+        io::{Cursor, Write},
+    };
+    use zip::{ZipWriter, write::SimpleFileOptions};
 
     impl Commands {
         pub(crate) fn put(&self) -> BoxedResult<&Restore> {
@@ -344,12 +557,241 @@ fn check_restore_pattern() {
         assert_eq!(wilcard.ends_with(".zip"), true);
     }
 
+    #[test]
+    fn check_reader_continues_after_corrupted_entry() {
+        let dir = TempDir::new("corrupt");
+
+        let mut archiver = Archiver::write_on(&dir, "demo_seq_{}.zip", Compression::Stored, 10);
+        for (curr, batch) in ["[{\"id\":\"first\"}]", "[{\"id\":\"second\"}]"].iter().enumerate() {
+            let step = Step { curr: curr.to_u64(), expected: 0, url: String::new() };
+            archiver.write_documents(&Documents { step, docs: batch.to_string() }).unwrap();
+        }
+        archiver.close_archive().unwrap();
+
+        let archive = dir.join("demo_seq_000000001.zip");
+        let mut bytes = fs::read(&archive).unwrap();
+        let pos = bytes.windows(5).position(|window| window == b"first").unwrap();
+        bytes[pos] = b'F';
+        fs::write(&archive, bytes).unwrap();
+
+        let reader = ArchiveReader::create_reader(&archive).unwrap();
+        let read: Vec<_> = reader.map(|item| item.map(|(name, _)| name).ok()).collect();
+
+        assert_eq!(read, vec![None, Some("docs_at_000000002.json".to_string())]);
+    }
+
+    #[test]
+    fn check_encrypted_archives_need_the_key() {
+        let dir = TempDir::new("encrypt");
+
+        let key = Some("s3cr3t".to_string());
+        let mut archiver =
+            Archiver::write_on(&dir, "demo_seq_{}.zip", Compression::Zip, 10).encrypted_with(key);
+        let step = Step { curr: 0, expected: 0, url: String::new() };
+        let batch = "[{\"id\":\"pii\"},{\"id\":\"ssn\"}]";
+        archiver.write_documents(&Documents { step, docs: batch.to_string() }).unwrap();
+        archiver.close_archive().unwrap();
+
+        let archive = dir.join("demo_seq_000000001.zip");
+        let contents = fs::read(&archive).unwrap();
+        let read_with = |key: Option<&str>| {
+            let entries = read_backup_file(&archive, &CsvArgs::default(), &[], key).unwrap();
+            entries.map(|item| item.ok().map(|(_, json, count)| (json, count))).collect::<Vec<_>>()
+        };
+        let decrypted = read_with(Some("s3cr3t"));
+        let without_key = read_with(None);
+        let wrong_key = read_with(Some("guess"));
+        let totals = ArchiveTotals::count(std::slice::from_ref(&archive), Some("s3cr3t"));
+
+        assert!(!contents.windows(3).any(|window| window == b"pii"));
+        assert_eq!(decrypted, vec![Some((batch.to_string(), 2))]);
+        assert_eq!(without_key, vec![None]);
+        assert_eq!(wrong_key, vec![None]);
+        assert_eq!(totals, ArchiveTotals { batches: 1, docs: Some(2) });
+    }
+
+    #[test]
+    fn check_totals_of_archives() {
+        let dir = TempDir::new("totals");
+
+        let mut archiver = Archiver::write_on(&dir, "demo_seq_{}.zip", Compression::Zip, 2);
+        let batches = ["[{\"id\":1},{\"id\":2}]", "[{\"id\":3}]", "[{\"id\":4},{\"id\":5}]"];
+        for (curr, batch) in batches.iter().enumerate() {
+            let step = Step { curr: curr.to_u64(), expected: 0, url: String::new() };
+            archiver.write_documents(&Documents { step, docs: batch.to_string() }).unwrap();
+        }
+        archiver.close_archive().unwrap();
+        let found = find_archives(&format!("{}/demo*.zip", dir.display()), SortOrder::Asc);
+        let with_manifests = ArchiveTotals::count(&found.unwrap(), None);
+
+        let legacy = dir.join("legacy.zip");
+        let mut zip = ZipWriter::new(fs::File::create(&legacy).unwrap());
+        zip.start_file("docs_at_000000001.json", SimpleFileOptions::default()).unwrap();
+        zip.write_all(b"[{\"id\":6}]").unwrap();
+        zip.finish().unwrap();
+        let without_manifest = ArchiveTotals::count(&[legacy], None);
+
+        assert_eq!(with_manifests, ArchiveTotals { batches: 3, docs: Some(5) });
+        assert_eq!(without_manifest, ArchiveTotals { batches: 1, docs: None });
+    }
+
+    #[test]
+    fn check_json_lines_round_trip() {
+        let dir = TempDir::new("jsonl");
+
+        let batches = ["[{\"id\":1,\"tx\":\"a\\nb\"},{\"id\":2}]", "[]", "[{\"id\":3}]"];
+        for (pattern, compression) in [
+            ("demo_seq_{}.jsonl", Compression::Stored),
+            ("demo_seq_{}.jsonl.gz", Compression::Zip),
+            ("demo_seq_{}.jsonl.zst", Compression::Zstd),
+        ] {
+            let mut writer = JsonLinesWriter::write_on(&dir, pattern, compression, 10);
+            for (curr, batch) in batches.iter().enumerate() {
+                let step = Step { curr: curr.to_u64(), expected: 0, url: String::new() };
+                writer.write_documents(&Documents { step, docs: batch.to_string() }).unwrap();
+            }
+            writer.close_archive().unwrap();
+        }
+        let found = find_archives(&format!("{}/demo*.jsonl*", dir.display()), SortOrder::Asc);
+        let found = found.unwrap();
+        let totals = ArchiveTotals::count(&found, None);
+        let read: Vec<_> = found
+            .iter()
+            .flat_map(|file| read_backup_file(file, &CsvArgs::default(), &[], None).unwrap())
+            .map(|item| item.unwrap())
+            .collect();
+
+        let expected = (
+            "lines_1_to_3".to_string(),
+            r#"[{"id":1,"tx":"a\nb"},{"id":2},{"id":3}]"#.to_string(),
+            3,
+        );
+        assert_eq!(found.len(), 3);
+        assert_eq!(totals, ArchiveTotals { batches: 0, docs: None });
+        assert_eq!(read, vec![expected.clone(), expected.clone(), expected]);
+    }
+
+    #[test]
+    fn check_dropped_files_are_not_published() {
+        let dir = TempDir::new("dropped");
+
+        let step = Step { curr: 0, expected: 0, url: String::new() };
+        let docs = Documents { step, docs: "[{\"id\":1}]".to_string() };
+        let mut writer =
+            JsonLinesWriter::write_on(&dir, "demo_seq_{}.jsonl", Compression::Stored, 10);
+        writer.write_documents(&docs).unwrap();
+        // Simulates a writer dropped after a failure or during an unwind
+        drop(writer);
+        let mut names: Vec<String> = fs::read_dir(&*dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+
+        assert_eq!(names, vec!["demo_seq_000000001.jsonl.partial"]);
+    }
+
+    #[test]
+    fn check_tar_archives_readable_when_unfinished() {
+        let dir = TempDir::new("tar");
+
+        let batches = ["[{\"id\":1},{\"id\":2}]", "[{\"id\":3}]"];
+        for (pattern, finished) in [("done_seq_{}.tar.zst", true), ("crash_seq_{}.tar.gz", false)] {
+            let compression = if finished { Compression::Zstd } else { Compression::Zip };
+            let mut archiver = TarArchiver::write_on(&dir, pattern, compression, 10);
+            for (curr, batch) in batches.iter().enumerate() {
+                let step = Step { curr: curr.to_u64(), expected: 0, url: String::new() };
+                archiver.write_documents(&Documents { step, docs: batch.to_string() }).unwrap();
+            }
+            if finished {
+                archiver.close_archive().unwrap();
+            } else {
+                // Simulates a backup killed before finishing the archive
+                std::mem::forget(archiver);
+            }
+        }
+        let done = dir.join("done_seq_000000001.tar.zst");
+        // Unfinished archives keep the temporary name and are not listed for restoring
+        let crash = dir.join("crash_seq_000000001.tar.gz.partial");
+        let found = find_archives(&format!("{}/*.tar*", dir.display()), SortOrder::Asc);
+        let totals = ArchiveTotals::count(std::slice::from_ref(&done), None);
+        let read_done: Vec<_> = read_backup_file(&done, &CsvArgs::default(), &[], None)
+            .unwrap()
+            .map(|item| item.map(|(name, _, count)| (name, count)).ok())
+            .collect();
+        let read_crash: Vec<_> = read_backup_file(&crash, &CsvArgs::default(), &[], None)
+            .unwrap()
+            .map(|item| item.map(|(name, _, count)| (name, count)).ok())
+            .collect();
+
+        let entries = vec![
+            Some(("docs_at_000000001.json".to_string(), 2)),
+            Some(("docs_at_000000002.json".to_string(), 1)),
+        ];
+        assert_eq!(found.unwrap(), vec![done]);
+        assert_eq!(totals, ArchiveTotals { batches: 2, docs: Some(3) });
+        assert_eq!(read_done, entries);
+        assert_eq!(read_crash[..2], entries);
+        assert_eq!(read_crash[2..], [None]);
+    }
+
+    #[test]
+    fn check_backup_streams_detect_compression() {
+        let dir = TempDir::new("stdio");
+
+        let step = Step { curr: 0, expected: 0, url: String::new() };
+        let docs = Documents { step, docs: "[{\"id\":1},{\"id\":2}]".to_string() };
+        let mut archiver =
+            TarArchiver::write_on(&dir, "demo_seq_{}.tar.zst", Compression::Zstd, 10);
+        archiver.write_documents(&docs).unwrap();
+        archiver.close_archive().unwrap();
+        for (pattern, compression) in
+            [("demo_seq_{}.jsonl", Compression::Stored), ("demo_seq_{}.jsonl.gz", Compression::Zip)]
+        {
+            let mut writer = JsonLinesWriter::write_on(&dir, pattern, compression, 10);
+            writer.write_documents(&docs).unwrap();
+            writer.close_archive().unwrap();
+        }
+        let read_stream = |file: &str, format: ArchiveFormat| {
+            let contents = fs::read(dir.join(file)).unwrap();
+            let source = decode_stream(Cursor::new(contents)).unwrap();
+            let entries = read_backup_stream(source, format, &CsvArgs::default(), &[]).unwrap();
+            entries.map(|item| item.unwrap().2).collect::<Vec<_>>()
+        };
+        let tar = read_stream("demo_seq_000000001.tar.zst", ArchiveFormat::Tar);
+        let plain = read_stream("demo_seq_000000001.jsonl", ArchiveFormat::Jsonl);
+        let gzip = read_stream("demo_seq_000000001.jsonl.gz", ArchiveFormat::Jsonl);
+
+        assert_eq!(tar, vec![2]);
+        assert_eq!(plain, vec![2]);
+        assert_eq!(gzip, vec![2]);
+    }
+
+    #[test]
+    fn check_tar_streams_with_long_names() {
+        let long = format!("{}/demo_seq_000000001.json", "nested".repeat(20));
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, json) in [(long.as_str(), "[{\"id\":1},{\"id\":2}]"), ("short.json", "[{}]")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(json.len().to_u64());
+            header.set_cksum();
+            builder.append_data(&mut header, name, json.as_bytes()).unwrap();
+        }
+        let contents = builder.into_inner().unwrap();
+
+        let source = decode_stream(Cursor::new(contents)).unwrap();
+        let entries = read_backup_stream(source, ArchiveFormat::Tar, &CsvArgs::default(), &[]);
+        let read = entries.unwrap().map(|item| item.unwrap()).collect::<Vec<_>>();
+        let names = read.iter().map(|(name, _, count)| (name.as_str(), *count)).collect::<Vec<_>>();
+        assert_eq!(names, vec![(long.as_str(), 2), ("short.json", 1)]);
+    }
+
     #[test]
     fn check_restore_iterator() {
         let parsed = Cli::mockup_args_restore();
         let puts = parsed.put().unwrap();
 
-        for zip in puts.find_archives().unwrap() {
+        for zip in open_source(puts).unwrap().find_archives().unwrap() {
             debug!("{:?}", zip);
             let path = zip.to_str().unwrap();
             assert_eq!(path.ends_with(".zip"), true);
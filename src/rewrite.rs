@@ -0,0 +1,242 @@
+use super::{
+    args::{AtomicOperation, MissingDocs, Restore},
+    connection::SolrClient,
+    fails::BoxedResult,
+    fetch::json_to_key,
+};
+use chrono::DateTime;
+use serde_json::{Map, Value, json};
+use std::{borrow::Cow, cmp::Ordering};
+
+// region Document rewriting
+
+/// Documents of a archive entry left for sending to Solr
+#[derive(Debug, Default)]
+pub(crate) struct Batch<'a> {
+    /// The documents to send or `None` when all were skipped
+    pub json: Option<Cow<'a, str>>,
+    /// Count of documents skipped because the copy in the target core is newer
+    pub skipped: u64,
+    /// Count of documents replacing an older copy in the target core
+    pub overwritten: u64,
+}
+
+/// Fields maintained by Solr itself that are rebuilt from the hierarchy of nested documents
+const NESTED_INTERNAL_FIELDS: [&str; 4] = ["_version_", "_root_", "_nest_path_", "_nest_parent_"];
+
+const VERSION_FIELD: &str = "_version_";
+
+impl Restore {
+    /// Filters and changes the json documents read from the archives before sending them to Solr
+    pub(crate) fn prepare_docs<'a>(
+        &self, client: &mut SolrClient, json: &'a str,
+    ) -> BoxedResult<Batch<'a>> {
+        let mut batch = match &self.only_if_newer {
+            None => Batch { json: Some(Cow::Borrowed(json)), ..Default::default() },
+            Some(field) => self.skip_older_docs(client, field, json)?,
+        };
+        if let Some(docs) = batch.json.take() {
+            batch.json = Some(self.rewrite_docs(docs)?);
+        }
+        Ok(batch)
+    }
+
+    /// Removes the documents whose copy in the target core has a greater value in `field`
+    fn skip_older_docs<'a>(
+        &self, client: &mut SolrClient, field: &str, json: &'a str,
+    ) -> BoxedResult<Batch<'a>> {
+        let docs: Vec<Value> = serde_json::from_str(json)?;
+        let key_field = self.unique_key.as_str();
+        let keys: Vec<String> =
+            docs.iter().filter_map(|doc| doc.get(key_field)).map(json_to_key).collect();
+        if keys.is_empty() {
+            return Ok(Batch { json: Some(Cow::Borrowed(json)), ..Default::default() });
+        }
+        let existing = self.query_target_values(client, field, &keys)?;
+
+        let mut batch = Batch::default();
+        let mut newer = Vec::with_capacity(docs.len());
+        for doc in docs {
+            let key = doc.get(key_field).map(json_to_key);
+            let current = key.and_then(|k| existing.get(&k));
+            match (current, doc.get(field)) {
+                (Some(target), Some(source)) if compare_values(target, source).is_gt() => {
+                    batch.skipped += 1;
+                    continue;
+                }
+                (Some(_), _) => batch.overwritten += 1,
+                _ => {}
+            }
+            newer.push(doc);
+        }
+        if !newer.is_empty() {
+            batch.json = Some(Cow::Owned(Value::Array(newer).to_string()));
+        }
+        Ok(batch)
+    }
+
+    /// Changes the json documents read from the archives before sending them to Solr.
+    /// Without any change configured the documents are returned as they are, without copying them
+    pub(crate) fn rewrite_docs<'a>(&self, json: Cow<'a, str>) -> BoxedResult<Cow<'a, str>> {
+        if !self.nested && self.atomic.is_none() {
+            return Ok(json);
+        }
+        let mut docs: Value = serde_json::from_str(&json)?;
+        if self.nested {
+            strip_nested_internals(&mut docs);
+        }
+        if self.atomic.is_some()
+            && let Value::Array(items) = &mut docs
+        {
+            for item in items.iter_mut() {
+                if let Value::Object(fields) = item {
+                    self.make_atomic_update(fields);
+                }
+            }
+        }
+        Ok(Cow::Owned(docs.to_string()))
+    }
+
+    /// Wraps the values of all fields but the uniqueKey with the operation of the atomic update:
+    /// `{"id":"1","price":9.9}` becomes `{"id":"1","price":{"set":9.9}}`
+    fn make_atomic_update(&self, fields: &mut Map<String, Value>) {
+        let unique_key = self.unique_key.as_str();
+        let source = std::mem::take(fields);
+        for (name, value) in source {
+            if name == VERSION_FIELD {
+                continue;
+            }
+            let wrapped = if name == unique_key {
+                value
+            } else {
+                json!({ self.get_operation(&name): value })
+            };
+            fields.insert(name, wrapped);
+        }
+        if self.atomic_missing == MissingDocs::Skip {
+            // A `_version_` of 1 tells Solr that the document must already exist
+            fields.insert(VERSION_FIELD.to_string(), json!(1));
+        }
+    }
+
+    fn get_operation(&self, field_name: &str) -> &'static str {
+        let custom = self.atomic_fields.iter().find(|af| af.field == field_name);
+        let operation = custom.map(|af| af.operation).or(self.atomic);
+        operation.unwrap_or(AtomicOperation::Set).as_key()
+    }
+}
+
+/// Compares numbers by value and text as timestamps in ISO 8601, using the first value of lists.
+/// Values of distinct types are considered equal.
+pub(crate) fn compare_values(left: &Value, right: &Value) -> Ordering {
+    match (left, right) {
+        (Value::Array(items), _) => {
+            items.first().map_or(Ordering::Equal, |l| compare_values(l, right))
+        }
+        (_, Value::Array(items)) => {
+            items.first().map_or(Ordering::Equal, |r| compare_values(left, r))
+        }
+        (Value::Number(l), Value::Number(r)) => match (l.as_i64(), r.as_i64()) {
+            (Some(li), Some(ri)) => li.cmp(&ri),
+            _ => l.as_f64().partial_cmp(&r.as_f64()).unwrap_or(Ordering::Equal),
+        },
+        (Value::String(l), Value::String(r)) => {
+            // Solr omits zero milliseconds, so the text of timestamps does not sort by time
+            match (DateTime::parse_from_rfc3339(l), DateTime::parse_from_rfc3339(r)) {
+                (Ok(lt), Ok(rt)) => lt.cmp(&rt),
+                _ => l.cmp(r),
+            }
+        }
+        _ => Ordering::Equal,
+    }
+}
+
+/// Removes the internal fields of parents and children, as the `[child]` transformer returns them.
+/// Solr refuses `_version_` values from another core and rebuilds `_root_` from the nesting.
+fn strip_nested_internals(value: &mut Value) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(strip_nested_internals),
+        Value::Object(fields) => {
+            for name in NESTED_INTERNAL_FIELDS {
+                fields.remove(name);
+            }
+            fields.values_mut().for_each(strip_nested_internals);
+        }
+        _ => {}
+    }
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use super::{compare_values, strip_nested_internals};
+    use crate::args::Cli;
+    use pretty_assertions::assert_eq;
+    use serde_json::{Value, json};
+
+    #[test]
+    fn check_strip_nested_internals() {
+        let mut docs: Value = json!([{
+            "id": "1", "_version_": 11, "_root_": "1",
+            "_childDocuments_": [{ "id": "1.1", "_version_": 12, "_root_": "1", "_nest_path_": "/c#0" }],
+            "labelled": { "id": "1.2", "_version_": 13, "_root_": "1" }
+        }]);
+        strip_nested_internals(&mut docs);
+        let expected = json!([{
+            "id": "1",
+            "_childDocuments_": [{ "id": "1.1" }],
+            "labelled": { "id": "1.2" }
+        }]);
+        assert_eq!(docs, expected);
+    }
+
+    #[test]
+    fn check_compare_values() {
+        let older = json!("2024-01-01T00:00:00Z");
+        let newer = json!(["2024-05-01T10:00:00Z"]);
+        assert!(compare_values(&newer, &older).is_gt());
+        assert!(compare_values(&older, &newer).is_lt());
+        let (whole, with_millis) =
+            (json!("2024-01-01T00:00:00Z"), json!("2024-01-01T00:00:00.500Z"));
+        assert!(compare_values(&with_millis, &whole).is_gt());
+        assert!(compare_values(&whole, &with_millis).is_lt());
+        assert!(
+            compare_values(&json!(1712345678901234567i64), &json!(1712345678901234566i64)).is_gt()
+        );
+        assert!(compare_values(&json!(2.5), &json!(3)).is_lt());
+        assert!(compare_values(&json!("a"), &json!(3)).is_eq());
+    }
+
+    #[test]
+    fn check_rewrite_atomic_updates() {
+        let parsed = Cli::mockup_from(&[
+            "solrcopy",
+            "restore",
+            "--core",
+            "target",
+            "--dir",
+            "./tmp",
+            "--atomic",
+            "set",
+            "--atomic-fields",
+            "tags=add-distinct,stock:inc",
+            "--atomic-missing",
+            "skip",
+        ]);
+        let puts = parsed.put().unwrap();
+        let docs = r#"[{"id":"1","price":9.9,"tags":["a"],"stock":2,"_version_":123}]"#;
+        let rewritten = puts.rewrite_docs(docs.to_string().into()).unwrap();
+        let parsed: Value = serde_json::from_str(&rewritten).unwrap();
+        let expected = json!([{
+            "id": "1",
+            "price": { "set": 9.9 },
+            "tags": { "add-distinct": ["a"] },
+            "stock": { "inc": 2 },
+            "_version_": 1
+        }]);
+        assert_eq!(parsed, expected);
+    }
+}
+
+// end of file \\
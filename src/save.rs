@@ -1,20 +1,159 @@
-use super::models::{Compression, Documents};
-use log::error;
+use super::{
+    fails::BoxedResult,
+    helpers::{IntegerHelpers, get_filename, is_stdio, sha256_file, sha256_hex},
+    models::{CHECKSUM_EXT, Compression, Documents, MANIFEST_ENTRY, PARTIAL_EXT},
+    storage::DocumentSink,
+};
+use flate2::write::GzEncoder;
+use log::{error, warn};
+use serde::de::IgnoredAny;
+use serde_json::{Value, json};
 use std::{
-    io::Write,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
     path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
-use zip::{ZipWriter, result::ZipResult, write::SimpleFileOptions};
+use tar::{Builder, Header};
+use zip::{AesMode, ZipWriter, result::ZipResult, write::SimpleFileOptions};
 
 // TODO: split in multiple files of constant size
 // TODO: limit file size based on zip.stats.bytes_written
 
+// region StreamEncoder
+
+/// Destination of a backup stream: a file or stdout
+type Sink = BufWriter<Box<dyn Write + Send>>;
+
+/// Output stream of a backup file optionally compressed
+pub(crate) enum StreamEncoder {
+    Plain(Sink),
+    Gzip(GzEncoder<Sink>),
+    Zstd(zstd::Encoder<'static, Sink>),
+}
+
+impl StreamEncoder {
+    fn create(path: &Path, compression: Compression) -> io::Result<Self> {
+        Self::wrap(Box::new(File::create(path)?), compression)
+    }
+
+    /// Opens the backup file named `file_name` in the folder or stdout when the folder is `-`.
+    /// Returns the final path of the file for publishing it when finished
+    pub(crate) fn create_on(
+        folder: &Path, file_name: &str, compression: Compression,
+    ) -> io::Result<(Self, Option<PathBuf>)> {
+        if is_stdio(folder) {
+            return Ok((Self::wrap(Box::new(io::stdout()), compression)?, None));
+        }
+        let file_path = folder.join(file_name);
+        Ok((Self::create(&partial_path(&file_path), compression)?, Some(file_path)))
+    }
+
+    fn wrap(sink: Box<dyn Write + Send>, compression: Compression) -> io::Result<Self> {
+        let file = BufWriter::new(sink);
+        let encoder = match compression {
+            Compression::Stored => StreamEncoder::Plain(file),
+            Compression::Zip => StreamEncoder::Gzip(GzEncoder::new(file, Default::default())),
+            Compression::Zstd => StreamEncoder::Zstd(zstd::Encoder::new(file, 0)?),
+        };
+        Ok(encoder)
+    }
+
+    pub(crate) fn finish(self) -> io::Result<()> {
+        let mut file = match self {
+            StreamEncoder::Plain(file) => file,
+            StreamEncoder::Gzip(encoder) => encoder.finish()?,
+            StreamEncoder::Zstd(encoder) => encoder.finish()?,
+        };
+        file.flush()
+    }
+
+    fn stream(&mut self) -> &mut dyn Write {
+        match self {
+            StreamEncoder::Plain(file) => file,
+            StreamEncoder::Gzip(encoder) => encoder,
+            StreamEncoder::Zstd(encoder) => encoder,
+        }
+    }
+}
+
+impl Write for StreamEncoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream().flush()
+    }
+}
+
+/// Counts the documents in the json array of a batch
+pub(crate) fn count_docs(json: &str) -> u64 {
+    serde_json::from_str::<Vec<IgnoredAny>>(json).map_or(0, |parsed| parsed.len().to_u64())
+}
+
+/// Name, document count, size and SHA-256 of an entry for the manifest of the archive
+fn describe_entry(filename: &str, json: &str) -> BoxedResult<Value> {
+    let docs = serde_json::from_str::<Vec<IgnoredAny>>(json)?.len().to_u64();
+    Ok(json!({
+        "name": filename,
+        "docs": docs,
+        "bytes": json.len().to_u64(),
+        "sha256": sha256_hex(json.as_bytes()),
+    }))
+}
+
+/// Contents of the manifest entry written as the last entry of the archive
+fn build_manifest(entries: Vec<Value>) -> String {
+    let docs: u64 = entries.iter().filter_map(|entry| entry["docs"].as_u64()).sum();
+    json!({ "docs": docs, "entries": entries }).to_string()
+}
+
+/// Path where the backup file is written until finished, so that an interrupted backup never
+/// leaves a truncated file under the name searched by restore
+pub(crate) fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(PARTIAL_EXT);
+    PathBuf::from(partial)
+}
+
+/// Renames the backup file finished to its final name and writes its `.sha256` checksum file
+pub(crate) fn publish_file(path: PathBuf) -> io::Result<()> {
+    fs::rename(partial_path(&path), &path)?;
+    write_checksum_file(path)
+}
+
+/// Warns about the backup file left under its temporary name after a failure
+pub(crate) fn warn_unpublished(path: Option<PathBuf>) {
+    if let Some(path) = path {
+        warn!("Left the unfinished backup file {:?} unpublished.", partial_path(&path));
+    }
+}
+
+/// Writes the `.sha256` checksum file of the backup file finished
+fn write_checksum_file(path: PathBuf) -> io::Result<()> {
+    let checksum = sha256_file(&path)?;
+    let name = get_filename(&path).unwrap_or_default();
+    let mut sidecar = path.into_os_string();
+    sidecar.push(CHECKSUM_EXT);
+    // Same format of `sha256sum` for checking with other tools
+    fs::write(sidecar, format!("{}  {}\n", checksum, name))
+}
+
+// endregion
+
 // region Archiver
 
-type Compressor = ZipWriter<std::fs::File>;
+type Compressor = ZipWriter<File>;
 
 pub(crate) struct Archiver {
     writer: Option<Compressor>,
+    /// Key for encrypting the entries with AES-256, when given
+    key: Option<String>,
+    /// Path of the archive being written
+    current: Option<PathBuf>,
+    /// Name, document count, size and SHA-256 of the entries written in the current archive
+    entries: Vec<Value>,
     folder: PathBuf,
     compression: Compression,
     file_pattern: String,
@@ -28,6 +167,9 @@ pub(crate) fn write_on(
     ) -> Self {
         Archiver {
             writer: None,
+            key: None,
+            current: None,
+            entries: vec![],
             folder: output_dir.to_owned(),
             compression,
             file_pattern: output_pattern.to_string(),
@@ -36,7 +178,12 @@ pub(crate) fn write_on(
         }
     }
 
-    fn create_archive(&mut self, suffix: &str) -> ZipResult<()> {
+    pub(crate) fn encrypted_with(mut self, key: Option<String>) -> Self {
+        self.key = key;
+        self
+    }
+
+    fn create_archive(&mut self, suffix: &str) -> BoxedResult<()> {
         self.close_archive()?;
 
         let file_name = self.file_pattern.replace("{}", suffix);
@@ -44,10 +191,11 @@ fn create_archive(&mut self, suffix: &str) -> ZipResult<()> {
         let zip_name = Path::new(&file_name);
         let zip_file = zip_path.join(zip_name);
 
-        let file = std::fs::File::create(&zip_file)?;
+        let file = File::create(partial_path(&zip_file))?;
         let zip = zip::ZipWriter::new(file);
 
         self.writer = Some(zip);
+        self.current = Some(zip_file);
         self.file_count = 0;
         Ok(())
     }
@@ -64,26 +212,48 @@ fn write_file(&mut self, filename: &str, docs: &str) -> ZipResult<()> {
         };
 
         let opts = SimpleFileOptions::default().compression_method(method).unix_permissions(0o644);
+        let opts = match &self.key {
+            Some(key) => opts.with_aes_encryption(AesMode::Aes256, key),
+            None => opts,
+        };
 
         zip.start_file(filename, opts)?;
         zip.write_all(bytes)?;
         zip.flush()?;
         Ok(())
     }
+}
 
-    pub(crate) fn close_archive(&mut self) -> ZipResult<()> {
+impl DocumentSink for Archiver {
+    /// Writes the manifest entry, renames the current archive and writes its `.sha256` file
+    fn close_archive(&mut self) -> BoxedResult<()> {
+        if self.writer.is_some() {
+            let manifest = build_manifest(self.entries.drain(..).collect());
+            self.write_file(MANIFEST_ENTRY, &manifest)?;
+        }
         if let Some(wr) = self.writer.take() {
             wr.finish()?;
         }
+        if let Some(zip_file) = self.current.take() {
+            publish_file(zip_file)?;
+        }
         self.writer = None;
         Ok(())
     }
 
-    pub(crate) fn write_documents(&mut self, docs: &Documents) -> ZipResult<()> {
+    fn abandon_archive(&mut self) -> BoxedResult<()> {
+        self.entries.clear();
+        warn_unpublished(self.current.take());
+        if let Some(wr) = self.writer.take() {
+            wr.finish()?;
+        }
+        Ok(())
+    }
+
+    fn write_documents(&mut self, docs: &Documents) -> BoxedResult<u64> {
         let json = &docs.docs;
-        let json_size = json.len();
-        if json_size <= 2 {
-            return Ok(());
+        if json.len() <= 2 {
+            return Ok(0);
         }
         let step = &docs.step;
 
@@ -96,16 +266,242 @@ pub(crate) fn write_documents(&mut self, docs: &Documents) -> ZipResult<()> {
             let suffix = format!("{:09}", step.curr + 1);
             self.create_archive(&suffix)?;
         }
-        self.write_file(&filename, json)
+        let entry = describe_entry(&filename, json)?;
+        self.write_file(&filename, json)?;
+        let written = entry["docs"].as_u64().unwrap_or_default();
+        self.entries.push(entry);
+        Ok(written)
     }
 }
 
 impl Drop for Archiver {
     fn drop(&mut self) {
-        let fail = self.close_archive();
+        let fail = self.abandon_archive();
+        if let Err(cause) = fail {
+            error!("> Dropping {}", cause);
+        }
+    }
+}
+
+// endregion
+
+// region TarArchiver
+
+/// Writes the batches as entries of a tar archive compressed as a stream, so the archive is
+/// readable up to its last complete entry even when the backup does not finish
+pub(crate) struct TarArchiver {
+    builder: Option<Builder<StreamEncoder>>,
+    /// Path of the archive being written
+    current: Option<PathBuf>,
+    /// Name, document count, size and SHA-256 of the entries written in the current archive
+    entries: Vec<Value>,
+    folder: PathBuf,
+    compression: Compression,
+    file_pattern: String,
+    max_files: usize,
+    file_count: usize,
+}
+
+impl TarArchiver {
+    pub(crate) fn write_on(
+        output_dir: &Path, output_pattern: &str, compression: Compression, max: usize,
+    ) -> Self {
+        TarArchiver {
+            builder: None,
+            current: None,
+            entries: vec![],
+            folder: output_dir.to_owned(),
+            compression,
+            file_pattern: output_pattern.to_string(),
+            max_files: max,
+            file_count: 0,
+        }
+    }
+
+    fn create_archive(&mut self, suffix: &str) -> BoxedResult<()> {
+        self.close_archive()?;
+
+        let file_name = self.file_pattern.replace("{}", suffix);
+        let (encoder, tar_file) =
+            StreamEncoder::create_on(&self.folder, &file_name, self.compression)?;
+
+        self.builder = Some(Builder::new(encoder));
+        self.current = tar_file;
+        self.file_count = 0;
+        Ok(())
+    }
+
+    fn write_file(&mut self, filename: &str, docs: &str) -> io::Result<()> {
+        let bytes = docs.as_bytes();
+        let modified = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        let mut header = Header::new_gnu();
+        header.set_size(bytes.len().to_u64());
+        header.set_mode(0o644);
+        header.set_mtime(modified.as_secs());
+
+        let tar = self.builder.as_mut().unwrap();
+        tar.append_data(&mut header, filename, bytes)?;
+        // Flushing the compressor makes the entry readable even if the archive is never finished
+        tar.get_mut().flush()
+    }
+}
+
+impl DocumentSink for TarArchiver {
+    /// Writes the manifest entry, renames the current archive and writes its `.sha256` file
+    fn close_archive(&mut self) -> BoxedResult<()> {
+        if self.builder.is_some() {
+            let manifest = build_manifest(self.entries.drain(..).collect());
+            self.write_file(MANIFEST_ENTRY, &manifest)?;
+        }
+        if let Some(tar) = self.builder.take() {
+            tar.into_inner()?.finish()?;
+        }
+        if let Some(tar_file) = self.current.take() {
+            publish_file(tar_file)?;
+        }
+        Ok(())
+    }
+
+    fn abandon_archive(&mut self) -> BoxedResult<()> {
+        self.entries.clear();
+        warn_unpublished(self.current.take());
+        if let Some(tar) = self.builder.take() {
+            tar.into_inner()?.finish()?;
+        }
+        Ok(())
+    }
+
+    fn write_documents(&mut self, docs: &Documents) -> BoxedResult<u64> {
+        let json = &docs.docs;
+        if json.len() <= 2 {
+            return Ok(0);
+        }
+        let step = &docs.step;
+
+        let filename = format!("docs_at_{:09}.json", step.curr + 1);
+
+        self.file_count += 1;
+        // Stdout is a single stream that is never split
+        let wrap = self.file_count >= self.max_files && !is_stdio(&self.folder);
+
+        if self.builder.is_none() || wrap {
+            let suffix = format!("{:09}", step.curr + 1);
+            self.create_archive(&suffix)?;
+        }
+        let entry = describe_entry(&filename, json)?;
+        self.write_file(&filename, json)?;
+        let written = entry["docs"].as_u64().unwrap_or_default();
+        self.entries.push(entry);
+        Ok(written)
+    }
+}
+
+impl Drop for TarArchiver {
+    fn drop(&mut self) {
+        let fail = self.abandon_archive();
+        if let Err(cause) = fail {
+            error!("> Dropping {}", cause);
+        }
+    }
+}
+
+// endregion
+
+// region JsonLinesWriter
+
+/// Writes the documents as json lines for being read by tools like Spark, DuckDB and jq
+pub(crate) struct JsonLinesWriter {
+    encoder: Option<StreamEncoder>,
+    /// Path of the file being written
+    current: Option<PathBuf>,
+    folder: PathBuf,
+    compression: Compression,
+    file_pattern: String,
+    max_files: usize,
+    file_count: usize,
+}
+
+impl JsonLinesWriter {
+    pub(crate) fn write_on(
+        output_dir: &Path, output_pattern: &str, compression: Compression, max: usize,
+    ) -> Self {
+        JsonLinesWriter {
+            encoder: None,
+            current: None,
+            folder: output_dir.to_owned(),
+            compression,
+            file_pattern: output_pattern.to_string(),
+            max_files: max,
+            file_count: 0,
+        }
+    }
+
+    fn create_file(&mut self, suffix: &str) -> BoxedResult<()> {
+        self.close_archive()?;
+
+        let file_name = self.file_pattern.replace("{}", suffix);
+        let (encoder, file_path) =
+            StreamEncoder::create_on(&self.folder, &file_name, self.compression)?;
+        self.encoder = Some(encoder);
+        self.current = file_path;
+        self.file_count = 0;
+        Ok(())
+    }
+}
+
+impl DocumentSink for JsonLinesWriter {
+    /// Finishes the compression, renames the current file and writes its `.sha256` file
+    fn close_archive(&mut self) -> BoxedResult<()> {
+        if let Some(encoder) = self.encoder.take() {
+            encoder.finish()?;
+        }
+        if let Some(file_path) = self.current.take() {
+            publish_file(file_path)?;
+        }
+        Ok(())
+    }
+
+    fn abandon_archive(&mut self) -> BoxedResult<()> {
+        warn_unpublished(self.current.take());
+        if let Some(encoder) = self.encoder.take() {
+            encoder.finish()?;
+        }
+        Ok(())
+    }
+
+    fn write_documents(&mut self, docs: &Documents) -> BoxedResult<u64> {
+        let json = &docs.docs;
+        if json.len() <= 2 {
+            return Ok(0);
+        }
+        // Reformatting each document guarantees that it fits in a single line
+        let parsed: Vec<Value> = serde_json::from_str(json)?;
+
+        self.file_count += 1;
+        let wrap = self.file_count >= self.max_files && !is_stdio(&self.folder);
+
+        if self.encoder.is_none() || wrap {
+            let suffix = format!("{:09}", docs.step.curr + 1);
+            self.create_file(&suffix)?;
+        }
+        let stream = self.encoder.as_mut().unwrap();
+        let written = parsed.len().to_u64();
+        for doc in parsed {
+            serde_json::to_writer(&mut *stream, &doc)?;
+            stream.write_all(b"\n")?;
+        }
+        Ok(written)
+    }
+}
+
+impl Drop for JsonLinesWriter {
+    fn drop(&mut self) {
+        let fail = self.abandon_archive();
         if let Err(cause) = fail {
             error!("> Dropping {}", cause);
         }
     }
 }
+
 // endregion
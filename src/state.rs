@@ -1,26 +1,30 @@
-use log::{debug, error};
+use log::error;
 use simplelog::{
     ColorChoice, CombinedLogger, ConfigBuilder, SharedLogger, TermLogger, WriteLogger,
 };
+use std::fmt;
 use std::fs::File;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 
-use crate::args::LoggingArgs;
+use crate::{args::LoggingArgs, fails::Error};
 
 // region Ctrl + C
 
+/// Flag raised by Ctrl-C, only after the handler was installed by [`handle_ctrl_c`]
 pub(crate) fn monitor_term_sinal() -> Arc<AtomicBool> {
     lazy_static! {
-        static ref ABORTING: Arc<AtomicBool> = start_monitoring_term_sinal();
+        static ref ABORTING: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
     }
     ABORTING.clone()
 }
 
-fn start_monitoring_term_sinal() -> Arc<AtomicBool> {
-    let handler = Arc::new(AtomicBool::new(false));
-    let aborting = handler.clone();
-
+/// Stops the commands run by [`crate::run_from_args`] on Ctrl-C and aborts on a second one.
+///
+/// The `solrcopy` binary installs it before running the command. The builders never need it,
+/// as they stop by their [`CancelToken`] instead.
+pub fn handle_ctrl_c() -> Result<(), Error> {
+    let handler = monitor_term_sinal();
     ctrlc::set_handler(move || {
         if handler.aborted() {
             error!("# Received abort signal (Ctrl-C) from user again!!! Aborting...\n");
@@ -30,11 +34,80 @@ fn start_monitoring_term_sinal() -> Arc<AtomicBool> {
             handler.store(true, Ordering::SeqCst);
         }
     })
-    .expect("Error setting Ctrl-C handler");
+    .map_err(|cause| Error::Failed(format!("Error setting Ctrl-C handler: {}", cause)))
+}
+
+// endregion
+
+// region Embedding
+
+/// Stops a running operation when cancelled from another thread.
+///
+/// Clones share the same state, so any of them can cancel the operation.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks the operation to stop as soon as the batches being transferred finish
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.aborted()
+    }
+}
+
+/// Documents transferred so far by a backup or restore
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub docs: u64,
+    /// Known when counted before starting, but not when restoring from stdin or buckets
+    pub total_docs: Option<u64>,
+}
 
-    debug!("# Waiting for Ctrl-C...");
+/// Receives the progress instead of the progress bars
+pub(crate) type ProgressCallback = Arc<dyn Fn(Progress) + Send + Sync>;
 
-    aborting
+/// Replaces the Ctrl-C handler and the progress bars when running as a library
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    pub cancel: Option<CancelToken>,
+    pub progress: Option<ProgressCallback>,
+}
+
+impl Hooks {
+    /// Flag telling the threads to stop: raised by the cancel token or else by Ctrl-C
+    pub(crate) fn aborting(&self) -> Arc<AtomicBool> {
+        match &self.cancel {
+            Some(token) => token.cancelled.clone(),
+            None => monitor_term_sinal(),
+        }
+    }
+
+    /// Sends the progress to the callback and tells if there was one
+    pub(crate) fn report(&self, docs: u64, total_docs: Option<u64>) -> bool {
+        match &self.progress {
+            Some(callback) => {
+                callback(Progress { docs, total_docs });
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let progress = self.progress.as_ref().map(|_| "callback");
+        f.debug_struct("Hooks").field("cancel", &self.cancel).field("progress", &progress).finish()
+    }
 }
 
 // endregion
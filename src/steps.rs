@@ -1,14 +1,15 @@
 use super::{
     args::{Backup, IterateMode},
     fails::{BoxedResult, throw},
-    helpers::{BRACKETS, COMMA, EMPTY_STR, EMPTY_STRING},
+    helpers::{BRACKETS, COMMA, EMPTY_STR, EMPTY_STRING, MILLION},
     helpers::{IntegerHelpers, StringHelpers, replace_solr_date, solr_query},
     models::{SolrCore, Step},
 };
 use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Utc};
 use log::debug;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
+use url::form_urlencoded::byte_serialize;
 
 // region Data Structures
 
@@ -36,6 +37,14 @@ pub(crate) struct Requests {
     pub url: String,
 }
 
+/// Documents found in a bucket of `--stratify` in the whole `--between` range and the ones
+/// to retrieve from it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BucketQuota {
+    pub found: u64,
+    pub quota: u64,
+}
+
 // endregion
 
 // region Iterators
@@ -199,6 +208,26 @@ pub(crate) fn len(&self) -> u64 {
         let res = self.limit / self.num_docs;
         if self.limit.is_multiple_of(self.num_docs) { res } else { res + 1 }
     }
+
+    /// Number of documents requested by all the steps
+    pub(crate) fn count_rows(&self) -> u64 {
+        self.limit.saturating_sub(self.curr)
+    }
+}
+
+impl BucketQuota {
+    /// Documents to retrieve from a slice with `num_found` docs of the bucket, when `seen` docs
+    /// were found and `taken` retrieved in the previous slices.
+    /// The quota is split across the slices in proportion to the docs of the bucket in each one
+    pub(crate) fn share_of(&self, seen: u64, num_found: u64, taken: u64) -> u64 {
+        if self.found == 0 {
+            return 0;
+        }
+        let found = (seen + num_found).min(self.found);
+        let target = u128::from(self.quota) * u128::from(found) / u128::from(self.found);
+        let target = u64::try_from(target).unwrap_or(self.quota);
+        target.saturating_sub(taken).min(num_found)
+    }
 }
 
 impl Iterator for Requests {
@@ -233,6 +262,12 @@ fn replace_solr_vars(query: &str, begin: &str, end: &str) -> String {
     replace_solr_date(&query2, "{end}", end)
 }
 
+/// Child doc transformer returning all fields of the children of the parents matching `parents`
+fn get_child_transformer(parents: &str) -> String {
+    let transformer = format!("[child parentFilter='{}' limit=-1 fl=*]", parents);
+    byte_serialize(transformer.as_bytes()).collect()
+}
+
 fn format_solr_time(date_time: NaiveDateTime) -> String {
     date_time.format("%Y-%m-%dT%H:%M:%SZ").to_string()
 }
@@ -243,6 +278,15 @@ fn format_solr_time(date_time: NaiveDateTime) -> String {
 
 impl Backup {
     pub(crate) fn get_archive_pattern(&self, num_retrieve: u64) -> String {
+        self.get_archive_pattern_as("seq", num_retrieve)
+    }
+
+    /// Pattern of the archives with the slices fetched again by `--verify`, apart from the others
+    pub(crate) fn get_refetch_pattern(&self, num_retrieve: u64) -> String {
+        self.get_archive_pattern_as("refetch", num_retrieve)
+    }
+
+    fn get_archive_pattern_as(&self, label: &str, num_retrieve: u64) -> String {
         let prefix = match &self.archive_prefix {
             Some(text) => text.to_string(),
             None => {
@@ -251,13 +295,57 @@ pub(crate) fn get_archive_pattern(&self, num_retrieve: u64) -> String {
                 format!("{}_at_{}", &self.options.core, &time)
             }
         };
-        let ext = self.archive_compression.get_ext();
-        format!("{}_docs_{}_seq_{}.{}", prefix, num_retrieve, BRACKETS, ext)
+        let ext = self.archive_format.get_ext(self.archive_compression);
+        format!("{}_docs_{}_{}_{}.{}", prefix, num_retrieve, label, BRACKETS, ext)
     }
 
     pub(crate) fn get_docs_to_retrieve(&self, num_found: u64) -> u64 {
         let num_retrieve = num_found - self.skip;
-        num_retrieve.min(self.limit.unwrap_or(u64::MAX))
+        let sampled = match self.sample {
+            Some(fraction) => (num_retrieve * fraction).div_ceil(MILLION),
+            None => num_retrieve,
+        };
+        sampled.min(self.limit.unwrap_or(u64::MAX))
+    }
+
+    /// Documents to retrieve from the `num_found` by the query, in all buckets of `--stratify`
+    pub(crate) fn count_docs_to_retrieve(
+        &self, num_found: u64, quotas: &HashMap<String, BucketQuota>,
+    ) -> u64 {
+        if self.stratify.is_none() {
+            return self.get_docs_to_retrieve(num_found);
+        }
+        quotas.values().map(|bucket| bucket.quota).sum()
+    }
+
+    /// Counts the documents of each bucket of `--stratify` in the whole `--between` range,
+    /// for splitting the docs to retrieve from each one across the slices
+    pub(crate) fn query_bucket_quotas(&self) -> BoxedResult<HashMap<String, BucketQuota>> {
+        if self.stratify.is_none() {
+            return Ok(HashMap::new());
+        }
+        let (begin, end) = self.get_between();
+        let strata = self.query_strata(begin, end)?;
+        let quotas = strata.into_iter().map(|(value, found)| {
+            (value, BucketQuota { found, quota: self.get_docs_in_bucket(found) })
+        });
+        Ok(quotas.collect())
+    }
+
+    /// Documents to retrieve from a bucket of `--stratify` with `num_found` docs
+    pub(crate) fn get_docs_in_bucket(&self, num_found: u64) -> u64 {
+        self.get_docs_to_retrieve(num_found).min(self.per_bucket.unwrap_or(u64::MAX))
+    }
+
+    /// Filter query selecting the documents of a bucket of the `--stratify` field
+    pub(crate) fn get_bucket_filter(&self, value: &str) -> String {
+        let field = self.stratify.as_deref().unwrap_or_default();
+        let filter = format!("{{!term f={}}}{}", field, value);
+        format!("&fq={}", byte_serialize(filter.as_bytes()).collect::<String>())
+    }
+
+    fn is_sampling(&self) -> bool {
+        self.sample.is_some() || self.stratify.is_some()
     }
 
     pub(crate) fn merge_core_fields(&self, schema: &SolrCore) -> Vec<String> {
@@ -271,6 +359,17 @@ pub(crate) fn merge_core_fields(&self, schema: &SolrCore) -> Vec<String> {
         diff
     }
 
+    /// Columns of the csv files: the fields selected or found in the core in their order
+    pub(crate) fn get_columns(&self, schema: &SolrCore) -> Vec<String> {
+        let mut columns: Vec<String> = vec![];
+        for field in &schema.fields {
+            if !self.exclude.contains(field) && !columns.contains(field) {
+                columns.push(field.clone());
+            }
+        }
+        columns
+    }
+
     pub(crate) fn get_requests_for_range(
         &self, retrieved: u64, num_retrieve: u64, core_fields: &[String], expected: u64,
         begin: &str, end: &str,
@@ -288,12 +387,16 @@ pub(crate) fn get_requests_for_range(
     }
 
     pub(crate) fn get_query_fields(&self, core_fields: &[String]) -> String {
-        if core_fields.is_empty() {
-            EMPTY_STRING
-        } else {
-            let all = core_fields.join(COMMA);
-            "&fl=".append(&all)
-        }
+        let all = match &self.nested {
+            None if core_fields.is_empty() => return EMPTY_STRING,
+            None => core_fields.join(COMMA),
+            Some(parents) => {
+                let fields =
+                    if core_fields.is_empty() { "*".to_string() } else { core_fields.join(COMMA) };
+                format!("{},{}", fields, get_child_transformer(parents))
+            }
+        };
+        "&fl=".append(&all)
     }
 
     pub(crate) fn get_query_for_diagnostics(&self) -> String {
@@ -315,8 +418,15 @@ pub(crate) fn get_query_url(&self, selected: &str, begin: &str, end: &str) -> St
         let filterq = solr_query(qfixed);
         let fqparam = self.fq.as_deref().unwrap_or("*:*");
         let filterfq = solr_query(fqparam);
+        let parentfq = match &self.nested {
+            Some(parents) => format!("&fq={}", solr_query(parents)),
+            None => EMPTY_STRING,
+        };
 
-        let sort: String = if self.order.is_empty() {
+        let sort: String = if self.is_sampling() {
+            // Documents are sorted randomly but always in the same order for the same seed
+            format!("&sort=random_{}%20asc", self.seed)
+        } else if self.order.is_empty() {
             EMPTY_STRING
         } else {
             let all: Vec<String> = self.order.iter().map(|field| field.to_string()).collect();
@@ -329,6 +439,7 @@ pub(crate) fn get_query_url(&self, selected: &str, begin: &str, end: &str) -> St
             "/select?wt=json&indent=off&omitHeader=true".to_string(),
             format!("&q={}", filterq),
             format!("&fq={}", filterfq),
+            parentfq,
             sort,
             self.transfer.get_param("&"),
             selected.to_string(),
@@ -365,7 +476,7 @@ mod tests {
         args::{Backup, Cli, Commands, IterateMode, shared::TEST_SELECT_FIELDS},
         fails::{BoxedResult, raise},
         helpers::{COMMA, EMPTY_STR},
-        steps::{Slices, SolrCore},
+        steps::{BucketQuota, Slices, SolrCore},
     };
     use pretty_assertions::assert_eq;
 
@@ -436,4 +547,53 @@ fn check_iterator_for_slices_datetime() {
     }
 
     // endregion
+
+    // region queries
+
+    #[test]
+    fn check_query_for_nested_docs() {
+        let parsed = Cli::mockup_from(&[
+            "solrcopy",
+            "backup",
+            "--core",
+            "demo",
+            "--dir",
+            "./tmp",
+            "--nested",
+            "doc_type:parent",
+        ]);
+        let gets = parsed.get().unwrap();
+        let fields = gets.get_query_fields(&["id".to_string(), "name".to_string()]);
+        assert_eq!(
+            fields,
+            "&fl=id,name,%5Bchild+parentFilter%3D%27doc_type%3Aparent%27+limit%3D-1+fl%3D*%5D"
+        );
+        let query = gets.get_query_url(&fields, EMPTY_STR, EMPTY_STR);
+        assert!(query.contains("&fq=doc_type:parent&"));
+    }
+
+    #[test]
+    fn check_query_for_samples() {
+        let backup = &["solrcopy", "backup", "--core", "demo", "--dir", "./tmp", "--seed", "7"];
+        let parsed = Cli::mockup_from(&[backup.as_slice(), &["--sample", "1%"]].concat());
+        let gets = parsed.get().unwrap();
+        let query = gets.get_query_url(EMPTY_STR, EMPTY_STR, EMPTY_STR);
+        assert!(query.contains("&sort=random_7%20asc"));
+        assert_eq!(gets.get_docs_to_retrieve(250), 3);
+
+        let strata = ["--stratify", "cat", "--per-bucket", "10"];
+        let parsed = Cli::mockup_from(&[backup.as_slice(), &strata].concat());
+        let gets = parsed.get().unwrap();
+        assert_eq!(gets.get_docs_in_bucket(25), 10);
+        assert_eq!(gets.get_docs_in_bucket(3), 3);
+        // The 10 docs of a bucket with 25 are split across slices with 5, 0 and 20 of them
+        let bucket = BucketQuota { found: 25, quota: 10 };
+        assert_eq!(bucket.share_of(0, 5, 0), 2);
+        assert_eq!(bucket.share_of(5, 0, 2), 0);
+        assert_eq!(bucket.share_of(5, 20, 2), 8);
+        assert_eq!(bucket.share_of(25, 3, 10), 0);
+        assert_eq!(gets.get_bucket_filter("a b"), "&fq=%7B%21term+f%3Dcat%7Da+b");
+    }
+
+    // endregion
 }
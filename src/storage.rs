@@ -0,0 +1,230 @@
+use super::{
+    args::{Backup, Restore},
+    bucket::{BucketUploader, S3Bucket, is_bucket},
+    columnar::ParquetWriter,
+    fails::BoxedResult,
+    helpers::{IntegerHelpers, STDIO, is_stdio},
+    ingest::{
+        ArchiveTotals, BackupEntries, decode_stream, find_archives, read_backup_file,
+        read_backup_stream,
+    },
+    models::{ArchiveFormat, Columns, Documents},
+    save::{Archiver, JsonLinesWriter, TarArchiver},
+    tabular::CsvWriter,
+};
+use log::warn;
+use std::{
+    fs,
+    io::{BufReader, stdin},
+    path::{Path, PathBuf},
+};
+
+// region DocumentSink
+
+/// Stores the batches of documents retrieved by backup in the files of an archive format
+pub(crate) trait DocumentSink {
+    /// Writes the batch and returns how many documents it had
+    fn write_documents(&mut self, docs: &Documents) -> BoxedResult<u64>;
+
+    /// Finishes the file being written, if any, and publishes it under its final name
+    fn close_archive(&mut self) -> BoxedResult<()>;
+
+    /// Finishes the stream of the file being written after a failure, leaving it under its
+    /// temporary name so that restore never reads it. The sinks also do it when dropped
+    fn abandon_archive(&mut self) -> BoxedResult<()>;
+}
+
+/// Creates the sink of the backup files in the `--archive-format` chosen.
+/// The `columns` are the fields written in csv and parquet files.
+/// For a bucket in `--dir`, the files are uploaded as soon as they are finished
+pub(crate) fn open_sink(
+    params: &Backup, columns: &Columns, output_pattern: &str,
+) -> BoxedResult<Box<dyn DocumentSink>> {
+    if !is_bucket(&params.transfer.dir) {
+        return create_file_writer(params, columns, output_pattern, &params.transfer.dir);
+    }
+    let bucket = S3Bucket::open(&params.transfer.dir)?;
+    let staging = BucketUploader::create_staging()?;
+    let writer = create_file_writer(params, columns, output_pattern, &staging)?;
+    Ok(Box::new(BucketUploader::upload_from(writer, bucket, staging)))
+}
+
+fn create_file_writer(
+    params: &Backup, columns: &Columns, output_pattern: &str, dir: &Path,
+) -> BoxedResult<Box<dyn DocumentSink>> {
+    let compression = params.archive_compression;
+    let max = params.archive_files.to_usize();
+    let writer: Box<dyn DocumentSink> = match params.archive_format {
+        ArchiveFormat::Zip => {
+            let key = params.encryption.get_key()?;
+            Box::new(Archiver::write_on(dir, output_pattern, compression, max).encrypted_with(key))
+        }
+        ArchiveFormat::Tar => {
+            Box::new(TarArchiver::write_on(dir, output_pattern, compression, max))
+        }
+        ArchiveFormat::Jsonl => {
+            Box::new(JsonLinesWriter::write_on(dir, output_pattern, compression, max))
+        }
+        ArchiveFormat::Csv => {
+            let (names, csv) = (&columns.names, &params.csv);
+            Box::new(CsvWriter::write_on(dir, output_pattern, compression, max, names, csv))
+        }
+        ArchiveFormat::Parquet => {
+            let row_group = params.parquet_row_group_size.to_usize();
+            let file_size = params.parquet_file_size.to_usize();
+            Box::new(ParquetWriter::write_on(
+                dir,
+                output_pattern,
+                compression,
+                max,
+                columns,
+                row_group,
+                file_size,
+            ))
+        }
+    };
+    Ok(writer)
+}
+
+// endregion
+
+// region DocumentSource
+
+/// Provides the backup files read by restore and the batches of documents stored in them
+pub(crate) trait DocumentSource: Sync {
+    /// Lists the backup files to restore in the `--order` chosen
+    fn find_archives(&self) -> BoxedResult<Vec<PathBuf>>;
+
+    /// Sums the batches and documents of the backup files when known before reading them
+    fn count_totals(&self, found: &[PathBuf]) -> ArchiveTotals;
+
+    /// Opens one of the backup files found for reading its batches of documents
+    fn read_archive(&self, path: &Path) -> BoxedResult<BackupEntries>;
+}
+
+/// Creates the source of the backup files in `--dir`: a local folder, stdin or a bucket
+pub(crate) fn open_source(params: &Restore) -> BoxedResult<Box<dyn DocumentSource + '_>> {
+    let key = params.encryption.get_key()?;
+    let dir = &params.transfer.dir;
+    let source: Box<dyn DocumentSource> = if is_stdio(dir) {
+        Box::new(StdinSource { params })
+    } else if is_bucket(dir) {
+        Box::new(BucketSource { params, bucket: S3Bucket::open(dir)?, key })
+    } else {
+        Box::new(FolderSource { params, key })
+    };
+    Ok(source)
+}
+
+/// Backup files matching the `--search` pattern in a local folder
+struct FolderSource<'a> {
+    params: &'a Restore,
+    key: Option<String>,
+}
+
+impl DocumentSource for FolderSource<'_> {
+    fn find_archives(&self) -> BoxedResult<Vec<PathBuf>> {
+        Ok(find_archives(&self.params.get_pattern(), self.params.order)?)
+    }
+
+    fn count_totals(&self, found: &[PathBuf]) -> ArchiveTotals {
+        ArchiveTotals::count(found, self.key.as_deref())
+    }
+
+    fn read_archive(&self, path: &Path) -> BoxedResult<BackupEntries> {
+        let params = self.params;
+        read_backup_file(path, &params.csv, &params.csv_split, self.key.as_deref())
+    }
+}
+
+/// A single backup stream piped into stdin whose format comes from `--archive-format`
+struct StdinSource<'a> {
+    params: &'a Restore,
+}
+
+impl DocumentSource for StdinSource<'_> {
+    fn find_archives(&self) -> BoxedResult<Vec<PathBuf>> {
+        Ok(vec![PathBuf::from(STDIO)])
+    }
+
+    fn count_totals(&self, _found: &[PathBuf]) -> ArchiveTotals {
+        // Stdin can only be read once, so its size is unknown until restored
+        ArchiveTotals { batches: 0, docs: None }
+    }
+
+    fn read_archive(&self, _path: &Path) -> BoxedResult<BackupEntries> {
+        let params = self.params;
+        let source = decode_stream(BufReader::new(stdin()))?;
+        read_backup_stream(source, params.archive_format, &params.csv, &params.csv_split)
+    }
+}
+
+/// Backup files stored in a S3-compatible bucket
+struct BucketSource<'a> {
+    params: &'a Restore,
+    bucket: S3Bucket,
+    key: Option<String>,
+}
+
+impl DocumentSource for BucketSource<'_> {
+    fn find_archives(&self) -> BoxedResult<Vec<PathBuf>> {
+        let pattern = self.params.get_pattern();
+        let wildcard = pattern.rsplit('/').next().unwrap_or_default();
+        self.bucket.find_objects(wildcard, self.params.order)
+    }
+
+    fn count_totals(&self, _found: &[PathBuf]) -> ArchiveTotals {
+        // The files are counted only when downloaded, so their size is unknown until restored
+        ArchiveTotals { batches: 0, docs: None }
+    }
+
+    fn read_archive(&self, path: &Path) -> BoxedResult<BackupEntries> {
+        let params = self.params;
+        let format = ArchiveFormat::of_file(path);
+        if format.is_streamable() {
+            let source = decode_stream(BufReader::new(self.bucket.read_object(path)?))?;
+            return read_backup_stream(source, format, &params.csv, &params.csv_split);
+        }
+        // Zip archives need seeking, so they are read from a local copy
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let local = std::env::temp_dir().join(format!("solrcopy_{}_{}", std::process::id(), name));
+        let key = self.key.as_deref();
+        let opened = self
+            .bucket
+            .download(path, &local)
+            .and_then(|_| read_backup_file(&local, &params.csv, &params.csv_split, key));
+        // Also removes the partial copy left by a failed download
+        if local.exists()
+            && let Err(cause) = fs::remove_file(&local)
+        {
+            warn!("Error removing the downloaded copy {:?}: {}", local, cause);
+        }
+        opened
+    }
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use super::open_source;
+    use crate::{args::Cli, ingest::ArchiveTotals};
+    use clap::Parser;
+    use pretty_assertions::assert_eq;
+    use std::path::PathBuf;
+
+    #[test]
+    fn check_stdin_source() {
+        let args = &["solrcopy", "restore", "--url", "http://localhost:8983/solr", "--core"];
+        let args = [args.as_slice(), &["demo", "--dir", "-", "--archive-format", "jsonl"]].concat();
+        let parsed = Cli::parse_from(args).arguments;
+        let restore = parsed.put().unwrap();
+        let source = open_source(restore).unwrap();
+        let found = source.find_archives().unwrap();
+
+        assert_eq!(found, vec![PathBuf::from("-")]);
+        assert_eq!(source.count_totals(&found), ArchiveTotals { batches: 0, docs: None });
+    }
+}
+
+// end of file \\
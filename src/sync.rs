@@ -0,0 +1,324 @@
+use super::{
+    args::{Backup, CommonArgs, CsvArgs, Diff, EncryptionArgs, IterateMode},
+    args::{SortDirection, SortField, Synchronize},
+    connection::SolrClient,
+    fails::{BoxedError, BoxedResult, Error, fail_with},
+    fetch::{json_to_key, without_internal_fields},
+    helpers::IntegerHelpers,
+    models::ArchiveFormat,
+    rewrite::compare_values,
+    state::UserInterruption,
+};
+use chrono::Utc;
+use log::{debug, info};
+use serde_json::{Value, json};
+use std::{fs, time::Instant};
+use url::form_urlencoded::byte_serialize;
+
+pub(crate) fn sync_main(params: &Synchronize) -> BoxedError {
+    debug!("# SYNC {:?}", params);
+
+    let started = Instant::now();
+    let since = params.load_mark()?;
+    let until = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+
+    let source = params.to_backup(&since, &until);
+    let num_found = source.query_num_found(&since, &until)?;
+    info!(
+        "Found {} documents in core {} modified between {} and {}.",
+        num_found, params.options.core, since, until
+    );
+
+    let target = params.get_target_options();
+    let (copied, mark) = params.copy_modified_docs(&source, &target, num_found, &since, &until)?;
+    let deleted = if params.delete_missing { params.delete_missing_docs(&target)? } else { 0 };
+
+    if (copied > 0 || deleted > 0) && !params.no_final_commit {
+        crate::commit::commit_main(&target.to_command())?;
+    }
+    params.save_mark(&mark)?;
+
+    info!(
+        "Synchronized core {} into {} in {:?}: {} documents copied and {} deleted. Next run starts from {}.",
+        params.options.core,
+        params.target_core,
+        started.elapsed(),
+        copied,
+        deleted,
+        mark
+    );
+    Ok(())
+}
+
+// region Copying
+
+impl Synchronize {
+    /// Backup of the documents modified between the `{begin}` and `{end}` of the `--field`
+    fn to_backup(&self, since: &str, until: &str) -> Backup {
+        let range = format!("{}:[{{begin}} TO {{end}}]", self.field);
+        let query = match &self.query {
+            Some(filter) => format!("({}) AND {}", filter, range),
+            None => range,
+        };
+        let order = [&self.field, &self.unique_key]
+            .map(|name| SortField { field: name.clone(), direction: SortDirection::Asc });
+        let mut backup = Backup::of_core(&self.options);
+        backup.query = Some(query);
+        backup.fq = self.fq.clone();
+        backup.order = order.to_vec();
+        backup.select = self.get_copied_fields();
+        backup.unique_key = self.unique_key.clone();
+        backup.iterate_by = IterateMode::None;
+        backup.iterate_between = vec![since.to_string(), until.to_string()];
+        backup.num_docs = self.num_docs;
+        backup.transfer.params = self.params.clone();
+        backup
+    }
+
+    /// The `--select` fields plus the ones needed for tracking the changes or all fields
+    fn get_copied_fields(&self) -> Vec<String> {
+        if self.select.is_empty() {
+            return vec!["*".to_string()];
+        }
+        let mut fields = self.select.clone();
+        for needed in [&self.unique_key, &self.field] {
+            if !fields.contains(needed) {
+                fields.push(needed.clone());
+            }
+        }
+        fields
+    }
+
+    fn get_target_options(&self) -> CommonArgs {
+        let mut target = self.options.clone();
+        target.core = self.target_core.clone();
+        if let Some(url) = &self.target_url {
+            target.url = url.clone();
+        }
+        target
+    }
+
+    /// Copies the modified documents and returns their count and the greatest `--field` value.
+    /// Pages with `cursorMark` sorted by the `--field` and uniqueKey, so that no document is
+    /// skipped or copied twice when the source changes while copying
+    fn copy_modified_docs(
+        &self, source: &Backup, target: &CommonArgs, num_found: u64, since: &str, until: &str,
+    ) -> BoxedResult<(u64, String)> {
+        let mut mark = Value::String(since.to_string());
+        if num_found == 0 {
+            return Ok((0, since.to_string()));
+        }
+        let ctrl_c = self.options.hooks.aborting();
+        let update_url = target.get_core_handler_url("/update/json/docs?overwrite=true");
+        debug!("Solr Update Handler: {}", update_url);
+
+        let selected = source.get_query_fields(&source.select);
+        let query = source.get_query_url(&selected, since, until);
+        let mut client = SolrClient::new();
+        let mut cursor = "*".to_string();
+        let mut copied = 0;
+        loop {
+            let url = get_cursor_url(&query, self.num_docs, &cursor);
+            let json = client.get_as_json(&url)?;
+            let (next, docs) = parse_cursor_page(&json)?;
+            let docs = without_internal_fields(docs);
+            if !docs.is_empty() {
+                self.track_mark(&docs, &mut mark);
+                copied += docs.len().to_u64();
+                client.post_as_json(&update_url, &Value::Array(docs).to_string())?;
+            }
+            if ctrl_c.aborted() {
+                fail_with(Error::Cancelled)?;
+            }
+            if next == cursor {
+                break;
+            }
+            debug!("Copied {} of {} documents into core {}", copied, num_found, target.core);
+            cursor = next;
+        }
+        Ok((copied, json_to_key(&mark)))
+    }
+
+    /// Keeps in `mark` the greatest value of the `--field` in the documents
+    fn track_mark(&self, docs: &[Value], mark: &mut Value) {
+        for value in docs.iter().filter_map(|doc| doc.get(&self.field)) {
+            let value = match value {
+                Value::Array(items) => items.first().unwrap_or(value),
+                _ => value,
+            };
+            if compare_values(value, mark).is_gt() {
+                *mark = value.clone();
+            }
+        }
+    }
+}
+
+/// Appends the page size and the `cursorMark` of the page to the query url
+fn get_cursor_url(query: &str, rows: u64, cursor: &str) -> String {
+    let mark: String = byte_serialize(cursor.as_bytes()).collect();
+    format!("{}&rows={}&cursorMark={}", query, rows, mark)
+}
+
+/// Returns the `nextCursorMark` and the documents of a page of the Solr response
+fn parse_cursor_page(json: &str) -> BoxedResult<(String, Vec<Value>)> {
+    let mut response: Value = serde_json::from_str(json)?;
+    let next = response.get("nextCursorMark").and_then(Value::as_str).map(String::from);
+    match (next, response.pointer_mut("/response/docs").map(Value::take)) {
+        (Some(next), Some(Value::Array(docs))) => Ok((next, docs)),
+        _ => fail_with(Error::Solr(format!("Error parsing docs from solr query: {}", json))),
+    }
+}
+
+// endregion
+
+// region Deletions
+
+impl Synchronize {
+    /// Removes from the target core the documents whose uniqueKey is not in the source anymore
+    fn delete_missing_docs(&self, target: &CommonArgs) -> BoxedResult<u64> {
+        let ids = Diff {
+            target_core: Some(self.target_core.clone()),
+            target_url: self.target_url.clone(),
+            dir: None,
+            search: None,
+            archive_format: ArchiveFormat::Zip,
+            query: self.query.clone(),
+            fq: self.fq.clone(),
+            select: vec![self.unique_key.clone()],
+            unique_key: self.unique_key.clone(),
+            num_docs: self.num_docs,
+            output_dir: None,
+            ids_file: None,
+            csv: CsvArgs::default(),
+            encryption: EncryptionArgs::default(),
+            options: self.options.clone(),
+        };
+        let removed = ids.compare()?.extra;
+
+        let url = target.get_update_url();
+        let mut client = SolrClient::new();
+        for chunk in removed.chunks(self.num_docs.to_usize()) {
+            let content = json!({ "delete": chunk });
+            client.post_as_json(&url, &content.to_string())?;
+        }
+        info!(
+            "Deleted {} documents not found anymore in core {}.",
+            removed.len(),
+            self.options.core
+        );
+        Ok(removed.len().to_u64())
+    }
+}
+
+// endregion
+
+// region State file
+
+impl Synchronize {
+    /// Reads the greatest value of `--field` copied in the last run or the `--since` date
+    fn load_mark(&self) -> BoxedResult<String> {
+        let path = &self.state_file;
+        if !path.exists() {
+            return Ok(self.since.clone());
+        }
+        let state: Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+        let field = state.get("field").and_then(Value::as_str);
+        match (field, state.get("mark").and_then(Value::as_str)) {
+            (Some(field), Some(mark)) if field == self.field => Ok(mark.to_string()),
+            (Some(field), Some(_)) => {
+                throws!(
+                    "The state file {:?} tracks the field '{}' instead of '{}'",
+                    path,
+                    field,
+                    self.field
+                )
+            }
+            _ => throws!("Missing the field or mark in the state file: {:?}", path),
+        }
+    }
+
+    /// Replaces the state file only after the new one was fully written
+    fn save_mark(&self, mark: &str) -> BoxedError {
+        let state = json!({
+            "source": self.options.core,
+            "target": self.target_core,
+            "field": self.field,
+            "mark": mark,
+            "synced_at": Utc::now().to_rfc3339(),
+        });
+        let mut partial = self.state_file.clone().into_os_string();
+        partial.push(".partial");
+        fs::write(&partial, serde_json::to_string_pretty(&state)?)?;
+        fs::rename(&partial, &self.state_file)?;
+        Ok(())
+    }
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use crate::args::{Cli, Commands, Synchronize};
+    use pretty_assertions::assert_eq;
+    use serde_json::{Value, json};
+
+    fn mockup_sync(extra: &[&str]) -> Synchronize {
+        let args = [
+            &["solrcopy", "sync", "--url", "http://solr:8983/solr", "--core", "demo"],
+            &["--target-core", "target", "--field", "modified", "--state-file", "demo.json"],
+            extra,
+        ];
+        match Cli::mockup_from(&args.concat()) {
+            Commands::Sync(syn) => syn,
+            _ => panic!("command must be 'sync' !"),
+        }
+    }
+
+    #[test]
+    fn check_sync_query_url() {
+        let syn = mockup_sync(&["--query", "type:car", "--num-docs", "10"]);
+        let source = syn.to_backup("2025-01-01", "2025-02-01T10:00:00Z");
+        let url = source.get_query_url("&fl=*", "2025-01-01", "2025-02-01T10:00:00Z");
+        assert_eq!(
+            url,
+            "http://solr:8983/solr/demo/select?wt=json&indent=off&omitHeader=true\
+             &q=(type:car)%20AND%20modified:[2025-01-01T00:00:00Z%20TO%202025-02-01T10:00:00Z]\
+             &fq=*:*&sort=modified%20Asc,id%20Asc&fl=*"
+        );
+    }
+
+    #[test]
+    fn check_sync_cursor_pages() {
+        let url = super::get_cursor_url("http://solr/demo/select?q=*:*", 10, "AoE+/=");
+        assert_eq!(url, "http://solr/demo/select?q=*:*&rows=10&cursorMark=AoE%2B%2F%3D");
+
+        let page = r#"{"response":{"numFound":2,"docs":[{"id":"1"}]},"nextCursorMark":"AoE"}"#;
+        let (next, docs) = super::parse_cursor_page(page).unwrap();
+        assert_eq!(next, "AoE");
+        assert_eq!(docs, vec![json!({"id": "1"})]);
+        assert!(super::parse_cursor_page(r#"{"response":{"docs":[]}}"#).is_err());
+    }
+
+    #[test]
+    fn check_sync_copied_fields() {
+        assert_eq!(mockup_sync(&[]).get_copied_fields(), vec!["*"]);
+        let syn = mockup_sync(&["--select", "name,id"]);
+        assert_eq!(syn.get_copied_fields(), vec!["name", "id", "modified"]);
+    }
+
+    #[test]
+    fn check_sync_tracks_greatest_mark() {
+        let syn = mockup_sync(&[]);
+        let docs = [
+            json!({"id": "1", "modified": "2025-01-03T00:00:00Z"}),
+            json!({"id": "2", "modified": ["2025-01-05T00:00:00.120Z"]}),
+            json!({"id": "3"}),
+            json!({"id": "4", "modified": "2025-01-04T00:00:00Z"}),
+        ];
+        let mut mark = Value::from("2025-01-01T00:00:00Z");
+        syn.track_mark(&docs, &mut mark);
+        assert_eq!(mark, "2025-01-05T00:00:00.120Z");
+    }
+}
+
+// end of file \\
@@ -0,0 +1,293 @@
+use super::{
+    args::CsvArgs,
+    fails::{BoxedResult, throw},
+    helpers::{IntegerHelpers, is_stdio},
+    ingest::STREAM_BATCH_DOCS,
+    models::{Compression, Documents},
+    save::{StreamEncoder, publish_file, warn_unpublished},
+    storage::DocumentSink,
+};
+use csv::{ReaderBuilder, StringRecordsIntoIter, Writer, WriterBuilder};
+use log::error;
+use serde_json::{Map, Value};
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+// region CsvWriter
+
+/// Writes the documents as csv with a column for each field for being opened in spreadsheets
+pub(crate) struct CsvWriter {
+    writer: Option<Writer<StreamEncoder>>,
+    /// Path of the file being written
+    current: Option<PathBuf>,
+    folder: PathBuf,
+    compression: Compression,
+    file_pattern: String,
+    max_files: usize,
+    file_count: usize,
+    columns: Vec<String>,
+    layout: CsvArgs,
+}
+
+impl CsvWriter {
+    pub(crate) fn write_on(
+        output_dir: &Path, output_pattern: &str, compression: Compression, max: usize,
+        columns: &[String], layout: &CsvArgs,
+    ) -> Self {
+        CsvWriter {
+            writer: None,
+            current: None,
+            folder: output_dir.to_owned(),
+            compression,
+            file_pattern: output_pattern.to_string(),
+            max_files: max,
+            file_count: 0,
+            columns: columns.to_vec(),
+            layout: layout.clone(),
+        }
+    }
+
+    fn create_file(&mut self, suffix: &str) -> BoxedResult<()> {
+        self.close_archive()?;
+
+        let file_name = self.file_pattern.replace("{}", suffix);
+        let (encoder, file_path) =
+            StreamEncoder::create_on(&self.folder, &file_name, self.compression)?;
+        let mut writer = WriterBuilder::new()
+            .delimiter(self.layout.csv_separator)
+            .quote(self.layout.csv_quote)
+            .from_writer(encoder);
+        writer.write_record(&self.columns)?;
+
+        self.writer = Some(writer);
+        self.current = file_path;
+        self.file_count = 0;
+        Ok(())
+    }
+}
+
+impl DocumentSink for CsvWriter {
+    /// Finishes the compression, renames the current file and writes its `.sha256` file
+    fn close_archive(&mut self) -> BoxedResult<()> {
+        if let Some(writer) = self.writer.take() {
+            let encoder = writer.into_inner().map_err(|cause| cause.into_error())?;
+            encoder.finish()?;
+        }
+        if let Some(file_path) = self.current.take() {
+            publish_file(file_path)?;
+        }
+        Ok(())
+    }
+
+    fn abandon_archive(&mut self) -> BoxedResult<()> {
+        warn_unpublished(self.current.take());
+        if let Some(writer) = self.writer.take() {
+            let encoder = writer.into_inner().map_err(|cause| cause.into_error())?;
+            encoder.finish()?;
+        }
+        Ok(())
+    }
+
+    fn write_documents(&mut self, docs: &Documents) -> BoxedResult<u64> {
+        let json = &docs.docs;
+        if json.len() <= 2 {
+            return Ok(0);
+        }
+        let parsed: Vec<Map<String, Value>> = serde_json::from_str(json)?;
+
+        self.file_count += 1;
+        let wrap = self.file_count >= self.max_files && !is_stdio(&self.folder);
+
+        if self.writer.is_none() || wrap {
+            let suffix = format!("{:09}", docs.step.curr + 1);
+            self.create_file(&suffix)?;
+        }
+        let writer = self.writer.as_mut().unwrap();
+        let written = parsed.len().to_u64();
+        for doc in parsed {
+            let cells = self.columns.iter().map(|col| to_cell(doc.get(col), &self.layout.csv_join));
+            writer.write_record(cells)?;
+        }
+        Ok(written)
+    }
+}
+
+impl Drop for CsvWriter {
+    fn drop(&mut self) {
+        let fail = self.abandon_archive();
+        if let Err(cause) = fail {
+            error!("> Dropping {}", cause);
+        }
+    }
+}
+
+/// Formats the field value as text joining the values of multi-valued fields with `join`
+fn to_cell(value: Option<&Value>, join: &str) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(text)) => text.clone(),
+        Some(Value::Array(values)) => {
+            let texts: Vec<String> = values.iter().map(|item| to_cell(Some(item), join)).collect();
+            texts.join(join)
+        }
+        Some(other) => other.to_string(),
+    }
+}
+
+// endregion
+
+// region CsvReader
+
+/// Reads the rows of a csv file as documents with the fields named by the header row.
+/// Empty cells are left out and the `split` columns become multi-valued fields
+pub(crate) struct CsvReader {
+    records: StringRecordsIntoIter<Box<dyn Read>>,
+    header: Vec<String>,
+    /// Which columns hold multi-valued fields
+    split: Vec<bool>,
+    join: String,
+    row_count: u64,
+    /// Error deferred until the documents read before it are yielded
+    failure: Option<String>,
+    finished: bool,
+}
+
+impl CsvReader {
+    pub(crate) fn from_stream(
+        stream: Box<dyn Read>, layout: &CsvArgs, split: &[String],
+    ) -> BoxedResult<CsvReader> {
+        let mut reader = Self::open_csv(stream, layout);
+        let header: Vec<String> = reader.headers()?.iter().map(str::to_string).collect();
+        let split = header.iter().map(|col| split.contains(col)).collect();
+        Ok(CsvReader {
+            records: reader.into_records(),
+            header,
+            split,
+            join: layout.csv_join.clone(),
+            row_count: 0,
+            failure: None,
+            finished: false,
+        })
+    }
+
+    fn open_csv(stream: Box<dyn Read>, layout: &CsvArgs) -> csv::Reader<Box<dyn Read>> {
+        ReaderBuilder::new()
+            .delimiter(layout.csv_separator)
+            .quote(layout.csv_quote)
+            .from_reader(stream)
+    }
+
+    fn to_doc(&self, record: &csv::StringRecord) -> Value {
+        let mut doc = Map::new();
+        for ((name, cell), split) in self.header.iter().zip(record.iter()).zip(&self.split) {
+            if cell.is_empty() {
+                continue;
+            }
+            let value = if *split && !self.join.is_empty() {
+                Value::Array(cell.split(&self.join).map(|item| item.into()).collect())
+            } else {
+                Value::String(cell.to_string())
+            };
+            doc.insert(name.clone(), value);
+        }
+        Value::Object(doc)
+    }
+}
+
+/// Yields the rows read as a json array, or why the file could not be read further.
+/// After an error the iteration continues only when the stream itself is not broken.
+impl Iterator for CsvReader {
+    type Item = BoxedResult<(String, String, u64)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(cause) = self.failure.take() {
+            return Some(throw(cause));
+        }
+        if self.finished {
+            return None;
+        }
+        let first = self.row_count + 1;
+        let mut docs: Vec<Value> = Vec::with_capacity(STREAM_BATCH_DOCS);
+        while docs.len() < STREAM_BATCH_DOCS {
+            match self.records.next() {
+                None => {
+                    self.finished = true;
+                    break;
+                }
+                Some(Err(cause)) => {
+                    self.row_count += 1;
+                    self.finished = cause.is_io_error();
+                    self.failure = Some(format!("error reading row {}: {}", self.row_count, cause));
+                    break;
+                }
+                Some(Ok(record)) => {
+                    self.row_count += 1;
+                    docs.push(self.to_doc(&record));
+                }
+            }
+        }
+        if docs.is_empty() {
+            return self.next();
+        }
+        let name = format!("rows_{}_to_{}", first, self.row_count);
+        let count = docs.len().to_u64();
+        Some(Ok((name, Value::Array(docs).to_string(), count)))
+    }
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use super::{CsvReader, CsvWriter};
+    use crate::args::CsvArgs;
+    use crate::helpers::shared::TempDir;
+    use crate::ingest::open_decoder;
+    use crate::models::{Compression, Documents, Step};
+    use crate::storage::DocumentSink;
+    use pretty_assertions::assert_eq;
+    use serde_json::{Value, json};
+
+    #[test]
+    fn check_csv_round_trip() {
+        let dir = TempDir::new("csv");
+
+        let layout = CsvArgs { csv_separator: b';', ..Default::default() };
+        let columns = ["id", "title", "tags", "price"].map(String::from);
+        let docs = json!([
+            {"id": "1", "title": "first; \"quoted\"", "tags": ["a", "b"], "price": 1.5},
+            {"id": "2", "tags": ["c"]},
+        ]);
+        let mut writer = CsvWriter::write_on(
+            &dir,
+            "demo_seq_{}.csv.gz",
+            Compression::Zip,
+            10,
+            &columns,
+            &layout,
+        );
+        let step = Step { curr: 0, expected: 0, url: String::new() };
+        writer.write_documents(&Documents { step, docs: docs.to_string() }).unwrap();
+        writer.close_archive().unwrap();
+
+        let file = dir.join("demo_seq_000000001.csv.gz");
+        let split = ["tags".to_string()];
+        let read: Vec<_> = CsvReader::from_stream(open_decoder(&file).unwrap(), &layout, &split)
+            .unwrap()
+            .map(|item| item.unwrap())
+            .collect();
+
+        let expected = json!([
+            {"id": "1", "title": "first; \"quoted\"", "tags": ["a", "b"], "price": "1.5"},
+            {"id": "2", "tags": ["c"]},
+        ]);
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].0, "rows_1_to_2");
+        assert_eq!(serde_json::from_str::<Value>(&read[0].1).unwrap(), expected);
+        assert_eq!(read[0].2, 2);
+    }
+}
+
+// end of file \\
@@ -102,11 +102,116 @@ fn check_exec_solrcopy_with_solr_running() {
 
         check_exec_backup(url, dir, "demo", "zip");
 
+        check_exec_inspect(dir);
+
+        check_exec_verify(dir);
+
         check_exec_delete(url, "target");
 
         check_exec_restore(url, dir, "target");
 
+        check_exec_diff(url, "demo", "target");
+
+        check_exec_sync(url, dir, "demo", "target");
+
         check_exec_backup(url, dir, "demo", "zstd");
+
+        check_exec_format(url, dir, "demo", "target", "jsonl");
+
+        check_exec_format(url, dir, "demo", "target", "tar");
+
+        // Set to a bucket like s3://solrcopy/test with the AWS_* variables pointing to MinIO
+        if let Ok(bucket) = std::env::var("SOLR_COPY_TEST_BUCKET") {
+            check_exec_format(url, &bucket, "demo", "target", "jsonl");
+        }
+
+        check_exec_backup_parquet(url, dir, "demo");
+    }
+
+    /// Run this command to test backup as parquet files for analytics
+    fn check_exec_backup_parquet(url: &str, dir: &str, core: &str) {
+        let test_args = &[
+            "solrcopy",
+            "backup",
+            "--url",
+            url,
+            "--core",
+            core,
+            "--dir",
+            dir,
+            "--archive-format",
+            "parquet",
+            "--parquet-row-group-size",
+            "1mb",
+        ];
+        test_command_line_args_for(test_args);
+    }
+
+    /// Run this command to test backup and restore in other formats than zip
+    fn check_exec_format(url: &str, dir: &str, core: &str, target: &str, format: &str) {
+        let backup_args = &[
+            "solrcopy",
+            "backup",
+            "--url",
+            url,
+            "--core",
+            core,
+            "--dir",
+            dir,
+            "--archive-format",
+            format,
+            "--archive-compression",
+            "zstd",
+        ];
+        test_command_line_args_for(backup_args);
+
+        let search = format!("{}*.{}.zst", core, format);
+        let restore_args = &[
+            "solrcopy", "restore", "--url", url, "--core", target, "--search", &search, "--dir",
+            dir,
+        ];
+        test_command_line_args_for(restore_args);
+    }
+
+    /// Run this command to test comparing two cores of a running Solr instance
+    fn check_exec_diff(url: &str, core: &str, target: &str) {
+        let test_args =
+            &["solrcopy", "diff", "--url", url, "--core", core, "--target-core", target];
+        test_command_line_args_for(test_args);
+    }
+
+    /// Run this command to test reporting the contents of the archives of a backup
+    fn check_exec_inspect(dir: &str) {
+        let test_args =
+            &["solrcopy", "inspect", "--dir", dir, "--search", "demo*.zip", "--field", "price"];
+        test_command_line_args_for(test_args);
+    }
+
+    /// Run this command to test checking the archives of a backup
+    fn check_exec_verify(dir: &str) {
+        let test_args = &["solrcopy", "verify", "--dir", dir, "--search", "demo*.zip"];
+        test_command_line_args_for(test_args);
+    }
+
+    /// Run this command to test copying the modified documents between two cores
+    fn check_exec_sync(url: &str, dir: &str, core: &str, target: &str) {
+        let state = format!("{}/demo_sync_state.json", dir);
+        let test_args = &[
+            "solrcopy",
+            "sync",
+            "--url",
+            url,
+            "--core",
+            core,
+            "--target-core",
+            target,
+            "--field",
+            "manufacturedate_dt",
+            "--state-file",
+            &state,
+            "--delete-missing",
+        ];
+        test_command_line_args_for(test_args);
     }
 
     /// Run this command to test the command commit with a running Solr instance
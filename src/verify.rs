@@ -0,0 +1,524 @@
+use super::{
+    args::{CsvArgs, Restore, Verify},
+    connection::SolrClient,
+    fails::{BoxedError, Error, fail_with},
+    fetch::json_to_key,
+    helpers::{EMPTY_STR, IntegerHelpers, sha256_file, sha256_hex},
+    ingest::{ArchiveReader, TarReader, find_archives, get_search_pattern, read_backup_file},
+    models::{ArchiveFormat, CHECKSUM_EXT, MANIFEST_ENTRY},
+};
+use log::{debug, error, info, warn};
+use serde::de::IgnoredAny;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{BuildHasher, RandomState};
+use std::sync::{
+    Mutex,
+    atomic::{AtomicU64, Ordering},
+};
+use std::{fs, io::Read, path::Path};
+
+/// How many sampled documents are fetched from the core in each real-time get request
+const SAMPLES_PER_REQUEST: usize = 50;
+
+pub(crate) fn verify_main(params: &Verify) -> BoxedError {
+    debug!("# VERIFY {:?}", params);
+
+    let ext = params.archive_format.get_search_ext();
+    let pattern = get_search_pattern(&params.dir, &params.search, EMPTY_STR, ext);
+    let found = find_archives(&pattern, params.order)?;
+    if found.is_empty() {
+        fail_with(Error::Archive(format!("Found no archives to verify: {}", pattern)))?;
+    }
+    let key = params.encryption.get_key()?;
+    let mut failed = 0;
+    for archive in &found {
+        let problems = verify_archive(archive, &params.csv, key.as_deref());
+        if problems.is_empty() {
+            info!("Verified archive {:?}: OK", archive);
+        } else {
+            failed += 1;
+            for problem in problems {
+                error!("Verified archive {:?}: {}", archive, problem);
+            }
+        }
+    }
+    info!("Verified {} archives: {} OK and {} failed.", found.len(), found.len() - failed, failed);
+    if failed > 0 {
+        let msg = format!("Found problems in {} of {} archives.", failed, found.len());
+        fail_with(Error::Mismatch(msg))?;
+    }
+    Ok(())
+}
+
+// region Checking
+
+/// Returns the problems found in the archive or none when it is intact
+pub(crate) fn verify_archive(archive: &Path, csv: &CsvArgs, key: Option<&str>) -> Vec<String> {
+    let mut problems = vec![];
+    check_archive_checksum(archive, &mut problems);
+    match ArchiveFormat::of_file(archive) {
+        ArchiveFormat::Zip => check_archive_entries(archive, key, &mut problems),
+        ArchiveFormat::Tar => check_tar_entries(archive, &mut problems),
+        _ => check_stream_batches(archive, csv, &mut problems),
+    }
+    problems
+}
+
+fn check_archive_checksum(archive: &Path, problems: &mut Vec<String>) {
+    let mut sidecar = archive.as_os_str().to_owned();
+    sidecar.push(CHECKSUM_EXT);
+    let Ok(contents) = fs::read_to_string(&sidecar) else {
+        warn!("Missing the checksum file {:?}. Checking only the entries.", sidecar);
+        return;
+    };
+    let expected = contents.split_whitespace().next().unwrap_or(EMPTY_STR);
+    match sha256_file(archive) {
+        Err(cause) => problems.push(format!("cannot read the archive: {}", cause)),
+        Ok(checksum) if checksum != expected => {
+            problems.push("the SHA-256 of the archive does not match the checksum file".into())
+        }
+        Ok(_) => {}
+    }
+}
+
+fn check_archive_entries(archive: &Path, key: Option<&str>, problems: &mut Vec<String>) {
+    let mut reader = match ArchiveReader::create_reader(archive) {
+        Ok(reader) => reader.decrypted_with(key),
+        Err(cause) => return problems.push(format!("cannot open the archive: {}", cause)),
+    };
+    let manifest = reader.read_manifest();
+    let mut recorded = get_recorded_entries(archive, manifest.as_ref());
+
+    for index in 0..reader.archive.len() {
+        let mut entry = match reader.open_entry(index) {
+            Ok(entry) => entry,
+            Err(cause) => {
+                problems.push(format!("cannot read the entry #{}: {}", index + 1, cause));
+                continue;
+            }
+        };
+        let name = entry.name().to_string();
+        if name == MANIFEST_ENTRY {
+            continue;
+        }
+        let mut bytes = vec![];
+        if let Err(cause) = entry.read_to_end(&mut bytes) {
+            problems.push(format!("cannot read the entry {}: {}", name, cause));
+            continue;
+        }
+        let docs = match serde_json::from_slice::<Vec<IgnoredAny>>(&bytes) {
+            Ok(docs) => docs.len(),
+            Err(cause) => {
+                problems.push(format!("the entry {} is not a json array of docs: {}", name, cause));
+                continue;
+            }
+        };
+        match recorded.remove(&name) {
+            None if manifest.is_some() => {
+                problems.push(format!("the entry {} is not listed in the manifest", name))
+            }
+            None => {}
+            Some(expected) => check_entry(&name, &sha256_hex(&bytes), docs, &expected, problems),
+        }
+    }
+    for name in recorded.keys() {
+        problems.push(format!("the entry {} listed in the manifest is missing", name));
+    }
+}
+
+/// Checks the entries of a tar archive against its manifest, written as its last entry
+fn check_tar_entries(archive: &Path, problems: &mut Vec<String>) {
+    let mut reader = match TarReader::create_reader(archive) {
+        Ok(reader) => reader,
+        Err(cause) => return problems.push(format!("cannot open the archive: {}", cause)),
+    };
+    // The checksum and docs of the entries read, as the manifest comes only after them
+    let mut read: Vec<(String, String, usize)> = vec![];
+    let mut manifest = None;
+    loop {
+        let (name, bytes) = match reader.read_entry() {
+            Ok(None) => break,
+            Ok(Some(entry)) => entry,
+            Err(cause) => {
+                let index = read.len() + 1;
+                problems.push(format!("cannot read the entry #{}: {}", index, cause));
+                break;
+            }
+        };
+        if name == MANIFEST_ENTRY {
+            match serde_json::from_slice::<Value>(&bytes) {
+                Ok(found) => manifest = Some(found),
+                Err(cause) => problems.push(format!("cannot parse the manifest: {}", cause)),
+            }
+            continue;
+        }
+        match serde_json::from_slice::<Vec<IgnoredAny>>(&bytes) {
+            Ok(docs) => read.push((name, sha256_hex(&bytes), docs.len())),
+            Err(cause) => {
+                problems.push(format!("the entry {} is not a json array of docs: {}", name, cause))
+            }
+        }
+    }
+    let mut recorded = get_recorded_entries(archive, manifest.as_ref());
+    for (name, checksum, docs) in read {
+        match recorded.remove(&name) {
+            None if manifest.is_some() => {
+                problems.push(format!("the entry {} is not listed in the manifest", name))
+            }
+            None => {}
+            Some(expected) => check_entry(&name, &checksum, docs, &expected, problems),
+        }
+    }
+    for name in recorded.keys() {
+        problems.push(format!("the entry {} listed in the manifest is missing", name));
+    }
+}
+
+/// The entries listed in the manifest by their names, or none when it is missing
+fn get_recorded_entries(archive: &Path, manifest: Option<&Value>) -> HashMap<String, Value> {
+    let mut recorded: HashMap<String, Value> = HashMap::new();
+    match manifest.and_then(|found| found.get("entries")) {
+        Some(Value::Array(entries)) => {
+            for entry in entries {
+                if let Some(name) = entry.get("name").and_then(Value::as_str) {
+                    recorded.insert(name.to_string(), entry.clone());
+                }
+            }
+        }
+        _ => warn!("Missing the manifest in {:?}. Checking only the json of the entries.", archive),
+    }
+    recorded
+}
+
+/// Reads all the documents of the json lines and csv files, written without a manifest
+fn check_stream_batches(file: &Path, csv: &CsvArgs, problems: &mut Vec<String>) {
+    let batches = match read_backup_file(file, csv, &[], None) {
+        Ok(batches) => batches,
+        Err(cause) => return problems.push(format!("cannot open the file: {}", cause)),
+    };
+    for read in batches {
+        match read {
+            Err(cause) => problems.push(format!("cannot read the file: {}", cause)),
+            Ok((name, json, _)) => {
+                if let Err(cause) = serde_json::from_str::<Vec<IgnoredAny>>(&json) {
+                    problems.push(format!("the {} are not a json array of docs: {}", name, cause));
+                }
+            }
+        }
+    }
+}
+
+fn check_entry(
+    name: &str, checksum: &str, docs: usize, expected: &Value, problems: &mut Vec<String>,
+) {
+    if expected.get("sha256").and_then(Value::as_str) != Some(checksum) {
+        problems.push(format!("the SHA-256 of the entry {} does not match the manifest", name));
+    }
+    let recorded = expected.get("docs").and_then(Value::as_u64);
+    if recorded != Some(docs.to_u64()) {
+        problems.push(format!(
+            "the entry {} has {} docs but the manifest recorded {:?}",
+            name, docs, recorded
+        ));
+    }
+}
+
+// endregion
+
+// region Restore verification
+
+/// Random sample of the documents sent to the core by all writer threads.
+/// Keeps the documents with the smallest hashes of their uniqueKeys, so the sample does not
+/// depend on the order the batches arrive in
+#[derive(Debug, Default)]
+pub(crate) struct DocSampler {
+    capacity: usize,
+    seed: RandomState,
+    picked: Mutex<BTreeMap<u64, Value>>,
+}
+
+impl DocSampler {
+    pub(crate) fn new(capacity: u64) -> Self {
+        Self { capacity: capacity.to_usize(), ..Default::default() }
+    }
+
+    pub(crate) fn is_sampling(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Keeps the documents of a batch that belong to the sample
+    pub(crate) fn offer(&self, key_field: &str, docs: Vec<Value>) {
+        let mut picked = self.picked.lock().unwrap();
+        for doc in docs {
+            let Some(key) = doc.get(key_field).map(json_to_key) else {
+                continue;
+            };
+            let hash = self.seed.hash_one(key);
+            let full = picked.len() >= self.capacity;
+            if full && picked.last_key_value().is_none_or(|(last, _)| hash >= *last) {
+                continue;
+            }
+            // Documents sent again replace the previous copy instead of growing the sample
+            if picked.insert(hash, doc).is_none() && full {
+                picked.pop_last();
+            }
+        }
+    }
+
+    pub(crate) fn into_docs(self) -> Vec<Value> {
+        self.picked.into_inner().unwrap().into_values().collect()
+    }
+}
+
+/// Hashes of the uniqueKeys of the documents restored by all writer threads.
+/// Counts the documents the core should hold when the archives have some of them twice, as the
+/// ones fetched again by backup `--verify` or written before `--duplicates` drops them
+#[derive(Debug, Default)]
+pub(crate) struct RestoredKeys {
+    seed: RandomState,
+    hashes: Mutex<HashSet<u64>>,
+    /// Documents without the uniqueKey, counted as distinct ones
+    unkeyed: AtomicU64,
+    /// Documents counted, including the ones repeated
+    total: AtomicU64,
+}
+
+impl RestoredKeys {
+    /// Counts the uniqueKeys of the json array of documents
+    pub(crate) fn add(&self, key_field: &str, json: &str) {
+        let Ok(docs) = serde_json::from_str::<Vec<Value>>(json) else {
+            return;
+        };
+        self.total.fetch_add(docs.len().to_u64(), Ordering::SeqCst);
+        let mut hashes = self.hashes.lock().unwrap();
+        for doc in docs {
+            if let Some(key) = doc.get(key_field).map(json_to_key) {
+                hashes.insert(self.seed.hash_one(key));
+            } else {
+                self.unkeyed.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Documents with distinct uniqueKeys and how many repeated the uniqueKey of another one
+    pub(crate) fn count_distinct(&self) -> (u64, u64) {
+        let distinct =
+            self.hashes.lock().unwrap().len().to_u64() + self.unkeyed.load(Ordering::SeqCst);
+        (distinct, self.total.load(Ordering::SeqCst) - distinct)
+    }
+}
+
+impl Restore {
+    /// Checks that the core holds the `restored` count of distinct documents and the `samples`
+    /// unchanged
+    pub(crate) fn verify_restored(&self, restored: u64, samples: Vec<Value>) -> BoxedError {
+        let core = self.options.core.as_str();
+        let query = self.verify_query.as_deref().unwrap_or("*:*");
+        let num_found = self.options.query_num_docs(query)?;
+
+        let mut problems = vec![];
+        if num_found != restored {
+            problems.push(format!(
+                "the core has {} documents matching '{}' but {} distinct ones were restored",
+                num_found, query, restored
+            ));
+        }
+        // The values read from csv files are all text
+        let textual = self.archive_format == ArchiveFormat::Csv;
+        let mut client = SolrClient::new();
+        for chunk in samples.chunks(SAMPLES_PER_REQUEST) {
+            let keys: Vec<String> =
+                chunk.iter().filter_map(|doc| doc.get(&self.unique_key)).map(json_to_key).collect();
+            let found = self.options.real_time_get(&mut client, &keys)?;
+            let stored: HashMap<String, Value> = found
+                .into_iter()
+                .filter_map(|doc| Some((json_to_key(doc.get(&self.unique_key)?), doc)))
+                .collect();
+            for (key, sent) in keys.iter().zip(chunk) {
+                match stored.get(key) {
+                    None => problems.push(format!("the document {} is missing", key)),
+                    Some(doc) => {
+                        if let Some(field) = find_changed_field(sent, doc, textual) {
+                            problems
+                                .push(format!("the field {} of document {} differs", field, key))
+                        }
+                    }
+                }
+            }
+        }
+        info!(
+            "Verified {} documents in core {} comparing {} sampled ones.",
+            num_found,
+            core,
+            samples.len()
+        );
+        if problems.is_empty() {
+            return Ok(());
+        }
+        for problem in &problems {
+            error!("Verified restore: {}", problem);
+        }
+        fail_with(Error::Mismatch(format!(
+            "Found {} problems in the documents restored into core {}.",
+            problems.len(),
+            core
+        )))
+    }
+}
+
+/// Returns the first field of the document `sent` whose value is not the `stored` one.
+/// Skips the internal fields starting with `_` and the child documents
+fn find_changed_field<'a>(sent: &'a Value, stored: &Value, textual: bool) -> Option<&'a str> {
+    let Value::Object(fields) = sent else {
+        return None;
+    };
+    let compared = fields.iter().filter(|(name, value)| !name.starts_with('_') && !is_child(value));
+    for (name, value) in compared {
+        match stored.get(name) {
+            Some(found) if same_values(value, found, textual) => {}
+            _ => return Some(name),
+        }
+    }
+    None
+}
+
+fn is_child(value: &Value) -> bool {
+    match value {
+        Value::Object(_) => true,
+        Value::Array(items) => items.first().is_some_and(Value::is_object),
+        _ => false,
+    }
+}
+
+/// Compares numbers by value and single values with lists holding only them.
+/// When `textual`, the text sent is converted to the type of the number or boolean stored
+fn same_values(sent: &Value, stored: &Value, textual: bool) -> bool {
+    match (sent, stored) {
+        (Value::Number(l), Value::Number(r)) => l == r || l.as_f64() == r.as_f64(),
+        (Value::Array(l), Value::Array(r)) => {
+            let mut pairs = l.iter().zip(r);
+            l.len() == r.len() && pairs.all(|(one, other)| same_values(one, other, textual))
+        }
+        (Value::Array(l), single) if l.len() == 1 => same_values(&l[0], single, textual),
+        (single, Value::Array(r)) if r.len() == 1 => same_values(single, &r[0], textual),
+        (Value::String(text), Value::Number(r)) if textual => {
+            text.trim().parse::<f64>().ok() == r.as_f64()
+        }
+        (Value::String(text), Value::Bool(r)) if textual => text.trim().parse() == Ok(*r),
+        _ => sent == stored,
+    }
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use super::{DocSampler, RestoredKeys, find_changed_field, verify_archive};
+    use crate::args::CsvArgs;
+    use crate::helpers::{IntegerHelpers, shared::TempDir};
+    use crate::models::{CHECKSUM_EXT, Compression, Documents, Step};
+    use crate::save::{Archiver, TarArchiver};
+    use crate::storage::DocumentSink;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use std::fs;
+
+    #[test]
+    fn check_verify_archive() {
+        let dir = TempDir::new("verify");
+
+        let mut archiver = Archiver::write_on(&dir, "demo_seq_{}.zip", Compression::Zip, 10);
+        let docs = [json!([{"id": "1"}, {"id": "2"}]), json!([{"id": "3"}])];
+        for (curr, batch) in docs.iter().enumerate() {
+            let step = Step { curr: curr.to_u64(), expected: 0, url: String::new() };
+            archiver.write_documents(&Documents { step, docs: batch.to_string() }).unwrap();
+        }
+        archiver.close_archive().unwrap();
+        let archive = dir.join("demo_seq_000000001.zip");
+        let intact = verify_archive(&archive, &CsvArgs::default(), None);
+
+        let mut sidecar = archive.clone().into_os_string();
+        sidecar.push(CHECKSUM_EXT);
+        fs::write(&sidecar, "0123456789abcdef  demo_seq_000000001.zip\n").unwrap();
+        let tampered = verify_archive(&archive, &CsvArgs::default(), None);
+
+        assert_eq!(intact, Vec::<String>::new());
+        assert_eq!(tampered.len(), 1);
+        assert!(tampered[0].contains("checksum file"));
+    }
+
+    #[test]
+    fn check_verify_tar_archive() {
+        let dir = TempDir::new("verify_tar");
+
+        let mut archiver = TarArchiver::write_on(&dir, "demo_seq_{}.tar", Compression::Stored, 10);
+        let docs = [json!([{"id": "1"}, {"id": "2"}]), json!([{"id": "3"}])];
+        for (curr, batch) in docs.iter().enumerate() {
+            let step = Step { curr: curr.to_u64(), expected: 0, url: String::new() };
+            archiver.write_documents(&Documents { step, docs: batch.to_string() }).unwrap();
+        }
+        archiver.close_archive().unwrap();
+        let archive = dir.join("demo_seq_000000001.tar");
+        let mut sidecar = archive.clone().into_os_string();
+        sidecar.push(CHECKSUM_EXT);
+        fs::remove_file(&sidecar).unwrap();
+        let intact = verify_archive(&archive, &CsvArgs::default(), None);
+
+        // Swaps the docs of the first entry keeping its size
+        let contents = fs::read(&archive).unwrap();
+        let at = contents.windows(5).position(|it| it == br#""id":"#).unwrap();
+        let mut tampered = contents.clone();
+        tampered[at + 6] = b'9';
+        fs::write(&archive, &tampered).unwrap();
+        let changed = verify_archive(&archive, &CsvArgs::default(), None);
+
+        assert_eq!(intact, Vec::<String>::new());
+        assert_eq!(changed.len(), 1);
+        assert!(changed[0].contains("does not match the manifest"));
+    }
+
+    #[test]
+    fn check_restored_keys_counts_distinct() {
+        let keys = RestoredKeys::default();
+        keys.add("id", r#"[{"id": "1"}, {"id": "2"}, {"name": "unkeyed"}]"#);
+        keys.add("id", r#"[{"id": "2"}, {"id": 3}]"#);
+        keys.add("id", "not json");
+
+        assert_eq!(keys.count_distinct(), (4, 1));
+    }
+
+    #[test]
+    fn check_doc_sampler_keeps_capacity() {
+        let sampler = DocSampler::new(3);
+        for batch in 0..4 {
+            let docs = (0..5).map(|it| json!({"id": format!("{}_{}", batch, it)})).collect();
+            sampler.offer("id", docs);
+        }
+        sampler.offer("id", vec![json!({"id": "0_0"}), json!({"other": 1})]);
+        let picked = sampler.into_docs();
+
+        assert_eq!(picked.len(), 3);
+        assert_eq!(DocSampler::default().is_sampling(), false);
+    }
+
+    #[test]
+    fn check_find_changed_field() {
+        let sent =
+            json!({"id": "1", "tags": ["a"], "price": 9, "_version_": 1, "kids": [{"id": "2"}]});
+        let same = json!({"id": "1", "tags": "a", "price": 9.0, "_version_": 7});
+        let changed = json!({"id": "1", "tags": ["a", "b"], "price": 9});
+        let missing = json!({"id": "1", "tags": ["a"]});
+
+        assert_eq!(find_changed_field(&sent, &same, false), None);
+        assert_eq!(find_changed_field(&sent, &changed, false), Some("tags"));
+        assert_eq!(find_changed_field(&sent, &missing, false), Some("price"));
+
+        let from_csv = json!({"id": "1", "tags": ["a"], "price": "9", "active": "true"});
+        let typed = json!({"id": "1", "tags": ["a"], "price": 9.0, "active": true});
+        assert_eq!(find_changed_field(&from_csv, &typed, true), None);
+        assert_eq!(find_changed_field(&from_csv, &typed, false), Some("active"));
+    }
+}
+
+// end of file \\